@@ -0,0 +1,80 @@
+#![feature(test)]
+
+extern crate advent;
+extern crate test;
+
+use advent::math::{self, executor, parser, vm};
+use test::Bencher;
+
+const FIB: &[u8] = b"fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2);\na = fib(20);";
+const GCD: &[u8] = b"gcd(a, b) = if b == 0 then a else gcd(b, a - b * (a / b));
+sum(n) = if n == 0 then 0 else gcd(n * 7919, 104729 - n) + sum(n - 1);
+a = sum(200);";
+// A formula that a program defines once and its caller evaluates many times.
+const FORMULA: &[u8] = b"rate = 7;\nscore(x, y) = if x > y then rate * (x - y) else mod(max(x, y), rate);";
+
+fn bench_executor(b: &mut Bencher, source: &[u8]) {
+    let statements = parser::parse(source).unwrap();
+    b.iter(|| executor::execute::<i64>(statements.clone()).unwrap());
+}
+
+fn bench_vm(b: &mut Bencher, source: &[u8]) {
+    let program = vm::compile(&parser::parse(source).unwrap());
+    b.iter(|| {
+        let mut vm = vm::Vm::new(&program);
+        vm.run().unwrap();
+        vm.variables()
+    });
+}
+
+fn bench_compiled(b: &mut Bencher, source: &[u8]) {
+    let mut program = math::compile(&parser::parse(source).unwrap());
+    b.iter(|| {
+        program.run().unwrap();
+        program.variables()
+    });
+}
+
+#[bench]
+fn fib_executor(b: &mut Bencher) {
+    bench_executor(b, FIB);
+}
+
+#[bench]
+fn fib_vm(b: &mut Bencher) {
+    bench_vm(b, FIB);
+}
+
+#[bench]
+fn fib_compiled(b: &mut Bencher) {
+    bench_compiled(b, FIB);
+}
+
+#[bench]
+fn gcd_executor(b: &mut Bencher) {
+    bench_executor(b, GCD);
+}
+
+#[bench]
+fn gcd_vm(b: &mut Bencher) {
+    bench_vm(b, GCD);
+}
+
+#[bench]
+fn gcd_compiled(b: &mut Bencher) {
+    bench_compiled(b, GCD);
+}
+
+#[bench]
+fn formula_executor(b: &mut Bencher) {
+    let mut executor = executor::Executor::<i64>::new();
+    executor.run(parser::parse(FORMULA).unwrap()).unwrap();
+    b.iter(|| (0..1000).map(|i| executor.call_fn("score", &[i, 500]).unwrap()).sum::<i64>());
+}
+
+#[bench]
+fn formula_compiled(b: &mut Bencher) {
+    let mut program = math::compile(&parser::parse(FORMULA).unwrap());
+    program.run().unwrap();
+    b.iter(|| (0..1000).map(|i| program.call("score", &[i, 500]).unwrap()).sum::<i64>());
+}
@@ -1,67 +1,544 @@
 extern crate advent;
-#[macro_use]
-extern crate nom;
 
 use advent::math;
+use advent::math::executor::{Error, Executor, Observer, Tracer, Value};
+use advent::math::import::{self, Loader};
+use advent::math::number::{BigInt, BigRational, Number};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+const HELP: &str = "\
+Enter statements such as `a = 1 + 2;` or `f(x) = x * 2;`. A statement may span
+several lines; it is run once its `;` has been entered. The builtins abs, min,
+max, pow, sqrt, floor, mod and gcd are always available, as is d, where
+`d(f, x)` is the derivative of f with respect to its param x, evaluated at the
+values of the variables named like f's params, and `d(f, x, 1, 2)` evaluates it
+at f(1, 2). d differentiates through calls to builtins and to functions the
+program defines, but not through gcd or lambdas. `#` starts a comment that
+runs to the end of the line. With --reactive, reassigning a variable or
+redefining a function recomputes every variable that uses it.
+
+Functions are values: `apply_twice(f, x) = f(f(x));` can be called as
+`apply_twice(abs, -3)` or with a lambda, `apply_twice(\\x -> x * 2, 3)`, and
+anything that gives a function can be called, as in `adder(5)(1)`. Lists are
+written `[1, 2, 3]`, and range(a, b), len(xs), map(f, xs), filter(f, xs)
+and fold(f, initial, xs) work with them.
+
+`import \"lib/geometry.math\";` runs a file, defining what it defines in a
+namespace named after it, as in `geometry.area(2)`. Imports are looked for
+beside the file that imports them, or in the current directory for the REPL
+and `-`, and then in each --path directory and each directory in MATH_PATH.
+
+:vars          list variables and their values
+:fns           list functions and their parameters
+:load <file>   run every statement in a file and the files it imports
+:undo          undo the last input, or the last :load
+:ast <input>   show the parsed statements of some input without running them
+:optimize <input>
+               show some input as it is after `math::optimize`
+:memo <fn>     cache the results of calls to a function
+:memo          list memoized functions with their cache hits and misses
+:reset         forget every variable and function
+:help          show this message
+:quit          exit";
+
+const DEBUG_HELP: &str = "\
+s, step          run until the next statement or function call
+n, next          run until the next statement
+c, continue      run until a breakpoint
+b, break <fn>    pause whenever <fn> is called
+d, delete <fn>   remove the breakpoint on <fn>
+bt, backtrace    list the function calls in progress
+vars             list the variables assigned so far
+q, quit          exit";
+
+const USAGE: &str = "\
+usage: math [--numbers i64|bigint|rational|float] [--reactive] [--trace] [--path DIR]... [FILE | -]
+       math [--numbers i64|bigint|rational|float] [--path DIR]... --debug FILE
+       math fmt FILE
+       math check FILE
+       math --optimize FILE";
 
 fn main() {
-    //println!("name {:?}", variable_name(b"a"));
-    //println!("name {:?}", variable_name(b"abc"));
-    //println!("name {:?}", variable_name(b"abc "));
-    //println!("name {:?}", variable_name(b"abc="));
-    //println!("name {:?}", variable_name(b"abc ="));
-    //println!("");
-
-    //println!("expression {:?}", expression(b"123456"));
-    //println!("expression {:?}", expression(b"1;"));
-    //println!("expression {:?}", expression(b"123;"));
-    //println!("expression {:?}", expression(b"1+4+7;"));
-    //println!("expression {:?}", expression(b"1 + 4 + 7;"));
-    //println!("expression {:?}", expression(b"3 + 6 + 9 ;"));
-    //println!("expression {:?}", expression(b"1 + 3 * 6 / 9;"));
-    //println!("expression {:?}", expression(b"1 + 4 + 7"));
-    //println!("expression {:?}", expression(b"1 + 4 + a(5)"));
-    //println!("expression {:?}", expression(b"1 + 4 + a(5 + 5)"));
-    //println!("");
-
-    //println!("variable_assignment {:?}", variable_assignment(b"a=1;"));
-    //println!("variable_assignment {:?}", variable_assignment(b"a = 1;"));
-    //println!("variable_assignment {:?}", variable_assignment(b"a = 1 + 3 * 6 / 9;"));
-    //println!("");
-
-    //println!("function_definition {:?}", function_definition(b"a(b) = b;"));
-    //println!("");
-
-    //println!("statement {:?}", statement(b"a = 1 + 3 * 6 / 9;"));
-    //println!("statement {:?}", statement(b"a(b) = b;"));
-    //println!("");
-
-    println!(
-        "statements {:?}",
-        math::parser::statements(b"a = 1 - -3 * 6 / 9;\nb = 5 * -3;")
-    );
-    println!(
-        "statements {:?}",
-        math::parser::statements(b"a = 1 + 3 * 6 / 9;\nb = 5 * a(-5 * 3) + 5;")
-    );
-    println!(
-        "statements {:?}",
-        math::parser::statements(
-            b"a = 1 + a * 6 / 9;\nb = 5 * a(5 * -3) + 5;\na(i, j) = 5 * i - j;"
-        )
-    );
-    println!("");
-
-    let mut executor = math::executor::Executor::new();
-    let statements = math::parser::statements(
-        b"a = 1 + 89 / 9;\na(i, j) = 5 * i - j;\nb = 5 * a(5 * -3, 2) + 5;",
-    ).unwrap()
-        .1;
-    executor.run(statements);
-    println!("{:?}", executor.variables);
-
-    println!(
-        "{:?}",
-        math::interpret(b"a = 1 + 89 / 9;\na(i, j) = 5 * i - j;\nb = 5 * a(5 * -3, 2) + 5;")
-    );
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // `--numbers` picks what programs compute with: checked 64-bit integers
+    // by default, integers that never overflow, exact fractions, or floats.
+    let mut numbers = String::from("i64");
+    if let Some(i) = args.iter().position(|arg| arg == "--numbers") {
+        if i + 1 == args.len() {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+        numbers = args.remove(i + 1);
+        args.remove(i);
+    }
+
+    // Each `--path` adds a directory to look for imports in, ahead of those
+    // in `MATH_PATH`.
+    let mut loader = Loader::new();
+    while let Some(i) = args.iter().position(|arg| arg == "--path") {
+        if i + 1 == args.len() {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+        loader.search_path.push(PathBuf::from(args.remove(i + 1)));
+        args.remove(i);
+    }
+    if let Some(paths) = env::var_os("MATH_PATH") {
+        loader.search_path.extend(env::split_paths(&paths));
+    }
+
+    // `--reactive` makes the REPL work like a spreadsheet, recomputing every
+    // variable that uses one that has been reassigned. `--trace` writes each
+    // step of evaluation to stderr, and `--debug` runs a file in the debugger.
+    let options = Options {
+        reactive: flag(&mut args, "--reactive"),
+        trace: flag(&mut args, "--trace"),
+        debug: flag(&mut args, "--debug"),
+        loader,
+    };
+    if options.debug && args.len() != 1 {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+
+    // `fmt` rewrites a file in canonical form, `check` reports problems with
+    // it without running it, and `--optimize` prints it after optimisation.
+    if args.len() == 2 && (args[0] == "fmt" || args[0] == "check" || args[0] == "--optimize") {
+        let path = &args[1];
+        let source = read_file(path).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        });
+        let statements = math::parser::parse(&source).unwrap_or_else(|e| {
+            eprint!("{}", e.render(&source));
+            process::exit(1);
+        });
+        if args[0] == "fmt" {
+            let formatted = math::format_with_comments(&statements, &source).unwrap_or_else(|e| {
+                eprint!("{}", e.render(&source));
+                process::exit(1);
+            });
+            File::create(path)
+                .and_then(|mut file| file.write_all(formatted.as_bytes()))
+                .unwrap_or_else(|e| {
+                    eprintln!("error: could not write {}: {}", path, e);
+                    process::exit(1);
+                });
+        } else if args[0] == "check" {
+            check(&statements, &source, path, &options.loader);
+        } else {
+            print!("{}", math::format(&math::optimize(statements)));
+        }
+        return;
+    }
+
+    match (numbers.as_str(), args.get(0)) {
+        ("i64", path) => start::<i64>(path, &options),
+        ("bigint", path) => start::<BigInt>(path, &options),
+        ("rational", path) => start::<BigRational>(path, &options),
+        ("float", path) => start::<f64>(path, &options),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    }
+}
+
+// Checks the file at `path` and then each file it imports, as running it
+// would read them, exiting if any has an error. Each file is checked on its
+// own, as `math::check` takes an import to define whatever is used from it, so
+// that every diagnostic is against the source it is about. An imported file's
+// functions are there for the files that import it, so they are not reported
+// as never called.
+fn check(statements: &[math::Statement], source: &[u8], path: &str, loader: &Loader) {
+    let imports = loader.imports(Path::new(path)).unwrap_or_else(|e| {
+        eprint!("{}", e.render());
+        process::exit(1);
+    });
+    let mut diagnostics = math::check(statements);
+    for diagnostic in &diagnostics {
+        eprint!("{}", diagnostic.render(source));
+    }
+    for import in &imports {
+        let path = import.display().to_string();
+        let source = read_file(&path).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        });
+        let statements = math::parser::parse(&source).unwrap_or_else(|e| {
+            eprint!("{}", e.render_in(&source, &path));
+            process::exit(1);
+        });
+        for diagnostic in math::check(&statements) {
+            if let math::DiagnosticKind::UnusedFunction(_) = diagnostic.kind {
+                continue;
+            }
+            eprint!("{}", diagnostic.render_in(&source, &path));
+            diagnostics.push(diagnostic);
+        }
+    }
+    if diagnostics.iter().any(math::Diagnostic::is_error) {
+        process::exit(1);
+    }
+}
+
+struct Options {
+    reactive: bool,
+    trace: bool,
+    debug: bool,
+    loader: Loader,
+}
+
+// Removes `flag` from `args`, returning whether it was there.
+fn flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let present = args.iter().any(|arg| arg == flag);
+    args.retain(|arg| arg != flag);
+    return present;
+}
+
+// With a path, run the whole file and print the final variables. With `-`,
+// run each statement from stdin as soon as it arrives and print what it
+// assigns. Without either, start the REPL.
+fn start<N: Number>(path: Option<&String>, options: &Options) {
+    if path.map_or(false, |path| path == "-") {
+        let stdin = io::stdin();
+        let mut assignments = math::interpret_stream::<_, N>(stdin.lock());
+        assignments.executor.observer = observer(options);
+        assignments.loader = Some(options.loader.clone());
+        while let Some(result) = assignments.next() {
+            match result {
+                Ok((name, value)) => println!("{} = {}", name, value),
+                Err(e) => eprint!("{}", e.render(assignments.statements.source())),
+            }
+        }
+        return;
+    }
+    if let Some(path) = path {
+        let statements = options.loader.load(Path::new(path)).unwrap_or_else(|e| {
+            eprint!("{}", e.render());
+            process::exit(1);
+        });
+        let mut executor = Executor::<N>::new();
+        executor.observer = if options.debug {
+            let source = read_file(path).unwrap_or_else(|e| {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            });
+            Some(Box::new(Debugger::new(source)))
+        } else {
+            observer(options)
+        };
+        if let Err(e) = executor.run(statements) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        print_variables(&executor);
+        return;
+    }
+
+    let stdin = io::stdin();
+    let mut executor = if options.reactive {
+        Executor::<N>::reactive()
+    } else {
+        Executor::<N>::new()
+    };
+    executor.observer = observer(options);
+    let mut buffer = String::new();
+    prompt(&buffer);
+    for line in stdin.lock().lines() {
+        let line = line.expect("could not read from stdin");
+        if buffer.is_empty() && line.trim_start().starts_with(':') {
+            if !meta_command(&mut executor, &options.loader, line.trim()) {
+                return;
+            }
+        } else {
+            buffer.push_str(&line);
+            buffer.push('\n');
+            // Input is complete once its last token is a `;`, which allows a
+            // comment to follow it. Input that does not even tokenize is run
+            // straight away so that the error is reported.
+            match math::parser::tokenize(buffer.as_bytes()) {
+                Ok(ref tokens) if tokens.is_empty() => buffer.clear(),
+                Ok(ref tokens) if tokens.last().unwrap().token != math::parser::Token::Semicolon => {}
+                _ => {
+                    executor.checkpoint();
+                    run(&mut executor, options.loader.load_source(buffer.as_bytes(), Path::new("")));
+                    buffer.clear();
+                }
+            }
+        }
+        prompt(&buffer);
+    }
+    println!();
+}
+
+fn prompt(buffer: &str) {
+    print!("{}", if buffer.is_empty() { "> " } else { "... " });
+    io::stdout().flush().expect("could not write to stdout");
+}
+
+// Returns false once the REPL should exit.
+fn meta_command<N: Number>(executor: &mut Executor<N>, loader: &Loader, line: &str) -> bool {
+    let mut parts = line.splitn(2, char::is_whitespace);
+    let command = parts.next().unwrap_or("");
+    let argument = parts.next().unwrap_or("").trim();
+    match command {
+        ":vars" => print_variables(executor),
+        ":fns" => {
+            let mut functions: Vec<_> = executor.functions.iter().collect();
+            functions.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+            for (name, function) in functions {
+                let params: Vec<_> = function.params.iter().map(|p| p.as_str()).collect();
+                println!("{}({})", name, params.join(", "));
+            }
+        }
+        ":load" if !argument.is_empty() => {
+            executor.checkpoint();
+            run(executor, loader.load(Path::new(argument)));
+        }
+        ":undo" => {
+            if !executor.undo() {
+                eprintln!("error: nothing to undo");
+            }
+        }
+        ":ast" => match math::parser::parse(argument.as_bytes()) {
+            Ok(statements) => println!("{:#?}", statements),
+            Err(e) => eprint!("{}", e.render(argument.as_bytes())),
+        },
+        ":optimize" => match math::parser::parse(argument.as_bytes()) {
+            Ok(statements) => print!("{}", math::format(&math::optimize(statements))),
+            Err(e) => eprint!("{}", e.render(argument.as_bytes())),
+        },
+        ":memo" if !argument.is_empty() => match math::parser::parse_name(argument) {
+            Ok(name) => executor.memoize(&name),
+            Err(e) => eprint!("{}", e.render(argument.as_bytes())),
+        },
+        ":memo" => {
+            let mut functions: Vec<_> = executor
+                .functions
+                .keys()
+                .filter_map(|name| executor.memo_stats(name).map(|stats| (name, stats)))
+                .collect();
+            functions.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+            for (name, stats) in functions {
+                println!(
+                    "{}: {} hits, {} misses, {} cached",
+                    name, stats.hits, stats.misses, stats.entries
+                );
+            }
+        }
+        ":reset" => {
+            let observer = executor.observer.take();
+            *executor = if executor.is_reactive() {
+                Executor::reactive()
+            } else {
+                Executor::new()
+            };
+            executor.observer = observer;
+        }
+        ":help" => println!("{}", HELP),
+        ":quit" | ":q" => return false,
+        _ => eprintln!("error: unknown command `{}`; try :help", line),
+    }
+    return true;
+}
+
+// Runs each statement that loaded in turn, printing the value of every
+// assignment and of every other variable that changed as a result, and stops
+// at the first error.
+fn run<N: Number>(executor: &mut Executor<N>, statements: Result<Vec<math::Statement>, import::Error>) {
+    let statements = match statements {
+        Ok(statements) => statements,
+        Err(e) => return eprint!("{}", e.render()),
+    };
+    for statement in statements {
+        let assigned = match statement {
+            math::Statement::VarAssignment(ref name, _, _) => Some(name.clone()),
+            _ => None,
+        };
+        let changed = match executor.update(statement) {
+            Ok(changed) => changed,
+            Err(e) => return eprintln!("error: {}", e),
+        };
+        if let Some(ref name) = assigned {
+            println!("{} = {}", name, variable(executor, name));
+        }
+        for name in changed.iter().filter(|&name| Some(name) != assigned.as_ref()) {
+            println!("{} = {}", name, variable(executor, name));
+        }
+    }
+}
+
+// The value of a variable that has been assigned, whether or not it is a
+// number.
+fn variable<N: Number>(executor: &Executor<N>, name: &math::Name) -> String {
+    match executor.variables.get(name) {
+        Some(n) => n.to_string(),
+        None => executor.values[name].to_string(),
+    }
+}
+
+// Every variable in name order.
+fn print_variables<N: Number>(executor: &Executor<N>) {
+    let numbers = executor.variables.iter().map(|(name, n)| (name, n.to_string()));
+    let values = executor.values.iter().map(|(name, value)| (name, value.to_string()));
+    let mut variables: Vec<_> = numbers.chain(values).collect();
+    variables.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+    for (name, value) in variables {
+        println!("{} = {}", name, value);
+    }
+}
+
+fn observer<N: Number>(options: &Options) -> Option<Box<dyn Observer<N>>> {
+    if options.trace {
+        Some(Box::new(Tracer::new(io::stderr())))
+    } else {
+        None
+    }
+}
+
+// Pauses before the first statement, and then wherever stepping or a
+// breakpoint says to, to take commands from stdin.
+struct Debugger {
+    source: Vec<u8>,
+    breakpoints: HashSet<String>,
+    pause: Pause,
+    // The calls in progress, innermost last.
+    calls: Vec<String>,
+    variables: BTreeMap<String, String>,
+}
+
+#[derive(PartialEq)]
+enum Pause {
+    Step,
+    Next,
+    Breakpoint,
+}
+
+impl Debugger {
+    fn new(source: Vec<u8>) -> Debugger {
+        Debugger {
+            source,
+            breakpoints: HashSet::new(),
+            pause: Pause::Next,
+            calls: vec![],
+            variables: BTreeMap::new(),
+        }
+    }
+
+    // Takes commands until one resumes evaluation.
+    fn prompt(&mut self) {
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().expect("could not write to stdout");
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line).expect("could not read from stdin") == 0 {
+                println!();
+                process::exit(0);
+            }
+            let mut words = line.split_whitespace();
+            let pause = match (words.next().unwrap_or(""), words.next()) {
+                ("s", None) | ("step", None) => Some(Pause::Step),
+                ("n", None) | ("next", None) => Some(Pause::Next),
+                ("c", None) | ("continue", None) => Some(Pause::Breakpoint),
+                ("b", Some(function)) | ("break", Some(function)) => {
+                    self.breakpoints.insert(function.to_string());
+                    None
+                }
+                ("d", Some(function)) | ("delete", Some(function)) => {
+                    if !self.breakpoints.remove(function) {
+                        eprintln!("error: no breakpoint on `{}`", function);
+                    }
+                    None
+                }
+                ("bt", None) | ("backtrace", None) => {
+                    for (i, call) in self.calls.iter().rev().enumerate() {
+                        println!("#{} {}", i, call);
+                    }
+                    None
+                }
+                ("vars", None) => {
+                    for (name, value) in &self.variables {
+                        println!("{} = {}", name, value);
+                    }
+                    None
+                }
+                ("q", None) | ("quit", None) => process::exit(0),
+                ("", None) | ("h", None) | ("help", None) => {
+                    println!("{}", DEBUG_HELP);
+                    None
+                }
+                _ => {
+                    eprintln!("error: unknown command `{}`; try help", line.trim());
+                    None
+                }
+            };
+            if let Some(pause) = pause {
+                self.pause = pause;
+                return;
+            }
+        }
+    }
+}
+
+impl<N: Number> Observer<N> for Debugger {
+    fn statement_start(&mut self, statement: &math::Statement) {
+        // Statements from imported files are not in `source`, so are only
+        // named.
+        if self.pause != Pause::Breakpoint {
+            let heading = format!("paused before `{}`", statement.name());
+            print!("{}", statement.span().render(&self.source, &heading));
+            self.prompt();
+        }
+    }
+
+    fn statement_end(&mut self, statement: &math::Name, value: Result<Option<&N>, &Error>) {
+        if let Ok(Some(value)) = value {
+            self.variables.insert(statement.to_string(), value.to_string());
+            if self.pause != Pause::Breakpoint {
+                println!("{} = {}", statement, value);
+            }
+        }
+    }
+
+    fn function_entry(&mut self, function: &math::Name, args: &[Value<N>]) {
+        let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+        self.calls.push(format!("{}({})", function, args.join(", ")));
+        if self.pause == Pause::Step || self.breakpoints.contains(function.as_str()) {
+            println!("paused in {}", self.calls.last().unwrap());
+            self.prompt();
+        }
+    }
+
+    fn function_return(&mut self, _function: &math::Name, result: Result<&Value<N>, &Error>) {
+        let call = self.calls.pop().unwrap();
+        if self.pause == Pause::Step {
+            match result {
+                Ok(value) => println!("{} returned {}", call, value),
+                Err(e) => println!("{} failed: {}", call, e),
+            }
+        }
+    }
+
+    fn operation(&mut self, operator: math::Operator, lhs: &N, rhs: &N, result: Result<&N, &Error>) {
+        if self.pause == Pause::Step {
+            if let Ok(value) = result {
+                println!("{} {} {} = {}", lhs, operator, rhs, value);
+            }
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut source = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut source))
+        .map_err(|e| format!("could not read {}: {}", path, e))?;
+    return Ok(source);
 }
@@ -2,6 +2,14 @@
 
 #[macro_use]
 extern crate nom;
+extern crate im_rc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 pub mod math;
 
@@ -0,0 +1,654 @@
+use super::*;
+use super::executor::{builtins, LIST_BUILTINS};
+use super::symbolic;
+use std::collections::{BTreeSet, HashSet};
+
+/// A problem that `check` found in a program, and the part of the source it
+/// is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    UndefinedVariable(Name),
+    UndefinedFunction(Name),
+    /// A name that is only defined later in the program, which `reorder` can
+    /// fix. Calling a function counts as using everything it calls in turn.
+    UsedBeforeDefinition(Name),
+    /// (function, number of params, number of args)
+    ArityMismatch(Name, usize, usize),
+    /// Definitions that each depend on the next and the last on the first, in
+    /// source order. Functions that merely call each other are not a cycle.
+    Cycle(Vec<Name>),
+    UnusedFunction(Name),
+    /// An assignment whose value is replaced before anything reads it.
+    UnusedAssignment(Name),
+}
+
+impl Diagnostic {
+    /// Whether the program would fail when run. Anything else is a warning.
+    pub fn is_error(&self) -> bool {
+        match self.kind {
+            DiagnosticKind::UnusedFunction(_) | DiagnosticKind::UnusedAssignment(_) => false,
+            _ => true,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self.kind {
+            DiagnosticKind::UndefinedVariable(ref name) => format!("`{}` is never assigned", name),
+            DiagnosticKind::UndefinedFunction(ref name) => format!("`{}` is never defined", name),
+            DiagnosticKind::UsedBeforeDefinition(ref name) => {
+                format!("`{}` is used before it is defined", name)
+            }
+            DiagnosticKind::ArityMismatch(ref name, params, args) => format!(
+                "`{}` takes {} argument(s) but {} were given",
+                name, params, args
+            ),
+            DiagnosticKind::Cycle(ref names) => {
+                let names: Vec<_> = names.iter().map(|name| format!("`{}`", name)).collect();
+                format!("{} depend on each other", names.join(", "))
+            }
+            DiagnosticKind::UnusedFunction(ref name) => format!("`{}` is never called", name),
+            DiagnosticKind::UnusedAssignment(ref name) => {
+                format!("`{}` is reassigned before this value is read", name)
+            }
+        }
+    }
+
+    pub fn render(&self, source: &[u8]) -> String {
+        self.span.render(source, &self.heading())
+    }
+
+    /// Like `render`, but names the file at `path` that `source` was read
+    /// from.
+    pub fn render_in(&self, source: &[u8], path: &str) -> String {
+        self.span.render_in(source, path, &self.heading())
+    }
+
+    fn heading(&self) -> String {
+        let severity = if self.is_error() { "error" } else { "warning" };
+        format!("{}: {}", severity, self.message())
+    }
+}
+
+/// Finds the ways a program would fail before running it, along with some
+/// things that are likely to be mistakes, in source order.
+///
+/// Every use of a name is resolved to the most recent earlier definition of it,
+/// or failing that to the first later one. Calls may also resolve to a builtin
+/// from `builtins::standard()`, or to a variable assigned a function. Naming a
+/// function without calling it passes it as a value, which counts as a call.
+///
+/// Imported files are not read, so an import is taken to define every name in
+/// its namespace, with any number of params. Check the statements that an
+/// `import::Loader` gives to look into them too.
+pub fn check(statements: &[Statement]) -> Vec<Diagnostic> {
+    let mut analysis = Analysis::new(statements);
+    analysis.analyse();
+    analysis.diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    analysis.diagnostics
+}
+
+/// Orders statements so that everything is defined before it is used, keeping
+/// them in source order wherever that already works. Fails with the cycles if
+/// there is no such order.
+pub fn reorder(statements: Vec<Statement>) -> Result<Vec<Statement>, Vec<Diagnostic>> {
+    let order = {
+        let mut analysis = Analysis::new(&statements);
+        analysis.analyse();
+        let cycles: Vec<_> = analysis
+            .diagnostics
+            .into_iter()
+            .filter(|diagnostic| match diagnostic.kind {
+                DiagnosticKind::Cycle(_) => true,
+                _ => false,
+            })
+            .collect();
+        if !cycles.is_empty() {
+            return Err(cycles);
+        }
+        topological_order(&statements, &analysis.after)?
+    };
+    let mut statements: Vec<_> = statements.into_iter().map(Some).collect();
+    Ok(order.into_iter().map(|i| statements[i].take().unwrap()).collect())
+}
+
+// Always runs the earliest statement that is ready, so that a program with no
+// forward references keeps its order.
+//
+// Even without a cycle of dependencies there may be no order, as in
+// `a = 1; b = a + c; a = 2; c = a;`, where `b` needs the first `a` but also
+// `c`, which needs the second. That is reported as a cycle of what is left.
+fn topological_order(
+    statements: &[Statement],
+    after: &[BTreeSet<usize>],
+) -> Result<Vec<usize>, Vec<Diagnostic>> {
+    let mut order = vec![];
+    let mut done = vec![false; after.len()];
+    while order.len() < after.len() {
+        match (0..after.len()).find(|&i| !done[i] && after[i].iter().all(|&j| done[j])) {
+            Some(i) => {
+                done[i] = true;
+                order.push(i);
+            }
+            None => {
+                let stuck: Vec<_> = (0..after.len()).filter(|&i| !done[i]).collect();
+                return Err(vec![Diagnostic {
+                    kind: DiagnosticKind::Cycle(
+                        stuck.iter().map(|&i| statements[i].name().clone()).collect(),
+                    ),
+                    span: statements[stuck[0]].span(),
+                }]);
+            }
+        }
+    }
+    Ok(order)
+}
+
+enum Use<'a> {
+    Variable(&'a Name, Span),
+    // The number of args, if the call has to have as many as the function has
+    // params.
+    Call(&'a Name, Option<usize>, Span),
+}
+
+struct Analysis<'a> {
+    statements: &'a [Statement],
+    builtins: HashMap<Name, usize>,
+    diagnostics: Vec<Diagnostic>,
+    // The statements each statement reads from, with the name and the span of
+    // the use that needs each of them. A statement that calls a function
+    // depends on everything that function calls, directly or not.
+    dependencies: Vec<Vec<(usize, &'a Name, Span)>>,
+    // The functions each function's body calls. Calls are resolved when they
+    // happen rather than when the function is defined, so these do not order
+    // the definitions themselves.
+    calls: Vec<Vec<usize>>,
+    used: Vec<bool>,
+    // Everything each statement must come after: its dependencies, earlier
+    // definitions of the same name, and earlier readers of the definition it
+    // replaces.
+    after: Vec<BTreeSet<usize>>,
+}
+
+impl<'a> Analysis<'a> {
+    fn new(statements: &'a [Statement]) -> Analysis<'a> {
+        Analysis {
+            statements,
+            builtins: builtins::standard::<i64>()
+                .into_iter()
+                .map(|(name, builtin)| (name, builtin.arity))
+                .chain(LIST_BUILTINS.iter().map(|&(name, arity)| (Name(name.to_string()), arity)))
+                .collect(),
+            diagnostics: vec![],
+            dependencies: vec![vec![]; statements.len()],
+            calls: vec![vec![]; statements.len()],
+            used: vec![false; statements.len()],
+            after: vec![BTreeSet::new(); statements.len()],
+        }
+    }
+
+    fn analyse(&mut self) {
+        let statements = self.statements;
+        let mut direct_calls = vec![];
+        for (i, statement) in statements.iter().enumerate() {
+            let mut uses = vec![];
+            match *statement {
+                Statement::VarAssignment(_, ref expr, _) => uses_of(&[], expr, &mut uses),
+                Statement::FnDefinition(_, ref params, ref expr, _) => uses_of(params, expr, &mut uses),
+                Statement::Import(..) => {}
+            }
+            for u in uses {
+                match u {
+                    Use::Variable(name, span) => match self.resolve(name, i, false) {
+                        Some(j) => self.depend(i, j, name, span),
+                        None if self.resolve(name, i, true).is_some() || self.builtins.contains_key(name) => {
+                            self.call(i, name, None, span, &mut direct_calls)
+                        }
+                        None => self.report(DiagnosticKind::UndefinedVariable(name.clone()), span),
+                    },
+                    Use::Call(name, args, span) => self.call(i, name, args, span, &mut direct_calls),
+                }
+            }
+        }
+
+        for (i, j, name, span) in direct_calls {
+            for k in self.called_from(j) {
+                let name = if k == j { name } else { self.statements[k].name() };
+                self.dependencies[i].push((k, name, span));
+                self.after[i].insert(k);
+            }
+        }
+
+        self.order_redefinitions();
+        self.report_forward_references();
+        self.report_cycles();
+        self.report_unused();
+    }
+
+    // The latest definition of `name` before statement `i`, or else the first
+    // one after it.
+    fn resolve(&self, name: &Name, i: usize, function: bool) -> Option<usize> {
+        let defines = |j: &usize| match self.statements[*j] {
+            Statement::Import(_, ref namespace, _) => in_namespace(name, namespace),
+            ref statement => statement.name() == name && statement.is_function() == function,
+        };
+        (0..i).rev().find(&defines).or_else(|| (i + 1..self.statements.len()).find(&defines))
+    }
+
+    fn depend(&mut self, i: usize, j: usize, name: &'a Name, span: Span) {
+        self.used[j] = true;
+        self.dependencies[i].push((j, name, span));
+        self.after[i].insert(j);
+    }
+
+    fn call(
+        &mut self,
+        i: usize,
+        name: &'a Name,
+        args: Option<usize>,
+        span: Span,
+        direct_calls: &mut Vec<(usize, usize, &'a Name, Span)>,
+    ) {
+        // A function can call itself, and a builtin is only shadowed once a
+        // definition has actually run.
+        let recursive = match self.statements[i] {
+            Statement::FnDefinition(ref defined, _, _, _) => defined == name,
+            _ => false,
+        };
+        let target = match self.resolve(name, i, true) {
+            _ if recursive => Some(i),
+            Some(j) if j < i || !self.builtins.contains_key(name) => Some(j),
+            _ => None,
+        };
+        let params = match (target, self.builtins.get(name)) {
+            (Some(j), _) => match self.statements[j] {
+                Statement::FnDefinition(_, ref params, _, _) => Some(params.len()),
+                Statement::Import(..) => None,
+                Statement::VarAssignment(..) => unreachable!(),
+            },
+            (None, Some(&arity)) => Some(arity),
+            (None, None) => {
+                return match self.resolve(name, i, false) {
+                    Some(j) => self.depend(i, j, name, span),
+                    None => self.report(DiagnosticKind::UndefinedFunction(name.clone()), span),
+                };
+            }
+        };
+        match (args, params) {
+            (Some(args), Some(params)) if args != params => {
+                self.report(DiagnosticKind::ArityMismatch(name.clone(), params, args), span);
+            }
+            _ => {}
+        }
+        if let Some(j) = target {
+            if j != i {
+                self.used[j] = true;
+            }
+            if self.statements[i].is_function() {
+                self.calls[i].push(j);
+            } else {
+                direct_calls.push((i, j, name, span));
+            }
+        }
+    }
+
+    // `j` and every function that calling it might call in turn.
+    fn called_from(&self, j: usize) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![j];
+        let mut called = vec![];
+        while let Some(k) = pending.pop() {
+            if seen.insert(k) {
+                called.push(k);
+                pending.extend(self.calls[k].iter().cloned());
+            }
+        }
+        called
+    }
+
+    // Definitions of the same name keep their order, so that the last one is
+    // still the one left at the end, and a definition that replaces another
+    // stays after everything that reads the one it replaces.
+    fn order_redefinitions(&mut self) {
+        for k in 0..self.statements.len() {
+            for j in 0..k {
+                let (a, b) = (&self.statements[j], &self.statements[k]);
+                if a.name() == b.name() && a.is_function() == b.is_function() {
+                    self.after[k].insert(j);
+                    for i in 0..self.statements.len() {
+                        if i != k && self.dependencies[i].iter().any(|&(d, _, _)| d == j) {
+                            self.after[k].insert(i);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn report_forward_references(&mut self) {
+        for i in 0..self.statements.len() {
+            let mut reported = HashSet::new();
+            for d in 0..self.dependencies[i].len() {
+                let (j, name, span) = self.dependencies[i][d];
+                if j > i && reported.insert(j) {
+                    self.report(DiagnosticKind::UsedBeforeDefinition(name.clone()), span);
+                }
+            }
+        }
+    }
+
+    // Tarjan's algorithm, over dependencies rather than over every ordering
+    // constraint so that each cycle is one the program itself contains.
+    fn report_cycles(&mut self) {
+        struct Tarjan<'b> {
+            edges: &'b [Vec<usize>],
+            index: Vec<Option<usize>>,
+            lowlink: Vec<usize>,
+            stack: Vec<usize>,
+            on_stack: Vec<bool>,
+            next: usize,
+            components: Vec<Vec<usize>>,
+        }
+
+        impl<'b> Tarjan<'b> {
+            fn visit(&mut self, v: usize) {
+                self.index[v] = Some(self.next);
+                self.lowlink[v] = self.next;
+                self.next += 1;
+                self.stack.push(v);
+                self.on_stack[v] = true;
+                for &w in &self.edges[v] {
+                    match self.index[w] {
+                        None => {
+                            self.visit(w);
+                            self.lowlink[v] = self.lowlink[v].min(self.lowlink[w]);
+                        }
+                        Some(index) if self.on_stack[w] => {
+                            self.lowlink[v] = self.lowlink[v].min(index);
+                        }
+                        Some(_) => {}
+                    }
+                }
+                if Some(self.lowlink[v]) == self.index[v] {
+                    let mut component = vec![];
+                    loop {
+                        let w = self.stack.pop().unwrap();
+                        self.on_stack[w] = false;
+                        component.push(w);
+                        if w == v {
+                            break;
+                        }
+                    }
+                    self.components.push(component);
+                }
+            }
+        }
+
+        let edges: Vec<Vec<usize>> = self
+            .dependencies
+            .iter()
+            .map(|dependencies| dependencies.iter().map(|&(j, _, _)| j).collect())
+            .collect();
+        let n = edges.len();
+        let mut tarjan = Tarjan {
+            edges: &edges,
+            index: vec![None; n],
+            lowlink: vec![0; n],
+            stack: vec![],
+            on_stack: vec![false; n],
+            next: 0,
+            components: vec![],
+        };
+        for v in 0..n {
+            if tarjan.index[v].is_none() {
+                tarjan.visit(v);
+            }
+        }
+        for mut component in tarjan.components {
+            if component.len() < 2 {
+                continue;
+            }
+            component.sort();
+            let names = component.iter().map(|&i| self.statements[i].name().clone()).collect();
+            let span = self.statements[component[0]].span();
+            self.report(DiagnosticKind::Cycle(names), span);
+        }
+    }
+
+    fn report_unused(&mut self) {
+        for (i, statement) in self.statements.iter().enumerate() {
+            let name = statement.name();
+            match *statement {
+                _ if self.used[i] => {}
+                Statement::Import(..) => {}
+                Statement::FnDefinition(..) => {
+                    self.report(DiagnosticKind::UnusedFunction(name.clone()), statement.span());
+                }
+                Statement::VarAssignment(..) if self.resolve_later(name, i) => {
+                    self.report(DiagnosticKind::UnusedAssignment(name.clone()), statement.span());
+                }
+                Statement::VarAssignment(..) => {}
+            }
+        }
+    }
+
+    fn resolve_later(&self, name: &Name, i: usize) -> bool {
+        self.statements[i + 1..]
+            .iter()
+            .any(|statement| statement.name() == name && !statement.is_function())
+    }
+
+    fn report(&mut self, kind: DiagnosticKind, span: Span) {
+        self.diagnostics.push(Diagnostic { kind, span });
+    }
+}
+
+impl Statement {
+    fn is_function(&self) -> bool {
+        match *self {
+            Statement::VarAssignment(..) | Statement::Import(..) => false,
+            Statement::FnDefinition(..) => true,
+        }
+    }
+}
+
+// Whether `name` is qualified by `namespace`, as `geometry.area` is.
+fn in_namespace(name: &Name, namespace: &Name) -> bool {
+    let name = name.as_str();
+    name.len() > namespace.as_str().len()
+        && name.starts_with(namespace.as_str())
+        && name.as_bytes()[namespace.as_str().len()] == b'.'
+}
+
+// Every name an expression reads, other than the params of the function it is
+// the body of.
+fn uses_of<'a>(params: &[Name], expr: &'a Expression, uses: &mut Vec<Use<'a>>) {
+    match *expr {
+        Expression::Operand(Operand::I64(_), _) | Expression::Operand(Operand::F64(_), _) => {}
+        Expression::Operand(Operand::VarSubstitution(ref name), span) => {
+            if !params.contains(name) {
+                uses.push(Use::Variable(name, span));
+            }
+        }
+        // `d(f, x)` uses `f`, and passes it args of its own unless it has none.
+        Expression::Operand(Operand::FnApplication(ref name, ref args), span) => {
+            match symbolic::derivative_call(name, args) {
+                Some((function, _, point)) => {
+                    let args = if point.is_empty() { None } else { Some(point.len()) };
+                    uses.push(Use::Call(function, args, span));
+                    for arg in point {
+                        uses_of(params, arg, uses);
+                    }
+                    return;
+                }
+                // A param may be passed any function, so calls of it are only
+                // checked where they happen.
+                None if params.contains(name) => {}
+                None => uses.push(Use::Call(name, Some(args.len()), span)),
+            }
+            for arg in args {
+                uses_of(params, arg, uses);
+            }
+        }
+        Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+            uses_of(params, condition, uses);
+            uses_of(params, consequent, uses);
+            uses_of(params, alternative, uses);
+        }
+        Expression::Operand(Operand::Lambda(ref inner, ref body), _) => {
+            let params: Vec<Name> = params.iter().chain(inner).cloned().collect();
+            uses_of(&params, body, uses);
+        }
+        Expression::Operand(Operand::List(ref items), _) => {
+            for item in items {
+                uses_of(params, item, uses);
+            }
+        }
+        Expression::Operand(Operand::Call(ref callee, ref args), _) => {
+            uses_of(params, callee, uses);
+            for arg in args {
+                uses_of(params, arg, uses);
+            }
+        }
+        Expression::Operation(ref lhs, _, ref rhs, _) => {
+            uses_of(params, lhs, uses);
+            uses_of(params, rhs, uses);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+
+    fn kinds(s: &[u8]) -> Vec<DiagnosticKind> {
+        check(&parser::parse(s).unwrap()).into_iter().map(|d| d.kind).collect()
+    }
+
+    #[test]
+    fn diagnostics_test() {
+        assert_eq!(
+            kinds(b"a = b + 1;\nf(x) = g(x) + y;\nc = f(1, 2) + abs(1, 2);\nc = 1;"),
+            vec![
+                DiagnosticKind::UndefinedVariable(name("b")),
+                DiagnosticKind::UndefinedFunction(name("g")),
+                DiagnosticKind::UndefinedVariable(name("y")),
+                DiagnosticKind::UnusedAssignment(name("c")),
+                DiagnosticKind::ArityMismatch(name("f"), 1, 2),
+                DiagnosticKind::ArityMismatch(name("abs"), 1, 2),
+            ]
+        );
+        assert_eq!(
+            kinds(b"f(x) = x;\ng(x) = g(x - 1);\na = 1;\nb = a;\na = 2;"),
+            vec![
+                DiagnosticKind::UnusedFunction(name("f")),
+                DiagnosticKind::UnusedFunction(name("g")),
+            ]
+        );
+        assert_eq!(
+            kinds(b"f(x) = x * x;\ng(x) = d(f, x);\na = d(f, x, 1, 2) + d(h, x) + g(1);"),
+            vec![
+                DiagnosticKind::ArityMismatch(name("f"), 1, 2),
+                DiagnosticKind::UndefinedFunction(name("h")),
+            ]
+        );
+    }
+
+    #[test]
+    fn function_values_test() {
+        let source = b"apply(f, x) = f(x);\nadd(n) = \\x -> x + n + y;\n\
+                       a = apply(abs, -1) + apply(add(1), 2);\nb = apply(g, 1);\nc = map(\\x -> x, [a, b]);";
+        assert_eq!(
+            kinds(source),
+            vec![DiagnosticKind::UndefinedVariable(name("y")), DiagnosticKind::UndefinedVariable(name("g"))]
+        );
+    }
+
+    #[test]
+    fn imports_test() {
+        let source = b"import \"lib/geometry.math\";\na = geometry.area(1, 2) + geometry.pi(2) + geometry.pi;\n\
+                       b = geometry2.pi + shapes.x;";
+        assert_eq!(
+            kinds(source),
+            vec![
+                DiagnosticKind::UndefinedVariable(name("geometry2.pi")),
+                DiagnosticKind::UndefinedVariable(name("shapes.x")),
+            ]
+        );
+    }
+
+    #[test]
+    fn forward_reference_test() {
+        let source = b"g(x) = f(x) * k;\na = g(1);\nf(x) = x + 1;\nk = 3;";
+        let diagnostics = check(&parser::parse(source).unwrap());
+        assert_eq!(
+            diagnostics.iter().map(|d| d.kind.clone()).collect::<Vec<_>>(),
+            vec![
+                DiagnosticKind::UsedBeforeDefinition(name("k")),
+                DiagnosticKind::UsedBeforeDefinition(name("f")),
+            ]
+        );
+        assert_eq!(
+            diagnostics[1].render(source),
+            "error: `f` is used before it is defined\n --> 2:5\n  |\n2 | a = g(1);\n  |     ^^^^\n"
+        );
+
+        let statements = reorder(parser::parse(source).unwrap()).unwrap();
+        assert_eq!(check(&statements), vec![]);
+        assert_eq!(
+            statements,
+            parser::parse(b"f(x) = x + 1;\nk = 3;\ng(x) = f(x) * k;\na = g(1);").unwrap()
+        );
+        assert_eq!(executor::execute::<i64>(statements).unwrap()[&name("a")], 6);
+    }
+
+    #[test]
+    fn reorder_keeps_redefinitions_in_order_test() {
+        let source = b"b = a;\na = 1;\nc = a;\na = 2;\nd = a + e;\ne = 10;";
+        let statements = reorder(parser::parse(source).unwrap()).unwrap();
+        let results = executor::execute::<i64>(statements).unwrap();
+        assert_eq!(
+            (results[&name("a")], results[&name("b")], results[&name("c")], results[&name("d")]),
+            (2, 1, 1, 12)
+        );
+    }
+
+    #[test]
+    fn cycle_test() {
+        let source = b"a = b + 1;\nb = f(2);\nf(x) = x * a;\neven(n) = if n == 0 then 1 else odd(n - 1);\nodd(n) = if n == 0 then 0 else even(n - 1);\nc = even(10);";
+        assert!(kinds(source).contains(&DiagnosticKind::Cycle(vec![name("a"), name("b"), name("f")])));
+        assert_eq!(
+            reorder(parser::parse(source).unwrap()).unwrap_err()[0].kind,
+            DiagnosticKind::Cycle(vec![name("a"), name("b"), name("f")])
+        );
+
+        let source = b"a = 1;\nb = a + c;\na = 2;\nc = a;";
+        assert!(check(&parser::parse(source).unwrap()).iter().all(|d| match d.kind {
+            DiagnosticKind::Cycle(_) => false,
+            _ => true,
+        }));
+        assert_eq!(
+            reorder(parser::parse(source).unwrap()).unwrap_err()[0].kind,
+            DiagnosticKind::Cycle(vec![name("b"), name("a"), name("c")])
+        );
+    }
+
+    // Programs that check finds no errors in already define everything before
+    // using it.
+    #[test]
+    fn reorder_test() {
+        for program in ::math::programs::PROGRAMS {
+            let statements = parser::parse(program.as_bytes()).unwrap();
+            if check(&statements).iter().any(Diagnostic::is_error) {
+                continue;
+            }
+            assert_eq!(reorder(statements.clone()).unwrap(), statements, "{:?}", program);
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use super::*;
+use super::executor::{
+    builtins, evaluate_operation, list_builtin_arity, Builtin, Error, Kind, DEFAULT_MAX_CALL_DEPTH,
+};
+use super::number::Number;
+use super::vm::{free_variables, Compiler, Scope, Slots};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Compiles a program into Rust closures, for formulas that are evaluated too
+/// often to walk their ASTs each time. Like the bytecode VM it computes with
+/// `i64`s, has the builtins of `builtins::standard()` but not `d`, and fails
+/// with `Error::Unsupported` on lambdas and lists. It also fails that way
+/// wherever the executor would use a function as a value: a function or
+/// builtin named without being called, or a call of what an expression gives.
+/// Programs that stay clear of all of these give the results the executor
+/// does, and fail with the errors it does.
+///
+/// Nothing runs until `CompiledProgram::run`, which defines what `call` calls.
+pub fn compile(statements: &[Statement]) -> CompiledProgram {
+    let mut compiler = Compiler {
+        globals: Slots::default(),
+        functions: Slots::default(),
+    };
+    let steps = statements
+        .iter()
+        .map(|statement| compiler.step(statement))
+        .collect();
+    let standard = builtins::standard();
+    CompiledProgram {
+        max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        steps,
+        globals: vec![None; compiler.globals.names.len()],
+        global_names: compiler.globals.names,
+        functions: vec![None; compiler.functions.names.len()],
+        builtins: compiler.functions.names.iter().map(|name| standard.get(name).cloned()).collect(),
+        standard,
+        function_slots: compiler
+            .functions
+            .indices
+            .into_iter()
+            .map(|(name, slot)| (name.0, slot))
+            .collect(),
+        function_names: compiler.functions.names,
+    }
+}
+
+/// Every name is resolved to a slot when the program is compiled: globals and
+/// functions to slots in the program, params to indices into the args of the
+/// call, and the other names a function body uses to indices into the values
+/// it captured. Calls still go through the function slots, so that they reach
+/// whichever definition is current, as they do in the executor.
+pub struct CompiledProgram {
+    /// How many calls may be in progress at once. Each takes a few native
+    /// stack frames.
+    pub max_call_depth: usize,
+    steps: Vec<Step>,
+    globals: Vec<Option<i64>>,
+    global_names: Vec<Name>,
+    functions: Vec<Option<Closure>>,
+    builtins: Vec<Option<Builtin<i64>>>,
+    // What `call` calls for names the program never uses.
+    standard: HashMap<Name, Builtin<i64>>,
+    function_slots: HashMap<String, usize>,
+    function_names: Vec<Name>,
+}
+
+impl CompiledProgram {
+    /// Runs the program's statements in order, forgetting anything an earlier
+    /// run defined.
+    pub fn run(&mut self) -> Result<(), Error> {
+        for global in &mut self.globals {
+            *global = None;
+        }
+        for function in &mut self.functions {
+            *function = None;
+        }
+        for step in &self.steps {
+            match *step {
+                Step::Assign(ref statement, slot, ref code) => {
+                    let value = {
+                        let mut machine = Machine {
+                            statement,
+                            globals: &self.globals,
+                            functions: &self.functions,
+                            builtins: &self.builtins,
+                            depth: 0,
+                            max_call_depth: self.max_call_depth,
+                        };
+                        code(&mut machine, &Env::global())?
+                    };
+                    self.globals[slot] = Some(value);
+                }
+                Step::Define(slot, ref function) => {
+                    let captures = function.captures.iter().map(|&g| self.globals[g]).collect();
+                    self.functions[slot] = Some(Closure {
+                        function: function.clone(),
+                        captures: Rc::new(captures),
+                    });
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// The value of every global that has been assigned.
+    pub fn variables(&self) -> HashMap<Name, i64> {
+        self.global_names
+            .iter()
+            .zip(&self.globals)
+            .filter_map(|(name, value)| value.map(|value| (name.clone(), value)))
+            .collect()
+    }
+
+    /// Calls the function `name` as defined when the program last ran, or the
+    /// builtin of that name if the program never defined one. Errors name the
+    /// function as their statement.
+    pub fn call(&self, name: &str, args: &[i64]) -> Result<i64, Error> {
+        let slot = match self.function_slots.get(name) {
+            Some(&slot) => slot,
+            None => {
+                let name = Name(name.to_string());
+                return match self.standard.get(&name) {
+                    Some(builtin) => call_builtin(&name, &name, builtin, args),
+                    None => Err(Error::UnknownFunction(name.clone(), name)),
+                };
+            }
+        };
+        let statement = &self.function_names[slot];
+        let mut machine = Machine {
+            statement,
+            globals: &self.globals,
+            functions: &self.functions,
+            builtins: &self.builtins,
+            depth: 0,
+            max_call_depth: self.max_call_depth,
+        };
+        machine.check_call(slot, statement, args.len(), None)?;
+        machine.call(slot, statement, args)
+    }
+}
+
+enum Step {
+    Assign(Name, usize, Code),
+    Define(usize, Rc<Function>),
+}
+
+struct Function {
+    params: usize,
+    /// The global slots to capture when the function is defined.
+    captures: Vec<usize>,
+    body: Code,
+}
+
+#[derive(Clone)]
+struct Closure {
+    function: Rc<Function>,
+    captures: Rc<Vec<Option<i64>>>,
+}
+
+// A compiled expression, which evaluates it in the environment it is given.
+type Code = Box<dyn Fn(&mut Machine, &Env) -> Result<i64, Error>>;
+
+// What evaluation shares, however deep in calls it is.
+struct Machine<'a> {
+    statement: &'a Name,
+    globals: &'a [Option<i64>],
+    functions: &'a [Option<Closure>],
+    builtins: &'a [Option<Builtin<i64>>],
+    depth: usize,
+    max_call_depth: usize,
+}
+
+// What the function being evaluated can see besides globals.
+struct Env<'a> {
+    args: &'a [i64],
+    captures: &'a [Option<i64>],
+}
+
+impl Env<'static> {
+    fn global() -> Env<'static> {
+        Env {
+            args: &[],
+            captures: &[],
+        }
+    }
+}
+
+impl<'a> Machine<'a> {
+    // Fails as the executor does when a call cannot be made, before its args
+    // are evaluated. At the top level, a call of a name that is neither a
+    // function nor a builtin is of the global in the slot given, if it has one.
+    fn check_call(&self, slot: usize, name: &Name, args: usize, global: Option<usize>) -> Result<(), Error> {
+        let params = match (&self.functions[slot], &self.builtins[slot]) {
+            (&Some(ref closure), _) => closure.function.params,
+            (&None, &Some(ref builtin)) => builtin.arity,
+            (&None, &None) if list_builtin_arity(name).is_some() => {
+                return Err(Error::Unsupported(self.statement.clone(), "lists".to_string()))
+            }
+            (&None, &None) if global.map_or(false, |global| self.globals[global].is_some()) => {
+                return Err(called_number(self.statement))
+            }
+            (&None, &None) => return Err(Error::UnknownFunction(self.statement.clone(), name.clone())),
+        };
+        if params != args {
+            return Err(Error::ArityMismatch(self.statement.clone(), name.clone(), params, args));
+        }
+        return Ok(());
+    }
+
+    fn call(&mut self, slot: usize, name: &Name, args: &[i64]) -> Result<i64, Error> {
+        let functions = self.functions;
+        let closure = match functions[slot] {
+            Some(ref closure) => closure,
+            None => {
+                let builtin = self.builtins[slot].as_ref().unwrap();
+                return call_builtin(self.statement, name, builtin, args);
+            }
+        };
+        if self.depth >= self.max_call_depth {
+            return Err(Error::CallDepthExceeded(self.statement.clone(), name.clone()));
+        }
+        self.depth += 1;
+        let env = Env {
+            args,
+            captures: &closure.captures,
+        };
+        let result = (closure.function.body)(self, &env);
+        self.depth -= 1;
+        return result;
+    }
+}
+
+fn call_builtin(statement: &Name, name: &Name, builtin: &Builtin<i64>, args: &[i64]) -> Result<i64, Error> {
+    if args.len() != builtin.arity {
+        return Err(Error::ArityMismatch(statement.clone(), name.clone(), builtin.arity, args.len()));
+    }
+    (builtin.function)(args).map_err(|message| Error::BuiltinFailed(statement.clone(), name.clone(), message))
+}
+
+// Compiling to closures rather than to bytecode, with the VM's scopes and
+// slots.
+impl Compiler {
+    fn step(&mut self, statement: &Statement) -> Step {
+        match *statement {
+            Statement::VarAssignment(ref name, ref expr, _) => {
+                let code = self.code(expr, &Scope::Global);
+                Step::Assign(name.clone(), self.globals.slot(name), code)
+            }
+            Statement::FnDefinition(ref name, ref params, ref expr, _) => {
+                let mut free = vec![];
+                free_variables(params, expr, &mut free);
+                let body = self.code(expr, &Scope::Function(params, &free));
+                let function = Function {
+                    params: params.len(),
+                    captures: free.iter().map(|name| self.globals.slot(name)).collect(),
+                    body,
+                };
+                Step::Define(self.functions.slot(name), Rc::new(function))
+            }
+            Statement::Import(_, ref namespace, _) => {
+                let code = unsupported("unresolved imports");
+                Step::Assign(namespace.clone(), self.globals.slot(namespace), code)
+            }
+        }
+    }
+
+    fn code(&mut self, expr: &Expression, scope: &Scope) -> Code {
+        match *expr {
+            Expression::Operand(ref operand, _) => self.operand_code(operand, scope),
+            Expression::Operation(ref lhs, operator, ref rhs, _) => {
+                let lhs = self.code(lhs, scope);
+                let rhs = self.code(rhs, scope);
+                Box::new(move |machine, env| {
+                    let lhs = lhs(machine, env)?;
+                    let rhs = rhs(machine, env)?;
+                    evaluate_operation(machine.statement, operator, lhs, rhs)
+                })
+            }
+        }
+    }
+
+    fn operand_code(&mut self, operand: &Operand, scope: &Scope) -> Code {
+        match *operand {
+            Operand::I64(value) => Box::new(move |_, _| Ok(value)),
+            Operand::F64(value) => match i64::from_f64(value) {
+                Some(value) => Box::new(move |_, _| Ok(value)),
+                None => {
+                    let literal = format!("{:?}", value);
+                    Box::new(move |machine, _| Err(Error::NotRepresentable(machine.statement.clone(), literal.clone())))
+                }
+            },
+            Operand::VarSubstitution(ref name) => self.variable_code(name, scope),
+            // Args are only ever numbers.
+            Operand::FnApplication(ref name, _) if is_param(name, scope) => {
+                Box::new(|machine, _| Err(called_number(machine.statement)))
+            }
+            Operand::FnApplication(ref name, ref args) => {
+                let slot = self.functions.slot(name);
+                // A function calls a variable that it captured in place of
+                // any function of that name, as the executor's do.
+                let (capture, global) = match *scope {
+                    Scope::Global => (None, Some(self.globals.slot(name))),
+                    Scope::Function(_, free) => (free.iter().position(|f| f == name), None),
+                };
+                let args: Vec<Code> = args.iter().map(|arg| self.code(arg, scope)).collect();
+                let name = name.clone();
+                Box::new(move |machine, env| {
+                    if capture.map_or(false, |i| env.captures[i].is_some()) {
+                        return Err(called_number(machine.statement));
+                    }
+                    machine.check_call(slot, &name, args.len(), global)?;
+                    // Most functions take only a few args, which need not be
+                    // allocated for.
+                    let mut small = [0; 4];
+                    let mut large = vec![];
+                    let values = if args.len() <= small.len() {
+                        &mut small[..args.len()]
+                    } else {
+                        large.resize(args.len(), 0);
+                        &mut large[..]
+                    };
+                    for (value, arg) in values.iter_mut().zip(&args) {
+                        *value = arg(machine, env)?;
+                    }
+                    machine.call(slot, &name, values)
+                })
+            }
+            Operand::If(ref condition, ref consequent, ref alternative) => {
+                let condition = self.code(condition, scope);
+                let consequent = self.code(consequent, scope);
+                let alternative = self.code(alternative, scope);
+                Box::new(move |machine, env| {
+                    if condition(machine, env)? != 0 {
+                        consequent(machine, env)
+                    } else {
+                        alternative(machine, env)
+                    }
+                })
+            }
+            Operand::Lambda(..) => unsupported("lambdas"),
+            Operand::Call(..) => unsupported("function values"),
+            Operand::List(..) => unsupported("lists"),
+        }
+    }
+
+    // A name that is not a variable may be a function, which the executor
+    // would pass as a value.
+    fn variable_code(&mut self, name: &Name, scope: &Scope) -> Code {
+        let function = self.functions.slot(name);
+        let unknown = move |machine: &Machine, name: &Name| {
+            let is_function = machine.functions[function].is_some()
+                || machine.builtins[function].is_some()
+                || list_builtin_arity(name).is_some();
+            if is_function {
+                Error::Unsupported(machine.statement.clone(), "function values".to_string())
+            } else {
+                Error::UnknownVariable(machine.statement.clone(), name.clone())
+            }
+        };
+        let name = name.clone();
+        match *scope {
+            Scope::Global => {
+                let slot = self.globals.slot(&name);
+                Box::new(move |machine, _| machine.globals[slot].ok_or_else(|| unknown(machine, &name)))
+            }
+            Scope::Function(params, free) => match params.iter().position(|param| *param == name) {
+                Some(i) => Box::new(move |_, env| Ok(env.args[i])),
+                None => {
+                    let i = free.iter().position(|f| *f == name).unwrap();
+                    Box::new(move |machine, env| env.captures[i].ok_or_else(|| unknown(machine, &name)))
+                }
+            },
+        }
+    }
+}
+
+fn is_param(name: &Name, scope: &Scope) -> bool {
+    match *scope {
+        Scope::Global => false,
+        Scope::Function(params, _) => params.contains(name),
+    }
+}
+
+fn called_number(statement: &Name) -> Error {
+    Error::WrongType(statement.clone(), Kind::Function, Kind::Number)
+}
+
+fn unsupported(what: &'static str) -> Code {
+    Box::new(move |machine, _| Err(Error::Unsupported(machine.statement.clone(), what.to_string())))
+}
+
+// Like the VM, the compiled program must agree with the executor on the result
+// or on the error of every program here.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+
+    fn run(s: &[u8]) -> (CompiledProgram, Result<(), Error>) {
+        let mut program = compile(&parser::parse(s).unwrap());
+        let result = program.run();
+        return (program, result);
+    }
+
+    #[test]
+    fn differential_test() {
+        for source in ::math::programs::PROGRAMS {
+            let statements = parser::parse(source.as_bytes()).unwrap();
+            let mut program = compile(&statements);
+            let compiled = program.run().map(|()| program.variables());
+            assert_eq!(
+                compiled,
+                executor::execute(statements.clone()),
+                "backends disagree on {:?}",
+                source
+            );
+        }
+    }
+
+    // Programs that use functions as values, which the executor runs and
+    // which fail to run compiled rather than giving a different result.
+    #[test]
+    fn function_values_test() {
+        let programs: &[(&[u8], &str, &str)] = &[
+            (b"f(x) = x + 1;\ng = f;\na = g(1);", "g", "function values"),
+            (b"f(h, x) = h(x);\ninc(x) = x + 1;\na = f(inc, 1);", "a", "function values"),
+            (b"f(h, x) = h(x);\na = f(abs, -1);", "a", "function values"),
+            (b"f(x) = g;\ng(x) = x;\na = f(1)(2);", "a", "function values"),
+            (b"a = max;", "a", "function values"),
+            (b"a = len(range(1, 3));", "a", "lists"),
+        ];
+        for &(source, statement, what) in programs {
+            let statements = parser::parse(source).unwrap();
+            assert!(executor::execute::<i64>(statements.clone()).is_ok(), "{:?}", source);
+            let mut program = compile(&statements);
+            let error = Error::Unsupported(name(statement), what.to_string());
+            assert_eq!(program.run(), Err(error), "{:?}", source);
+        }
+        let (_, result) = run(b"a = 1;\nb = a(2);");
+        assert_eq!(result, Err(Error::WrongType(name("b"), Kind::Function, Kind::Number)));
+        let (_, result) = run(b"f(x) = x;\na = g;");
+        assert_eq!(result, Err(Error::UnknownVariable(name("a"), name("g"))));
+    }
+
+    #[test]
+    fn call_test() {
+        let (program, result) = run(b"k = 10;\nf(x, y) = k * x + y;\nk = 20;\ng(x) = f(x, k) + h(1, 2, 3, 4, 5);\n\
+                                      h(a, b, c, d, e) = a + b + c + d + e;");
+        result.unwrap();
+        assert_eq!(program.call("f", &[2, 3]), Ok(23));
+        assert_eq!(program.call("g", &[1]), Ok(45));
+        assert_eq!(program.call("max", &[2, 3]), Ok(3));
+        assert_eq!(program.call("f", &[1]), Err(Error::ArityMismatch(name("f"), name("f"), 2, 1)));
+        assert_eq!(program.call("max", &[1]), Err(Error::ArityMismatch(name("max"), name("max"), 2, 1)));
+        assert_eq!(program.call("nope", &[]), Err(Error::UnknownFunction(name("nope"), name("nope"))));
+        assert_eq!(program.call("f", &[i64::max_value(), 0]), Err(Error::Overflow(name("f"), Operator::Multiply)));
+        assert_eq!(program.call("k", &[]), Err(Error::UnknownFunction(name("k"), name("k"))));
+    }
+
+    #[test]
+    fn late_binding_test() {
+        // `g` calls whichever `f` is defined when it runs.
+        let (program, result) = run(b"f(x) = x;\ng(x) = f(x) * 2;\na = g(1);\nf(x) = x + 1;\nb = g(1);");
+        result.unwrap();
+        let variables = program.variables();
+        assert_eq!((variables[&name("a")], variables[&name("b")]), (2, 4));
+        assert_eq!(program.call("g", &[1]), Ok(4));
+    }
+
+    #[test]
+    fn call_depth_test() {
+        let mut program = compile(&parser::parse(b"f(n) = if n == 0 then 0 else f(n - 1);").unwrap());
+        program.max_call_depth = 10;
+        program.run().unwrap();
+        assert_eq!(program.call("f", &[9]), Ok(0));
+        assert_eq!(program.call("f", &[10]), Err(Error::CallDepthExceeded(name("f"), name("f"))));
+    }
+
+    #[test]
+    fn unsupported_test() {
+        let errors: &[(&[u8], Error)] = &[
+            (b"a = 1;\nf = \\x -> x;", Error::Unsupported(name("f"), "lambdas".to_string())),
+            (b"xs = [1];", Error::Unsupported(name("xs"), "lists".to_string())),
+            (b"import \"lib/geometry.math\";", Error::Unsupported(name("geometry"), "unresolved imports".to_string())),
+            (b"a = 1.5;", Error::NotRepresentable(name("a"), "1.5".to_string())),
+        ];
+        for &(source, ref error) in errors {
+            assert_eq!(run(source).1.as_ref(), Err(error), "{:?}", source);
+        }
+        // Calls are only defined once the program has run.
+        let program = compile(&parser::parse(b"f(x) = x;").unwrap());
+        assert_eq!(program.call("f", &[1]), Err(Error::UnknownFunction(name("f"), name("f"))));
+    }
+}
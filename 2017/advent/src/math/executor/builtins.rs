@@ -0,0 +1,96 @@
+use super::*;
+
+/// A function implemented in Rust rather than in math. It fails with a message
+/// saying what went wrong, which the executor reports as
+/// `Error::BuiltinFailed`.
+pub struct Builtin<N> {
+    pub arity: usize,
+    pub function: Rc<dyn Fn(&[N]) -> Result<N, String>>,
+}
+
+impl<N> Clone for Builtin<N> {
+    fn clone(&self) -> Builtin<N> {
+        Builtin {
+            arity: self.arity,
+            function: self.function.clone(),
+        }
+    }
+}
+
+/// The builtins every `Executor` starts with:
+///
+/// - `abs(x)`, `min(a, b)` and `max(a, b)`
+/// - `pow(x, n)`, which integer types only allow for `n >= 0`
+/// - `sqrt(x)`, rounded down for integer types and exact for rationals
+/// - `floor(x)`
+/// - `mod(a, b)`, the remainder of `a / b`, which has the sign of `a`
+/// - `gcd(a, b)`, which is never negative
+///
+/// Executors also understand `d(f, x)`, the derivative of `f` with respect to
+/// its param `x`, which needs to see its args as names rather than as values
+/// and so is not a `Builtin`. It differentiates through calls to the functions
+/// that the program defines as well as to builtins. See `math::symbolic`. Nor
+/// are the builtins over lists, whose args are not all numbers:
+///
+/// - `range(a, b)`, the list of `a` up to but not including `b`
+/// - `len(xs)`
+/// - `map(f, xs)` and `filter(f, xs)`, which keeps the items `f` is nonzero for
+/// - `fold(f, initial, xs)`, which calls `f(accumulator, item)` for each item
+pub fn standard<N: Number>() -> HashMap<Name, Builtin<N>> {
+    let mut builtins = HashMap::new();
+    {
+        let mut register = |name: &str, arity, function: fn(&[N]) -> Result<N, String>| {
+            builtins.insert(
+                Name(name.to_string()),
+                Builtin {
+                    arity,
+                    function: Rc::new(function),
+                },
+            );
+        };
+        register("abs", 1, |args| abs(&args[0]));
+        register("min", 2, |args| {
+            Ok(if args[1] < args[0] { &args[1] } else { &args[0] }.clone())
+        });
+        register("max", 2, |args| {
+            Ok(if args[1] > args[0] { &args[1] } else { &args[0] }.clone())
+        });
+        register("pow", 2, |args| {
+            args[0]
+                .checked_pow(&args[1])
+                .ok_or_else(|| format!("{} to the power of {} is not representable", args[0], args[1]))
+        });
+        register("sqrt", 1, |args| {
+            args[0]
+                .sqrt()
+                .ok_or_else(|| format!("the square root of {} is not representable", args[0]))
+        });
+        register("floor", 1, |args| Ok(args[0].floor()));
+        register("mod", 2, |args| rem(&args[0], &args[1]));
+        register("gcd", 2, |args| {
+            let (mut a, mut b) = (args[0].clone(), args[1].clone());
+            while !b.is_zero() {
+                let r = rem(&a, &b)?;
+                a = b;
+                b = r;
+            }
+            abs(&a)
+        });
+    }
+    builtins
+}
+
+fn abs<N: Number>(x: &N) -> Result<N, String> {
+    if *x < N::from_i64(0) {
+        N::from_i64(0).checked_sub(x).ok_or_else(|| format!("the absolute value of {} overflowed", x))
+    } else {
+        Ok(x.clone())
+    }
+}
+
+fn rem<N: Number>(a: &N, b: &N) -> Result<N, String> {
+    if b.is_zero() {
+        return Err("division by zero".to_string());
+    }
+    a.checked_rem(b).ok_or_else(|| format!("the remainder of {} / {} overflowed", a, b))
+}
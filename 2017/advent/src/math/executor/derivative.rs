@@ -0,0 +1,175 @@
+use super::*;
+use math::symbolic;
+
+impl<N: Number> Executor<N> {
+    // `d(f, x)` or `d(f, x, args...)`, which works like a builtin but needs to
+    // see its args unevaluated. It evaluates the derivative that
+    // `symbolic::derive` gives, as a function with the same params and
+    // captures as `f`.
+    pub(super) fn evaluate_derivative(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        args: &[Expression],
+    ) -> Result<Value<N>, Error> {
+        let (derivative, arg_values) = self.derivative(statement, name, args)?;
+        if self.frames.len() >= self.limits.max_call_depth {
+            return Err(Error::CallDepthExceeded(statement.clone(), name.clone()));
+        }
+        if let Some(ref mut observer) = self.observer {
+            observer.function_entry(name, &arg_values);
+        }
+        self.frames.push(Frame {
+            function: derivative.clone(),
+            args: arg_values,
+        });
+        let result = self.evaluate_expression(statement, &derivative.body);
+        self.frames.pop();
+        if let Some(ref mut observer) = self.observer {
+            observer.function_return(name, result.as_ref());
+        }
+        return result;
+    }
+
+    // The derivative that `d` calls, and the args it calls it with. Out of
+    // line, since `d` may be evaluated inside itself through the chain rule.
+    #[inline(never)]
+    fn derivative(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        args: &[Expression],
+    ) -> Result<(Rc<Function<N>>, Vec<Value<N>>), Error> {
+        let failed = |message: String| Error::BuiltinFailed(statement.clone(), name.clone(), message);
+        let (function_name, param, point) = match symbolic::derivative_call(name, args) {
+            Some(call) => call,
+            None => {
+                let message = "expected a function and one of its params, as in `d(f, x)`";
+                return Err(failed(message.to_string()));
+            }
+        };
+        let function = match self.functions.get(function_name) {
+            Some(function) => function.clone(),
+            None => return Err(Error::UnknownFunction(statement.clone(), function_name.clone())),
+        };
+        let body = {
+            // Derivatives of calls to the program's functions are evaluated by
+            // calling `d`, so there are none if the program has redefined it.
+            let (functions, builtins) = (&self.functions, &self.builtins);
+            let d_defined = functions.contains_key(name);
+            let callee = |callee: &Name| match functions.get(callee) {
+                Some(function) if !d_defined => symbolic::Callee::Function(function.params.clone()),
+                Some(_) => symbolic::Callee::Other,
+                None if builtins.contains_key(callee) => symbolic::Callee::Builtin,
+                None => symbolic::Callee::Other,
+            };
+            symbolic::derive_body(function_name, &function.params, &function.body, param, &callee)
+                .map_err(|e| failed(e.to_string()))?
+        };
+
+        let mut arg_values = Vec::with_capacity(function.params.len());
+        if point.is_empty() {
+            for param in &function.params {
+                let value = self.lookup_variable(param);
+                arg_values.push(value.ok_or_else(|| Error::UnknownVariable(statement.clone(), param.clone()))?);
+            }
+        } else if point.len() == function.params.len() {
+            for arg in point {
+                arg_values.push(self.evaluate_expression(statement, arg)?);
+            }
+        } else {
+            return Err(Error::ArityMismatch(
+                statement.clone(),
+                name.clone(),
+                2 + function.params.len(),
+                args.len(),
+            ));
+        }
+
+        let derivative = Rc::new(Function {
+            params: function.params.clone(),
+            captures: function.captures.clone(),
+            body,
+        });
+        return Ok((derivative, arg_values));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+
+    fn run(s: &[u8]) -> Result<Executor<f64>, Error> {
+        let mut executor = Executor::new();
+        executor.run(parser::parse(s).unwrap())?;
+        return Ok(executor);
+    }
+
+    #[test]
+    fn derivative_test() {
+        let executor = run(b"k = 3;\n\
+                             f(x, y) = k * x * x * y;\n\
+                             fx(x, y) = d(f, x);\n\
+                             a = fx(2, 5);\n\
+                             b = d(f, y, 2, 5);\n\
+                             x = 1;\n\
+                             y = 2;\n\
+                             c = d(f, x);")
+            .unwrap();
+        let value = |s: &str| executor.variables[&name(s)];
+        assert_eq!((value("a"), value("b"), value("c")), (60.0, 12.0, 12.0));
+    }
+
+    // Calls to the program's functions are differentiated through, each in the
+    // scope it was defined in.
+    #[test]
+    fn chain_rule_test() {
+        let executor = run(b"f(x) = x * x;\n\
+                             g(x) = f(x) * 2;\n\
+                             v = d(g, x, 2);\n\
+                             k = 3;\n\
+                             h(x, y) = k * x * y;\n\
+                             j(k) = h(k, k) + f(2 * k) + h(1, 2);\n\
+                             w = d(j, k, 2);\n\
+                             p(x, n) = if n == 0 then 1 else x * p(x, n - 1);\n\
+                             q = d(p, x, 2, 3);\n\
+                             abs(x) = 5 * x;\n\
+                             r(x) = abs(x - 1);\n\
+                             s = d(r, x, 0);")
+            .unwrap();
+        let value = |s: &str| executor.variables[&name(s)];
+        assert_eq!((value("v"), value("w"), value("q"), value("s")), (8.0, 28.0, 12.0, 5.0));
+    }
+
+    // Newton's method, finding the square root of 2.
+    #[test]
+    fn newton_test() {
+        let executor = run(b"f(x) = x * x - 2;\n\
+                             newton(x, n) = if n == 0 then x else newton(x - f(x) / d(f, x), n - 1);\n\
+                             a = newton(1, 6);")
+            .unwrap();
+        assert!((executor.variables[&name("a")] - 2f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn errors_test() {
+        let failed = |message: &str| Error::BuiltinFailed(name("a"), name("d"), message.to_string());
+        let errors: &[(&[u8], Error)] = &[
+            (b"f(x) = x;\na = d(f, 1);", failed("expected a function and one of its params, as in `d(f, x)`")),
+            (b"a = d(f, x);", Error::UnknownFunction(name("a"), name("f"))),
+            (b"f(x) = x;\na = d(f, y);", failed("`y` is not a param of `f`")),
+            (b"g = \\x -> x;\nf(x) = g(x);\na = d(f, x, 1);", failed("cannot differentiate calls to `g`")),
+            (b"f(x) = gcd(x, 2);\na = d(f, x, 1);", failed("cannot differentiate calls to `gcd`")),
+            (b"f(x) = f(x) + x;\na = d(f, x, 1);", Error::CallDepthExceeded(name("a"), name("d"))),
+            (b"f(x) = x;\na = d(f, x);", Error::UnknownVariable(name("a"), name("x"))),
+            (b"f(x) = x;\na = d(f, x, 1, 2);", Error::ArityMismatch(name("a"), name("d"), 3, 4)),
+        ];
+        for &(source, ref error) in errors {
+            assert_eq!(run(source).err().as_ref(), Some(error), "{:?}", source);
+        }
+        // A function the program defines takes precedence.
+        let executor = run(b"d(a, b) = a - b;\nx = 3;\ny = 1;\na = d(x, y);").unwrap();
+        assert_eq!(executor.variables[&name("a")], 2.0);
+    }
+}
@@ -0,0 +1,232 @@
+use super::*;
+use std::time::Instant;
+
+/// The default for `Limits::max_call_depth`. Each call of a typical function
+/// body takes a few KiB of native stack in debug builds, so this stays clear of
+/// overflowing even a 2MiB thread.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 256;
+
+// How many steps go by between looks at the clock.
+const STEPS_PER_DEADLINE_CHECK: u64 = 1024;
+
+/// Bounds on what running a program may use, for programs that cannot be
+/// trusted to finish. Going over any of them fails with an error of its own.
+/// By default only the call depth is limited.
+///
+/// The limits apply to everything an executor runs, not to each statement, so
+/// a program cannot get around them by being split into many statements. A
+/// single step is bounded too: big numbers are no larger than
+/// `number::MAX_BITS`, so no operation or builtin can run for long.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// How many expressions may be evaluated, each operand and operation being
+    /// one step. See `Error::StepLimitExceeded`.
+    pub max_steps: Option<u64>,
+    /// How many function calls may be in progress at once, so that runaway
+    /// recursion is reported rather than overflowing the native stack. See
+    /// `Error::CallDepthExceeded`.
+    pub max_call_depth: usize,
+    /// How many variables may be defined. See `Error::TooManyVariables`.
+    pub max_variables: Option<usize>,
+    /// How many functions may be defined, not counting builtins. See
+    /// `Error::TooManyFunctions`.
+    pub max_functions: Option<usize>,
+    /// When evaluation must have finished by. See `Error::DeadlineExceeded`.
+    pub deadline: Option<Instant>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_steps: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_variables: None,
+            max_functions: None,
+            deadline: None,
+        }
+    }
+}
+
+impl<N: Number> Executor<N> {
+    pub fn with_limits(limits: Limits) -> Executor<N> {
+        let mut executor = Executor::new();
+        executor.limits = limits;
+        return executor;
+    }
+
+    /// How many steps have been taken towards `Limits::max_steps`.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Counts one step of evaluation, returning whether that was within the
+    // limits. Returning a `bool` rather than a `Result` keeps the frame of
+    // `evaluate_expression` small, and so how deep recursion can go.
+    #[inline(never)]
+    pub(super) fn step(&mut self) -> bool {
+        self.steps += 1;
+        if self.limits.max_steps.map_or(false, |max| self.steps > max) {
+            return false;
+        }
+        if self.steps % STEPS_PER_DEADLINE_CHECK == 0 {
+            return self.limits.deadline.map_or(true, |deadline| Instant::now() < deadline);
+        }
+        return true;
+    }
+
+    // Why `step` failed.
+    #[inline(never)]
+    pub(super) fn limit_error(&self, statement: &Name) -> Error {
+        if self.limits.max_steps.map_or(false, |max| self.steps > max) {
+            Error::StepLimitExceeded(statement.clone())
+        } else {
+            Error::DeadlineExceeded(statement.clone())
+        }
+    }
+
+    fn check_deadline(&self, statement: &Name) -> Result<(), Error> {
+        match self.limits.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(Error::DeadlineExceeded(statement.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Whether `statement` may start: the deadline must not have passed, and a
+    // statement defining a new variable or function must not exceed the limit
+    // on how many there can be.
+    pub(super) fn check_limits(&self, statement: &Statement) -> Result<(), Error> {
+        let name = statement.name();
+        self.check_deadline(name)?;
+        match *statement {
+            // A variable holds either a number or a value such as a list, never
+            // both, so the two maps together count each variable once.
+            Statement::VarAssignment(..) => match self.limits.max_variables {
+                Some(max)
+                    if !self.variables.contains_key(name) && !self.values.contains_key(name)
+                        && self.variables.len() + self.values.len() >= max =>
+                {
+                    Err(Error::TooManyVariables(name.clone()))
+                }
+                _ => Ok(()),
+            },
+            Statement::FnDefinition(..) => match self.limits.max_functions {
+                Some(max) if !self.functions.contains_key(name) && self.functions.len() >= max => {
+                    Err(Error::TooManyFunctions(name.clone()))
+                }
+                _ => Ok(()),
+            },
+            Statement::Import(..) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+    use math::number::BigInt;
+    use std::time::Duration;
+
+    fn run(limits: Limits, s: &[u8]) -> Result<(), Error> {
+        let mut executor: Executor = Executor::with_limits(limits);
+        executor.run(parser::parse(s).unwrap())
+    }
+
+    #[test]
+    fn step_limit_test() {
+        let mut executor: Executor = Executor::with_limits(Limits {
+            max_steps: Some(7),
+            ..Limits::default()
+        });
+        // `1 + 2 * 3` is five steps, and the limit is on the total.
+        executor.run(parser::parse(b"a = 1 + 2 * 3;").unwrap()).unwrap();
+        assert_eq!(executor.steps(), 5);
+        assert_eq!(
+            executor.run(parser::parse(b"b = 1 + 2;").unwrap()),
+            Err(Error::StepLimitExceeded(name("b")))
+        );
+    }
+
+    // Recursion that never gets deep still ends once it has taken too long.
+    #[test]
+    fn runaway_recursion_test() {
+        let fib = b"fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2);\na = fib(60);";
+        let limits = Limits {
+            max_steps: Some(1_000_000),
+            ..Limits::default()
+        };
+        assert_eq!(run(limits, fib), Err(Error::StepLimitExceeded(name("a"))));
+        let limits = Limits {
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..Limits::default()
+        };
+        assert_eq!(run(limits, fib), Err(Error::DeadlineExceeded(name("a"))));
+        assert_eq!(
+            run(Limits::default(), b"f(n) = f(n + 1);\na = f(0);"),
+            Err(Error::CallDepthExceeded(name("a"), name("f")))
+        );
+    }
+
+    // Each `pow` is one step, but the size of big numbers keeps it from
+    // running past the deadline.
+    #[test]
+    fn big_number_test() {
+        let mut executor = Executor::<BigInt>::with_limits(Limits {
+            max_steps: Some(1000),
+            deadline: Some(Instant::now() + Duration::from_millis(100)),
+            ..Limits::default()
+        });
+        for source in &[&b"a = pow(3, 3000000);"[..], b"a = pow(3, 4000000000);"] {
+            match executor.run(parser::parse(source).unwrap()) {
+                Err(Error::BuiltinFailed(ref statement, ref builtin, _)) => {
+                    assert_eq!((statement, builtin), (&name("a"), &name("pow")))
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(
+            executor.run(parser::parse(b"f(x, n) = if n == 0 then x else f(x * x, n - 1);\nb = f(3, 40);").unwrap()),
+            Err(Error::Overflow(name("b"), Operator::Multiply))
+        );
+        assert!(executor.steps() < 1000);
+    }
+
+    // Adding is bounded like multiplying, so that doubling cannot grow a
+    // number past `number::MAX_BITS` either.
+    #[test]
+    fn doubling_test() {
+        let mut executor = Executor::<BigInt>::new();
+        let source = b"f(x, n) = if n == 0 then x else f(x + x, n - 1);\na = f(pow(2, 1048570), 10);";
+        assert_eq!(
+            executor.run(parser::parse(source).unwrap()),
+            Err(Error::Overflow(name("a"), Operator::Add))
+        );
+    }
+
+    #[test]
+    fn definition_limits_test() {
+        let limits = Limits {
+            max_variables: Some(2),
+            max_functions: Some(1),
+            ..Limits::default()
+        };
+        // Redefining what is already defined is always allowed.
+        assert_eq!(run(limits.clone(), b"a = 1; b = 2; a = 3; f(x) = x; f(x) = 2;"), Ok(()));
+        assert_eq!(
+            run(limits.clone(), b"a = 1; b = 2; c = 3;"),
+            Err(Error::TooManyVariables(name("c")))
+        );
+        // Lists and functions held in variables count the same as numbers.
+        assert_eq!(run(limits.clone(), b"a = [1]; b = \\x -> x; a = [2]; b = 1;"), Ok(()));
+        assert_eq!(
+            run(limits.clone(), b"a = [1]; b = \\x -> x; c = 1;"),
+            Err(Error::TooManyVariables(name("c")))
+        );
+        assert_eq!(
+            run(limits, b"f(x) = x; g(x) = x;"),
+            Err(Error::TooManyFunctions(name("g")))
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use super::*;
+
+/// The builtins over lists, by name and arity. See `builtins::standard`.
+pub(crate) const LIST_BUILTINS: [(&str, usize); 5] =
+    [("range", 2), ("len", 1), ("map", 2), ("filter", 2), ("fold", 3)];
+
+pub(crate) fn list_builtin_arity(name: &Name) -> Option<usize> {
+    LIST_BUILTINS
+        .iter()
+        .find(|&&(builtin, _)| builtin == name.as_str())
+        .map(|&(_, arity)| arity)
+}
+
+impl<N: Number> Executor<N> {
+    // A call of one of `LIST_BUILTINS`. They cannot be `Builtin`s, which only
+    // see numbers, and the functions they are given are called just like
+    // functions called by name, so they are reported to the observer as calls
+    // of `f`, the name their docs give them.
+    pub(super) fn evaluate_list_builtin(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        arity: usize,
+        args: &[Expression],
+    ) -> Result<Value<N>, Error> {
+        if args.len() != arity {
+            return Err(Error::ArityMismatch(statement.clone(), name.clone(), arity, args.len()));
+        }
+        let mut arg_values = Vec::with_capacity(args.len());
+        for arg in args {
+            arg_values.push(self.evaluate_expression(statement, arg)?);
+        }
+        if let Some(ref mut observer) = self.observer {
+            observer.function_entry(name, &arg_values);
+        }
+        let result = self.apply_list_builtin(statement, name, arg_values);
+        if let Some(ref mut observer) = self.observer {
+            observer.function_return(name, result.as_ref());
+        }
+        return result;
+    }
+
+    fn apply_list_builtin(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        mut args: Vec<Value<N>>,
+    ) -> Result<Value<N>, Error> {
+        let f = Name("f".to_string());
+        if name.as_str() == "range" {
+            let end = args.pop().unwrap().number(statement)?;
+            let mut n = args.pop().unwrap().number(statement)?;
+            let mut items = vec![];
+            while n < end {
+                // Each item is a step, so that a huge range cannot get around
+                // `Limits::max_steps`.
+                if !self.step() {
+                    return Err(self.limit_error(statement));
+                }
+                let next = n.checked_add(&N::from_i64(1));
+                items.push(n);
+                n = next.ok_or_else(|| Error::Overflow(statement.clone(), Operator::Add))?;
+            }
+            return Ok(Value::List(Rc::new(items)));
+        }
+        let items = match args.pop().unwrap() {
+            Value::List(items) => items,
+            other => return Err(Error::WrongType(statement.clone(), Kind::List, other.kind())),
+        };
+        if name.as_str() == "len" {
+            return Ok(Value::Number(N::from_i64(items.len() as i64)));
+        }
+        let function = match args.remove(0) {
+            Value::Function(function) => function,
+            other => return Err(Error::WrongType(statement.clone(), Kind::Function, other.kind())),
+        };
+        let arity = if name.as_str() == "fold" { 2 } else { 1 };
+        if function.params.len() != arity {
+            return Err(Error::ArityMismatch(statement.clone(), f, function.params.len(), arity));
+        }
+        match name.as_str() {
+            "map" => {
+                let mut results = Vec::with_capacity(items.len());
+                for item in items.iter() {
+                    let result = self.call(statement, &f, &function, vec![Value::Number(item.clone())])?;
+                    results.push(result.number(statement)?);
+                }
+                Ok(Value::List(Rc::new(results)))
+            }
+            "filter" => {
+                let mut kept = vec![];
+                for item in items.iter() {
+                    let keep = self.call(statement, &f, &function, vec![Value::Number(item.clone())])?;
+                    if !keep.number(statement)?.is_zero() {
+                        kept.push(item.clone());
+                    }
+                }
+                Ok(Value::List(Rc::new(kept)))
+            }
+            _ => {
+                let mut accumulator = args.pop().unwrap();
+                for item in items.iter() {
+                    let args = vec![accumulator, Value::Number(item.clone())];
+                    accumulator = self.call(statement, &f, &function, args)?;
+                }
+                Ok(accumulator)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+
+    fn run(s: &[u8]) -> Result<Executor, Error> {
+        let mut executor = Executor::new();
+        executor.run(parser::parse(s).unwrap())?;
+        return Ok(executor);
+    }
+
+    #[test]
+    fn list_builtins_test() {
+        let executor = run(b"xs = range(1, 6);\n\
+                             squares = map(\\x -> x * x, xs);\n\
+                             odd(x) = mod(x, 2);\n\
+                             a = fold(\\sum, x -> sum + x, 0, filter(odd, squares));\n\
+                             b = len(xs) + len([]) + len([a, a]);\n\
+                             c = fold(max, 0, map(abs, [-3, 9, -12]));")
+            .unwrap();
+        let value = |s: &str| executor.values[&name(s)].to_string();
+        assert_eq!((value("xs"), value("squares")), ("[1, 2, 3, 4, 5]".to_string(), "[1, 4, 9, 16, 25]".to_string()));
+        assert_eq!(executor.variables[&name("a")], 35);
+        assert_eq!(executor.variables[&name("b")], 7);
+        assert_eq!(executor.variables[&name("c")], 12);
+    }
+
+    #[test]
+    fn errors_test() {
+        let wrong = |expected, found| Error::WrongType(name("a"), expected, found);
+        let errors: &[(&[u8], Error)] = &[
+            (b"a = map([1], [2]);", wrong(Kind::Function, Kind::List)),
+            (b"a = len(3);", wrong(Kind::List, Kind::Number)),
+            (b"a = range(1, [2]);", wrong(Kind::Number, Kind::List)),
+            (b"a = map(\\x -> [x], [1]);", wrong(Kind::Number, Kind::List)),
+            (b"a = fold(\\x -> x, 0, [1]);", Error::ArityMismatch(name("a"), name("f"), 1, 2)),
+            (b"a = map(\\x -> 1 / x, [1, 0]);", Error::DivisionByZero(name("a"))),
+            (b"a = filter(\\x -> x);", Error::ArityMismatch(name("a"), name("filter"), 2, 1)),
+        ];
+        for &(source, ref error) in errors {
+            assert_eq!(run(source).err().as_ref(), Some(error), "{:?}", source);
+        }
+        let limits = Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        };
+        let mut executor: Executor = Executor::with_limits(limits);
+        assert_eq!(
+            executor.run(parser::parse(b"a = len(range(0, 1000000000000));").unwrap()),
+            Err(Error::StepLimitExceeded(name("a")))
+        );
+    }
+}
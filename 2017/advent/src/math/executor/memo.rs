@@ -0,0 +1,295 @@
+use super::*;
+use math::symbolic;
+use std::collections::{HashSet, VecDeque};
+
+/// The default for `Executor::memo_capacity`.
+pub const DEFAULT_MEMO_CAPACITY: usize = 10_000;
+
+/// How a memoized function's cache has fared since `Executor::memoize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoStats {
+    /// Calls answered from the cache.
+    pub hits: usize,
+    /// Calls that had to evaluate the body.
+    pub misses: usize,
+    /// Results currently cached.
+    pub entries: usize,
+}
+
+// The results of one memoized function, by args. Args are keyed by their
+// `Debug` text, since numbers need not be `Hash`, and since `==` would mix up
+// args such as `0.0` and `-0.0` that a body can tell apart.
+pub(super) struct Memo<N> {
+    results: HashMap<String, Value<N>>,
+    // Keys oldest first, for evicting when full.
+    order: VecDeque<String>,
+    stats: MemoStats,
+}
+
+impl<N: Number> Memo<N> {
+    fn new() -> Memo<N> {
+        Memo {
+            results: HashMap::new(),
+            order: VecDeque::new(),
+            stats: MemoStats::default(),
+        }
+    }
+
+    fn key(args: &[Value<N>]) -> String {
+        format!("{:?}", args)
+    }
+
+    fn get(&mut self, key: &str) -> Option<Value<N>> {
+        let result = self.results.get(key).cloned();
+        match result {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        return result;
+    }
+
+    fn insert(&mut self, key: String, value: Value<N>, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        while self.order.len() >= capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.results.remove(&oldest);
+        }
+        self.order.push_back(key.clone());
+        self.results.insert(key, value);
+        self.stats.entries = self.results.len();
+    }
+
+    fn clear(&mut self) {
+        self.results.clear();
+        self.order.clear();
+        self.stats.entries = 0;
+    }
+}
+
+impl<N: Number> Executor<N> {
+    /// Caches the results of calls to `function` by their args, so that each
+    /// distinct call only evaluates the body once. This turns naive recursive
+    /// definitions like fibonacci from exponential time to linear.
+    ///
+    /// The choice outlives redefinitions of `function`, but the cache does
+    /// not: it is cleared whenever `function`, or any function it calls in
+    /// turn, is redefined, including by assigning to a variable it calls. Only successful calls are cached, and at most
+    /// `memo_capacity` of them, the oldest being evicted first.
+    pub fn memoize(&mut self, function: &Name) {
+        self.memos.entry(function.clone()).or_insert_with(Memo::new);
+    }
+
+    pub fn is_memoized(&self, function: &Name) -> bool {
+        self.memos.contains_key(function)
+    }
+
+    /// `None` unless `function` is memoized.
+    pub fn memo_stats(&self, function: &Name) -> Option<MemoStats> {
+        self.memos.get(function).map(|memo| memo.stats)
+    }
+
+    // The cached result of a call, if `function` is memoized and has been
+    // called with `args` before. This and `memo_insert` are kept out of
+    // `call_memoized` so as not to grow its stack frame, which every level of
+    // recursion pays for.
+    #[inline(never)]
+    pub(super) fn memo_lookup(&mut self, function: &Name, args: &[Value<N>]) -> Option<Value<N>> {
+        let value = self.memos.get_mut(function)?.get(&Memo::key(args))?;
+        if let Some(ref mut observer) = self.observer {
+            observer.function_entry(function, args);
+            observer.function_return(function, Ok(&value));
+        }
+        Some(value)
+    }
+
+    #[inline(never)]
+    pub(super) fn memo_insert(
+        &mut self,
+        function: &Name,
+        args: &[Value<N>],
+        result: &Result<Value<N>, Error>,
+    ) {
+        let capacity = self.memo_capacity;
+        if let (Some(memo), &Ok(ref value)) = (self.memos.get_mut(function), result) {
+            memo.insert(Memo::key(args), value.clone(), capacity);
+        }
+    }
+
+    // Clears the cache of every memoized function whose results could depend
+    // on how `function` is defined.
+    pub(super) fn invalidate_memos(&mut self, function: &Name) {
+        let stale: Vec<Name> = self
+            .memos
+            .keys()
+            .filter(|&memoized| memoized == function || self.calls(memoized, function))
+            .cloned()
+            .collect();
+        for name in stale {
+            self.memos.get_mut(&name).unwrap().clear();
+        }
+    }
+
+    // Whether the body of `caller` calls `callee`, directly or through other
+    // functions.
+    fn calls(&self, caller: &Name, callee: &Name) -> bool {
+        let mut seen = HashSet::new();
+        let mut pending = vec![caller.clone()];
+        while let Some(name) = pending.pop() {
+            if let Some(function) = self.functions.get(&name) {
+                for called in called_functions(&function.body) {
+                    if called == *callee {
+                        return true;
+                    }
+                    if seen.insert(called.clone()) {
+                        pending.push(called);
+                    }
+                }
+            }
+        }
+        return false;
+    }
+}
+
+fn called_functions(expr: &Expression) -> Vec<Name> {
+    match *expr {
+        Expression::Operand(Operand::I64(_), _) | Expression::Operand(Operand::F64(_), _) => vec![],
+        // A function passed by name may be called in turn.
+        Expression::Operand(Operand::VarSubstitution(ref name), _) => vec![name.clone()],
+        Expression::Operand(Operand::FnApplication(ref name, ref args), _) => {
+            let mut called = vec![name.clone()];
+            if let Some((function, _, _)) = symbolic::derivative_call(name, args) {
+                called.push(function.clone());
+            }
+            for arg in args {
+                called.extend(called_functions(arg));
+            }
+            called
+        }
+        Expression::Operand(Operand::Lambda(_, ref body), _) => called_functions(body),
+        Expression::Operand(Operand::Call(ref callee, ref args), _) => {
+            let mut called = called_functions(callee);
+            called.extend(args.iter().flat_map(called_functions));
+            called
+        }
+        Expression::Operand(Operand::List(ref items), _) => {
+            items.iter().flat_map(called_functions).collect()
+        }
+        Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+            let mut called = called_functions(condition);
+            called.extend(called_functions(consequent));
+            called.extend(called_functions(alternative));
+            called
+        }
+        Expression::Operation(ref lhs, _, ref rhs, _) => {
+            let mut called = called_functions(lhs);
+            called.extend(called_functions(rhs));
+            called
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+
+    const FIB: &[u8] = b"fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2);";
+
+    fn run(executor: &mut Executor, s: &[u8]) -> Result<(), Error> {
+        executor.run(parser::parse(s).unwrap())
+    }
+
+    #[test]
+    fn fibonacci_test() {
+        let mut executor = Executor::new();
+        executor.memoize(&name("fib"));
+        run(&mut executor, FIB).unwrap();
+        run(&mut executor, b"a = fib(90);").unwrap();
+        assert_eq!(executor.variables[&name("a")], 2_880_067_194_370_816_120);
+        let stats = MemoStats {
+            hits: 88,
+            misses: 91,
+            entries: 91,
+        };
+        assert_eq!(executor.memo_stats(&name("fib")), Some(stats));
+        run(&mut executor, b"b = fib(90);").unwrap();
+        assert_eq!(executor.memo_stats(&name("fib")).unwrap().hits, 89);
+        assert_eq!(executor.memo_stats(&name("other")), None);
+    }
+
+    #[test]
+    fn capacity_test() {
+        let mut executor = Executor::new();
+        executor.memoize(&name("fib"));
+        executor.memo_capacity = 2;
+        run(&mut executor, FIB).unwrap();
+        run(&mut executor, b"a = fib(20);").unwrap();
+        assert_eq!(executor.variables[&name("a")], 6765);
+        assert_eq!(executor.memo_stats(&name("fib")).unwrap().entries, 2);
+    }
+
+    #[test]
+    fn redefinition_test() {
+        let mut executor = Executor::new();
+        executor.memoize(&name("f"));
+        run(&mut executor, b"g(x) = x + 1; f(x) = g(x) * 2; h(x) = x; a = f(1);").unwrap();
+        assert_eq!(executor.memo_stats(&name("f")).unwrap().entries, 1);
+        // Redefining something `f` does not call keeps its results.
+        run(&mut executor, b"h(x) = x * 3; b = f(1);").unwrap();
+        assert_eq!(executor.memo_stats(&name("f")).unwrap().hits, 1);
+        // But redefining what it calls, or `f` itself, clears them.
+        run(&mut executor, b"g(x) = x + 2; c = f(1);").unwrap();
+        assert_eq!(executor.variables[&name("c")], 6);
+        run(&mut executor, b"f(x) = x; d = f(1);").unwrap();
+        assert_eq!(executor.variables[&name("d")], 1);
+        assert!(executor.is_memoized(&name("f")));
+        // Nor does the derivative of a function escape its redefinition.
+        executor.memoize(&name("slope"));
+        run(&mut executor, b"g(x) = x * x; slope(x) = d(g, x); e = slope(3);").unwrap();
+        run(&mut executor, b"g(x) = x * x * x; e = slope(3);").unwrap();
+        assert_eq!(executor.variables[&name("e")], 27);
+        let stats = executor.memo_stats(&name("f")).unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 3, 1));
+    }
+
+    // Functions held in variables are looked up when called, so reassigning
+    // one is a redefinition too.
+    #[test]
+    fn reassigned_function_test() {
+        let mut executor = Executor::new();
+        executor.memoize(&name("f"));
+        run(&mut executor, b"g = \\x -> x; f(x) = g(x); a = f(1);").unwrap();
+        run(&mut executor, b"g = \\x -> x * 10; b = f(1);").unwrap();
+        assert_eq!(executor.variables[&name("b")], 10);
+        let snapshot = executor.snapshot();
+        run(&mut executor, b"g = \\x -> x * 100; c = f(1);").unwrap();
+        executor.restore(&snapshot);
+        run(&mut executor, b"d = f(1);").unwrap();
+        assert_eq!(executor.variables[&name("d")], 10);
+    }
+
+    #[test]
+    fn failures_are_not_cached_test() {
+        let mut executor = Executor::new();
+        executor.memoize(&name("f"));
+        run(&mut executor, b"f(x) = 1 / x;").unwrap();
+        assert!(run(&mut executor, b"a = f(0);").is_err());
+        assert!(run(&mut executor, b"b = f(0);").is_err());
+        assert_eq!(executor.memo_stats(&name("f")).unwrap().entries, 0);
+    }
+
+    // Restoring the previous definition after a failed update clears the
+    // results computed with the definition that failed.
+    #[test]
+    fn reactive_roll_back_test() {
+        let mut executor = Executor::reactive();
+        executor.memoize(&name("f"));
+        run(&mut executor, b"f(x) = x + 1; a = f(2); c = f(1);").unwrap();
+        // `a` is recomputed, caching `f(2) = 2`, before `c` fails.
+        assert!(run(&mut executor, b"f(x) = 2 / (x - 1);").is_err());
+        run(&mut executor, b"b = f(2);").unwrap();
+        assert_eq!(executor.variables[&name("b")], 3);
+    }
+}
@@ -1,100 +1,1038 @@
+pub mod builtins;
+mod derivative;
+mod limits;
+mod lists;
+mod memo;
+mod query;
+mod sheet;
+mod snapshot;
+mod trace;
+mod value;
+
 use super::*;
+use super::number::Number;
+use im_rc;
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use self::memo::Memo;
+use self::sheet::Sheet;
+use self::snapshot::UndoStack;
+
+pub use self::builtins::Builtin;
+pub use self::limits::{Limits, DEFAULT_MAX_CALL_DEPTH};
+pub use self::memo::{MemoStats, DEFAULT_MEMO_CAPACITY};
+pub use self::query::Definition;
+pub use self::sheet::Dependency;
+pub use self::snapshot::{Snapshot, DEFAULT_UNDO_LIMIT};
+pub use self::trace::{Observer, Tracer};
+pub use self::value::{Kind, Value};
+pub(crate) use self::lists::{list_builtin_arity, LIST_BUILTINS};
 
+// Every variant starts with the name of the statement that was being executed
+// when evaluation failed, so that errors inside function bodies can be traced
+// back to the assignment that called them.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
-    UnknownVariable(Name),
-    UnknownFunction(Name),
+    /// (statement, variable)
+    UnknownVariable(Name, Name),
+    /// (statement, function)
+    UnknownFunction(Name, Name),
+    /// (statement, function, number of params, number of args)
+    ArityMismatch(Name, Name, usize, usize),
+    /// (statement)
+    DivisionByZero(Name),
+    /// (statement, operator that overflowed)
+    Overflow(Name, Operator),
+    /// (statement, function whose call would have exceeded
+    /// `Limits::max_call_depth`)
+    CallDepthExceeded(Name, Name),
+    /// (statement, literal that the number type cannot represent exactly)
+    NotRepresentable(Name, String),
+    /// (statement, builtin, what went wrong)
+    BuiltinFailed(Name, Name, String),
+    /// (statement, variable that already depends on it) in reactive mode,
+    /// where assigning the statement would make it depend on itself.
+    CircularReference(Name, Name),
+    /// (statement) that would have taken more than `Limits::max_steps`.
+    StepLimitExceeded(Name),
+    /// (statement) that would have defined more than `Limits::max_variables`.
+    TooManyVariables(Name),
+    /// (statement) that would have defined more than `Limits::max_functions`.
+    TooManyFunctions(Name),
+    /// (statement) that was running when `Limits::deadline` passed.
+    DeadlineExceeded(Name),
+    /// (statement, kind of value needed, kind of value found), such as when
+    /// adding a function to a number, or calling a list.
+    WrongType(Name, Kind, Kind),
+    /// (statement, what it needed) that a backend without the whole of the
+    /// language cannot run, such as lambdas on the bytecode VM.
+    Unsupported(Name, String),
+    /// (namespace) of an import that was run rather than replaced by the file
+    /// it imports, which only an `import::Loader` can do.
+    UnresolvedImport(Name),
 }
 
-pub fn execute(statements: Vec<Statement>) -> Result<HashMap<Name, i64>, Error> {
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownVariable(ref statement, ref variable) => {
+                write!(f, "in `{}`: unknown variable `{}`", statement, variable)
+            }
+            Error::UnknownFunction(ref statement, ref function) => {
+                write!(f, "in `{}`: unknown function `{}`", statement, function)
+            }
+            Error::ArityMismatch(ref statement, ref function, params, args) => write!(
+                f,
+                "in `{}`: `{}` takes {} argument(s) but {} were given",
+                statement, function, params, args
+            ),
+            Error::DivisionByZero(ref statement) => {
+                write!(f, "in `{}`: division by zero", statement)
+            }
+            Error::Overflow(ref statement, operator) => {
+                write!(f, "in `{}`: {:?} overflowed", statement, operator)
+            }
+            Error::CallDepthExceeded(ref statement, ref function) => write!(
+                f,
+                "in `{}`: calling `{}` exceeded the maximum call depth",
+                statement, function
+            ),
+            Error::NotRepresentable(ref statement, ref literal) => write!(
+                f,
+                "in `{}`: `{}` cannot be represented exactly",
+                statement, literal
+            ),
+            Error::BuiltinFailed(ref statement, ref builtin, ref message) => {
+                write!(f, "in `{}`: `{}` failed: {}", statement, builtin, message)
+            }
+            Error::CircularReference(ref statement, ref variable) => write!(
+                f,
+                "in `{}`: circular reference through `{}`",
+                statement, variable
+            ),
+            Error::StepLimitExceeded(ref statement) => {
+                write!(f, "in `{}`: exceeded the maximum number of steps", statement)
+            }
+            Error::TooManyVariables(ref statement) => {
+                write!(f, "in `{}`: exceeded the maximum number of variables", statement)
+            }
+            Error::TooManyFunctions(ref statement) => {
+                write!(f, "in `{}`: exceeded the maximum number of functions", statement)
+            }
+            Error::DeadlineExceeded(ref statement) => {
+                write!(f, "in `{}`: ran past the deadline", statement)
+            }
+            Error::WrongType(ref statement, expected, found) => write!(
+                f,
+                "in `{}`: expected {} but found {}",
+                statement, expected, found
+            ),
+            Error::Unsupported(ref statement, ref what) => {
+                write!(f, "in `{}`: {} are not supported", statement, what)
+            }
+            Error::UnresolvedImport(ref namespace) => write!(
+                f,
+                "in `{}`: imports must be loaded from a file before they can run",
+                namespace
+            ),
+        }
+    }
+}
+
+pub fn execute<N: Number>(statements: Vec<Statement>) -> Result<HashMap<Name, N>, Error> {
     let mut executor = Executor::new();
-    executor.run(statements);
-    return Ok(executor.variables);
+    executor.run(statements)?;
+    return Ok(executor.variables.into_iter().collect());
+}
+
+/// Runs programs a statement at a time, keeping the variables and functions
+/// they define. These are kept in persistent maps, which share structure with
+/// their earlier versions, so that a `snapshot` costs next to nothing.
+pub struct Executor<N = i64> {
+    pub variables: im_rc::HashMap<Name, N>,
+    /// Variables assigned functions or lists rather than numbers. A name is
+    /// never in both this and `variables`.
+    pub values: im_rc::HashMap<Name, Value<N>>,
+    pub functions: im_rc::HashMap<Name, Rc<Function<N>>>,
+    /// Functions implemented in Rust, starting with `builtins::standard()`.
+    /// A function defined by the program takes precedence over a builtin of
+    /// the same name.
+    pub builtins: HashMap<Name, Builtin<N>>,
+    /// What running programs may use.
+    pub limits: Limits,
+    steps: u64,
+    /// How many results each memoized function keeps. See `memoize`.
+    pub memo_capacity: usize,
+    memos: HashMap<Name, Memo<N>>,
+    frames: Vec<Frame<N>>,
+    /// Told about each step of evaluation as it happens.
+    pub observer: Option<Box<dyn Observer<N>>>,
+    // Only kept in reactive mode.
+    sheet: Option<Sheet>,
+    /// How many checkpoints `undo` can go back through. See `checkpoint`.
+    pub undo_limit: usize,
+    undo_stack: UndoStack<N>,
+    // When each variable and function was first defined, counting from 0.
+    order: im_rc::HashMap<Dependency, usize>,
+}
+
+/// A function body is lexically scoped: it can read its parameters, and the
+/// globals it names that were already defined when the function was. Those
+/// globals are captured by value at definition time, so reassigning them later
+/// does not change what the function computes. A lambda likewise captures what
+/// it uses from the function it is evaluated in, including functions passed to
+/// that one, which is what lets functions return functions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function<N = i64> {
+    pub params: Vec<Name>,
+    pub captures: HashMap<Name, Value<N>>,
+    pub body: Expression,
 }
 
-pub struct Executor {
-    pub variables: HashMap<Name, i64>,
-    pub functions: HashMap<Name, (Vec<Name>, Expression)>,
+// The environment of one function call in progress. Lookups only ever consult
+// the innermost frame, so their cost depends on the size of the function being
+// called rather than on how many globals the program has defined.
+struct Frame<N> {
+    function: Rc<Function<N>>,
+    args: Vec<Value<N>>,
 }
 
-impl Executor {
-    pub fn new() -> Executor {
+impl<N: Number> Executor<N> {
+    pub fn new() -> Executor<N> {
         Executor {
-            variables: HashMap::new(),
-            functions: HashMap::new(),
+            variables: im_rc::HashMap::new(),
+            values: im_rc::HashMap::new(),
+            functions: im_rc::HashMap::new(),
+            builtins: builtins::standard(),
+            limits: Limits::default(),
+            steps: 0,
+            memo_capacity: DEFAULT_MEMO_CAPACITY,
+            memos: HashMap::new(),
+            frames: vec![],
+            observer: None,
+            sheet: None,
+            undo_limit: DEFAULT_UNDO_LIMIT,
+            undo_stack: UndoStack::new(),
+            order: im_rc::HashMap::new(),
         }
     }
 
-    pub fn run(&mut self, statements: Vec<Statement>) {
+    /// Makes `function` callable from programs as `name`, replacing any
+    /// builtin already registered under that name.
+    pub fn register_builtin<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[N]) -> Result<N, String> + 'static,
+    {
+        let builtin = Builtin {
+            arity,
+            function: Rc::new(function),
+        };
+        let name = Name(name.to_string());
+        self.invalidate_memos(&name);
+        self.builtins.insert(name, builtin);
+    }
+
+    /// Executes each statement in turn, stopping at the first that fails. The
+    /// statements before it keep their effects; see `run_atomic`.
+    pub fn run(&mut self, statements: Vec<Statement>) -> Result<(), Error> {
         for statement in statements {
-            self.execute(statement)
+            self.execute(statement)?;
+        }
+        return Ok(());
+    }
+
+    pub fn execute(&mut self, statement: Statement) -> Result<(), Error> {
+        self.update(statement).map(|_| ())
+    }
+
+    /// Executes `statement`, and returns every variable whose value changed as
+    /// a result, starting with any it assigns.
+    ///
+    /// In reactive mode, every variable that uses what the statement defines is
+    /// then brought up to date. A variable is only recomputed when something it
+    /// uses has changed, and only after everything it uses has been. If any
+    /// recomputation fails then the statement has no effect at all.
+    pub fn update(&mut self, statement: Statement) -> Result<Vec<Name>, Error> {
+        let name = statement.name().clone();
+        let assigns = match statement {
+            Statement::VarAssignment(..) => true,
+            Statement::FnDefinition(..) | Statement::Import(..) => false,
+        };
+        if let Some(ref mut observer) = self.observer {
+            observer.statement_start(&statement);
         }
+        let result = match self.check_limits(&statement) {
+            Err(e) => Err(e),
+            Ok(()) if self.sheet.is_some() => self.update_reactive(statement),
+            Ok(()) => self.execute_statement(statement),
+        };
+        if let Some(ref mut observer) = self.observer {
+            let value = match result {
+                Ok(_) if assigns => Ok(self.variables.get(&name)),
+                Ok(_) => Ok(None),
+                Err(ref e) => Err(e),
+            };
+            observer.statement_end(&name, value);
+        }
+        return result;
     }
 
-    pub fn execute(&mut self, statement: Statement) {
+    fn execute_statement(&mut self, statement: Statement) -> Result<Vec<Name>, Error> {
         match statement {
-            Statement::VarAssignment(name, expr) => {
-                let expr_value = self.evaluate_expression(expr);
-                self.variables.insert(name, expr_value);
+            Statement::VarAssignment(name, expr, _) => {
+                let expr_value = self.evaluate_expression(&name, &expr)?;
+                self.record_definition(Dependency::Variable(name.clone()));
+                let held_value = self.values.contains_key(&name);
+                let changed = match expr_value {
+                    Value::Number(n) => {
+                        self.values.remove(&name);
+                        self.variables.insert(name.clone(), n.clone()) != Some(n)
+                    }
+                    value => {
+                        self.variables.remove(&name);
+                        self.values.insert(name.clone(), value.clone()) != Some(value)
+                    }
+                };
+                // Functions capture the numbers they read, but look up the
+                // functions held in variables when they call them.
+                if changed && (held_value || self.values.contains_key(&name)) {
+                    self.invalidate_memos(&name);
+                }
+                return Ok(if changed { vec![name] } else { vec![] });
+            }
+            Statement::FnDefinition(name, params, expr, _) => {
+                self.define_function(name, params, expr);
+                return Ok(vec![]);
+            }
+            Statement::Import(_, namespace, _) => return Err(Error::UnresolvedImport(namespace)),
+        }
+    }
+
+    // Returns the definition this replaces, if any.
+    fn define_function(
+        &mut self,
+        name: Name,
+        params: Vec<Name>,
+        expr: Expression,
+    ) -> Option<Rc<Function<N>>> {
+        let mut captures = HashMap::new();
+        self.capture(&params, &expr, &mut captures);
+        let function = Function {
+            params,
+            captures,
+            body: expr,
+        };
+        self.invalidate_memos(&name);
+        self.record_definition(Dependency::Function(name.clone()));
+        self.functions.insert(name, Rc::new(function))
+    }
+
+    // Captures what `expr` reads that is not one of `params`: variables, and
+    // the functions in the current frame that it calls. Calls of anything else
+    // are resolved when they happen.
+    fn capture(&self, params: &[Name], expr: &Expression, captures: &mut HashMap<Name, Value<N>>) {
+        match *expr {
+            Expression::Operand(Operand::I64(_), _) | Expression::Operand(Operand::F64(_), _) => {}
+            Expression::Operand(Operand::VarSubstitution(ref name), _) => {
+                if !params.contains(name) {
+                    if let Some(value) = self.lookup_variable(name) {
+                        captures.insert(name.clone(), value);
+                    }
+                }
+            }
+            Expression::Operand(Operand::FnApplication(ref name, ref args), _) => {
+                if !params.contains(name) && !self.frames.is_empty() {
+                    if let Some(value) = self.lookup_variable(name) {
+                        captures.insert(name.clone(), value);
+                    }
+                }
+                for arg in args {
+                    self.capture(params, arg, captures);
+                }
+            }
+            Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+                self.capture(params, condition, captures);
+                self.capture(params, consequent, captures);
+                self.capture(params, alternative, captures);
             }
-            Statement::FnDefinition(name, params, expr) => {
-                self.functions.insert(name, (params, expr));
+            Expression::Operand(Operand::Lambda(ref inner, ref body), _) => {
+                let params: Vec<Name> = params.iter().chain(inner).cloned().collect();
+                self.capture(&params, body, captures);
             }
+            Expression::Operand(Operand::List(ref items), _) => {
+                for item in items {
+                    self.capture(params, item, captures);
+                }
+            }
+            Expression::Operand(Operand::Call(ref callee, ref args), _) => {
+                self.capture(params, callee, captures);
+                for arg in args {
+                    self.capture(params, arg, captures);
+                }
+            }
+            Expression::Operation(ref lhs, _, ref rhs, _) => {
+                self.capture(params, lhs, captures);
+                self.capture(params, rhs, captures);
+            }
+        }
+    }
+
+    fn evaluate_expression(&mut self, statement: &Name, expr: &Expression) -> Result<Value<N>, Error> {
+        if !self.step() {
+            return Err(self.limit_error(statement));
+        }
+        match *expr {
+            Expression::Operand(ref operand, _) => self.evaluate_operand(statement, operand),
+            Expression::Operation(ref lhs, operator, ref rhs, _) => {
+                let lhs_value = self.evaluate_expression(statement, lhs)?;
+                let rhs_value = self.evaluate_expression(statement, rhs)?;
+                self.observe_operation(statement, operator, lhs_value, rhs_value)
+            }
+        }
+    }
+
+    // `evaluate_operation`, telling the observer about it. Out of line, like
+    // `step`, for the sake of the stack.
+    #[inline(never)]
+    fn observe_operation(
+        &mut self,
+        statement: &Name,
+        operator: Operator,
+        lhs: Value<N>,
+        rhs: Value<N>,
+    ) -> Result<Value<N>, Error> {
+        let (lhs, rhs) = (lhs.number(statement)?, rhs.number(statement)?);
+        let result = match self.observer {
+            Some(ref mut observer) => {
+                let operands = (lhs.clone(), rhs.clone());
+                let result = evaluate_operation(statement, operator, lhs, rhs);
+                observer.operation(operator, &operands.0, &operands.1, result.as_ref());
+                result
+            }
+            None => evaluate_operation(statement, operator, lhs, rhs),
+        };
+        result.map(Value::Number)
+    }
+
+    fn evaluate_operand(&mut self, statement: &Name, operand: &Operand) -> Result<Value<N>, Error> {
+        match *operand {
+            Operand::I64(value) => Ok(Value::Number(N::from_i64(value))),
+            Operand::F64(value) => N::from_f64(value)
+                .map(Value::Number)
+                .ok_or_else(|| Error::NotRepresentable(statement.clone(), format!("{:?}", value))),
+            Operand::VarSubstitution(ref name) => self.substitute(statement, name),
+            Operand::FnApplication(ref name, ref args) => {
+                self.evaluate_function(statement, name, args)
+            }
+            Operand::Call(ref callee, ref args) => self.evaluate_call(statement, callee, args),
+            Operand::If(ref condition, ref consequent, ref alternative) => {
+                if self.evaluate_condition(statement, condition)? {
+                    self.evaluate_expression(statement, consequent)
+                } else {
+                    self.evaluate_expression(statement, alternative)
+                }
+            }
+            Operand::Lambda(ref params, ref body) => Ok(self.lambda(params, body)),
+            Operand::List(ref items) => self.evaluate_list(statement, items),
+        }
+    }
+
+    // The value of a variable, or of a function named without being called.
+    #[inline(never)]
+    fn substitute(&self, statement: &Name, name: &Name) -> Result<Value<N>, Error> {
+        self.lookup_variable(name)
+            .or_else(|| self.function_value(name))
+            .ok_or_else(|| Error::UnknownVariable(statement.clone(), name.clone()))
+    }
+
+    // Whether the condition of an `if` holds, which it does for any number
+    // but zero.
+    #[inline(never)]
+    fn evaluate_condition(&mut self, statement: &Name, condition: &Expression) -> Result<bool, Error> {
+        let condition = self.evaluate_expression(statement, condition)?;
+        return Ok(!condition.number(statement)?.is_zero());
+    }
+
+    fn lookup_variable(&self, name: &Name) -> Option<Value<N>> {
+        match self.frames.last() {
+            Some(frame) => match frame.function.params.iter().position(|param| param == name) {
+                Some(i) => Some(frame.args[i].clone()),
+                None => frame.function.captures.get(name).cloned(),
+            },
+            None => match self.variables.get(name) {
+                Some(n) => Some(Value::Number(n.clone())),
+                None => self.values.get(name).cloned(),
+            },
         }
     }
 
-    // @TODO: Evaluation.
-    // 1. Replace all operands by their values.
-    // @TODO: 2. Devise queue of evaluations from highest to lowest priority operations.
-    // 3. Perform series of evaluations.
-    // 4. Return the expr's evaluated value.
-    fn evaluate_expression(&mut self, expr: Expression) -> i64 {
-        let mut accumulator = self.evaluate_operand(expr.0);
-        for (operator, operand) in expr.1 {
-            let operand_value = self.evaluate_operand(operand);
-            accumulator = self.evaluate_operation(operator, accumulator, operand_value);
+    // A function named where a value was expected, as in `map(f, xs)`, which
+    // passes the definition of `f` as it is now. A builtin is passed as a
+    // function that calls it.
+    #[inline(never)]
+    fn function_value(&self, name: &Name) -> Option<Value<N>> {
+        if let Some(function) = self.functions.get(name) {
+            return Some(Value::Function(function.clone()));
         }
-        return accumulator;
+        let arity = match self.builtins.get(name) {
+            Some(builtin) => builtin.arity,
+            None => list_builtin_arity(name)?,
+        };
+        let params: Vec<Name> = (0..arity).map(|i| Name(format!("_{}", i))).collect();
+        let args = params
+            .iter()
+            .map(|param| Expression::Operand(Operand::VarSubstitution(param.clone()), Span::default()))
+            .collect();
+        return Some(Value::Function(Rc::new(Function {
+            params,
+            captures: HashMap::new(),
+            body: Expression::Operand(Operand::FnApplication(name.clone(), args), Span::default()),
+        })));
+    }
+
+    #[inline(never)]
+    fn lambda(&self, params: &[Name], body: &Expression) -> Value<N> {
+        let mut captures = HashMap::new();
+        self.capture(params, body, &mut captures);
+        Value::Function(Rc::new(Function {
+            params: params.to_vec(),
+            captures,
+            body: body.clone(),
+        }))
     }
 
-    fn evaluate_operation(&self, operator: Operator, operand1: i64, operand2: i64) -> i64 {
-        match operator {
-            Operator::Add => operand1 + operand2,
-            Operator::Subtract => operand1 - operand2,
-            Operator::Multiply => operand1 * operand2,
-            Operator::Divide => operand1 / operand2,
+    #[inline(never)]
+    fn evaluate_list(&mut self, statement: &Name, items: &[Expression]) -> Result<Value<N>, Error> {
+        let mut values = Vec::with_capacity(items.len());
+        for item in items {
+            values.push(self.evaluate_expression(statement, item)?.number(statement)?);
         }
+        Ok(Value::List(Rc::new(values)))
     }
 
-    fn evaluate_operand(&mut self, operand: Operand) -> i64 {
-        match operand {
-            Operand::I64(value) => value,
-            Operand::VarSubstitution(name) => self.variables[&name],
-            Operand::FnApplication(name, args) => self.evaluate_function(name, args),
+    // What a call of `name` runs. A param or capture holding a function comes
+    // first, so that a function passed in can be called by the name it was
+    // passed as, then a definition, then a global variable holding a function.
+    // Anything else is left to `evaluate_builtin`.
+    #[inline(never)]
+    fn resolve_call(&self, statement: &Name, name: &Name) -> Result<Callee<N>, Error> {
+        if !self.frames.is_empty() {
+            match self.lookup_variable(name) {
+                Some(Value::Function(function)) => return Ok(Callee::Value(function)),
+                Some(other) => {
+                    return Err(Error::WrongType(statement.clone(), Kind::Function, other.kind()))
+                }
+                None => {}
+            }
+        }
+        if let Some(function) = self.functions.get(name) {
+            return Ok(Callee::Defined(function.clone()));
+        }
+        match self.values.get(name) {
+            Some(&Value::Function(ref function)) => Ok(Callee::Value(function.clone())),
+            _ => Ok(Callee::Builtin),
         }
     }
 
     // 1. Verify number of args matches the expected number of params.
-    // 2. Replace all args by their values.
-    // 3. Make a backup copy of the current variables.
-    // 4. Insert all evaluated args as variables.
-    // 5. Evaluate the function's expression as normal.
-    // 6. Restore the variables to the backup.
-    // 7. Return the function's evaluated value.
-    // @TODO: This allows functions to access global variables. Hmmm.
-    fn evaluate_function(&mut self, name: Name, args: Vec<Expression>) -> i64 {
-        let (function_params, function_expr) = self.functions[&name].clone();
-        assert_eq!(args.len(), function_params.len());
-        let backup_of_global_variables = self.variables.clone();
-
-        for (name, arg) in function_params.into_iter().zip(args) {
-            let arg_value = self.evaluate_expression(arg);
-            self.variables.insert(name.clone(), arg_value);
-        }
-        let result = self.evaluate_expression(function_expr);
-
-        self.variables = backup_of_global_variables;
+    // 2. Replace all args by their values, in the caller's environment.
+    // 3. `call` the function with them.
+    //
+    // Builtins are called directly once their args are evaluated, without a
+    // frame.
+    fn evaluate_function(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        args: &[Expression],
+    ) -> Result<Value<N>, Error> {
+        let (function, defined) = match self.resolve_call(statement, name)? {
+            Callee::Defined(function) => (function, true),
+            Callee::Value(function) => (function, false),
+            Callee::Builtin => return self.evaluate_builtin(statement, name, args),
+        };
+        let arg_values = self.evaluate_args(statement, name, function.params.len(), args)?;
+        if defined && self.is_memoized(name) {
+            self.call_memoized(statement, name, &function, arg_values)
+        } else {
+            self.call(statement, name, &function, arg_values)
+        }
+    }
+
+    // Calls whatever function `callee` gives, which is reported to the
+    // observer and in errors by how it was written.
+    #[inline(never)]
+    fn evaluate_call(
+        &mut self,
+        statement: &Name,
+        callee: &Expression,
+        args: &[Expression],
+    ) -> Result<Value<N>, Error> {
+        let function = match self.evaluate_expression(statement, callee)? {
+            Value::Function(function) => function,
+            other => return Err(Error::WrongType(statement.clone(), Kind::Function, other.kind())),
+        };
+        let name = Name(callee.to_string());
+        let arg_values = self.evaluate_args(statement, &name, function.params.len(), args)?;
+        self.call(statement, &name, &function, arg_values)
+    }
+
+    #[inline(never)]
+    fn evaluate_args(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        arity: usize,
+        args: &[Expression],
+    ) -> Result<Vec<Value<N>>, Error> {
+        if args.len() != arity {
+            return Err(Error::ArityMismatch(statement.clone(), name.clone(), arity, args.len()));
+        }
+        let mut arg_values = Vec::with_capacity(args.len());
+        for arg in args {
+            arg_values.push(self.evaluate_expression(statement, arg)?);
+        }
+        return Ok(arg_values);
+    }
+
+    // `call` for a memoized function, called by the name it was defined with.
+    // It skips the call when it has been made with the same args before, and
+    // otherwise caches what it gives.
+    #[inline(never)]
+    fn call_memoized(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        function: &Rc<Function<N>>,
+        args: Vec<Value<N>>,
+    ) -> Result<Value<N>, Error> {
+        if let Some(value) = self.memo_lookup(name, &args) {
+            return Ok(value);
+        }
+        let result = self.call(statement, name, function, args.clone());
+        self.memo_insert(name, &args, &result);
         return result;
     }
+
+    // Calls `function` with `args`, one for each of its params:
+    //
+    // 1. Push a frame binding the params to `args`, unless that would exceed
+    //    the maximum call depth.
+    // 2. Evaluate the function's expression within that frame.
+    // 3. Pop the frame, whether or not evaluation succeeded.
+    fn call(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        function: &Rc<Function<N>>,
+        args: Vec<Value<N>>,
+    ) -> Result<Value<N>, Error> {
+        if self.frames.len() >= self.limits.max_call_depth {
+            return Err(Error::CallDepthExceeded(statement.clone(), name.clone()));
+        }
+        if let Some(ref mut observer) = self.observer {
+            observer.function_entry(name, &args);
+        }
+        self.frames.push(Frame {
+            function: function.clone(),
+            args,
+        });
+        let result = self.evaluate_expression(statement, &function.body);
+        self.frames.pop();
+        if let Some(ref mut observer) = self.observer {
+            observer.function_return(name, result.as_ref());
+        }
+        return result;
+    }
+
+    #[inline(never)]
+    fn evaluate_builtin(
+        &mut self,
+        statement: &Name,
+        name: &Name,
+        args: &[Expression],
+    ) -> Result<Value<N>, Error> {
+        let builtin = match self.builtins.get(name) {
+            Some(builtin) => builtin.clone(),
+            None if name.as_str() == "d" => return self.evaluate_derivative(statement, name, args),
+            None => {
+                if let Some(arity) = list_builtin_arity(name) {
+                    return self.evaluate_list_builtin(statement, name, arity, args);
+                }
+                return Err(match self.lookup_variable(name) {
+                    Some(value) => Error::WrongType(statement.clone(), Kind::Function, value.kind()),
+                    None => Error::UnknownFunction(statement.clone(), name.clone()),
+                });
+            }
+        };
+        if args.len() != builtin.arity {
+            return Err(Error::ArityMismatch(
+                statement.clone(),
+                name.clone(),
+                builtin.arity,
+                args.len(),
+            ));
+        }
+        let mut arg_values = Vec::with_capacity(args.len());
+        for arg in args {
+            arg_values.push(self.evaluate_expression(statement, arg)?);
+        }
+        if let Some(ref mut observer) = self.observer {
+            observer.function_entry(name, &arg_values);
+        }
+        let mut numbers = Vec::with_capacity(arg_values.len());
+        let mut result = Ok(());
+        for value in arg_values {
+            match value.number(statement) {
+                Ok(n) => numbers.push(n),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let result = result.and_then(|()| {
+            (builtin.function)(&numbers)
+                .map(Value::Number)
+                .map_err(|message| Error::BuiltinFailed(statement.clone(), name.clone(), message))
+        });
+        if let Some(ref mut observer) = self.observer {
+            observer.function_return(name, result.as_ref());
+        }
+        return result;
+    }
+}
+
+enum Callee<N> {
+    // A definition, called by the name it was defined with.
+    Defined(Rc<Function<N>>),
+    Value(Rc<Function<N>>),
+    Builtin,
+}
+
+// Shared with the bytecode VM so that both backends agree on every result and
+// every error.
+pub(crate) fn evaluate_operation<N: Number>(
+    statement: &Name,
+    operator: Operator,
+    operand1: N,
+    operand2: N,
+) -> Result<N, Error> {
+    if operator == Operator::Divide && operand2.is_zero() {
+        return Err(Error::DivisionByZero(statement.clone()));
+    }
+    let result = match operator {
+        Operator::Add => operand1.checked_add(&operand2),
+        Operator::Subtract => operand1.checked_sub(&operand2),
+        Operator::Multiply => operand1.checked_mul(&operand2),
+        Operator::Divide => operand1.checked_div(&operand2),
+        Operator::Equal => Some(N::from_i64((operand1 == operand2) as i64)),
+        Operator::NotEqual => Some(N::from_i64((operand1 != operand2) as i64)),
+        Operator::Less => Some(N::from_i64((operand1 < operand2) as i64)),
+        Operator::LessEqual => Some(N::from_i64((operand1 <= operand2) as i64)),
+        Operator::Greater => Some(N::from_i64((operand1 > operand2) as i64)),
+        Operator::GreaterEqual => Some(N::from_i64((operand1 >= operand2) as i64)),
+    };
+    result.ok_or_else(|| Error::Overflow(statement.clone(), operator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+    use math::number::{BigInt, BigRational};
+
+    fn variable(results: &HashMap<Name, i64>, name: &str) -> i64 {
+        results[&Name(name.to_string())]
+    }
+
+    #[test]
+    fn precedence_test() {
+        let results = interpret(b"a = 1 + 3 * 6;\nb = (1 + 3) * 6;\nc = 2 * 3 - 8 / 4 / 2;").unwrap();
+        assert_eq!(variable(&results, "a"), 19);
+        assert_eq!(variable(&results, "b"), 24);
+        assert_eq!(variable(&results, "c"), 5);
+    }
+
+    #[test]
+    fn function_precedence_test() {
+        let results = interpret(b"f(i, j) = 5 * (i - j);\na = 1 + f(3, 1) * 2;").unwrap();
+        assert_eq!(variable(&results, "a"), 21);
+    }
+
+    fn error(s: &[u8]) -> Error {
+        match interpret(s) {
+            Err(::math::Error::ExecutionError(e)) => e,
+            other => panic!("expected an execution error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_name_test() {
+        assert_eq!(error(b"a = b + 1;"), Error::UnknownVariable(name("a"), name("b")));
+        assert_eq!(error(b"a = 1;\nb = f(a);"), Error::UnknownFunction(name("b"), name("f")));
+    }
+
+    #[test]
+    fn arity_mismatch_test() {
+        assert_eq!(
+            error(b"f(i, j) = i + j;\na = f(1);"),
+            Error::ArityMismatch(name("a"), name("f"), 2, 1)
+        );
+    }
+
+    #[test]
+    fn division_by_zero_test() {
+        assert_eq!(
+            error(b"f(i) = 10 / i;\na = f(2);\nb = f(a - 5);"),
+            Error::DivisionByZero(name("b"))
+        );
+    }
+
+    #[test]
+    fn overflow_test() {
+        assert_eq!(
+            error(b"a = 9223372036854775807 + 1;"),
+            Error::Overflow(name("a"), Operator::Add)
+        );
+        assert_eq!(
+            error(b"a = -9223372036854775808;\nb = a / -1;"),
+            Error::Overflow(name("b"), Operator::Divide)
+        );
+    }
+
+    #[test]
+    fn function_captures_globals_at_definition_test() {
+        let results = interpret(b"a = 2;\nf(x) = x * a;\na = 10;\nb = f(3);").unwrap();
+        assert_eq!(variable(&results, "b"), 6);
+        assert_eq!(variable(&results, "a"), 10);
+    }
+
+    #[test]
+    fn function_cannot_see_later_globals_test() {
+        assert_eq!(
+            error(b"f(x) = x + a;\na = 1;\nb = f(1);"),
+            Error::UnknownVariable(name("b"), name("a"))
+        );
+    }
+
+    #[test]
+    fn function_cannot_see_callers_params_test() {
+        assert_eq!(
+            error(b"f(x) = x + y;\ng(y) = f(y);\na = g(1);"),
+            Error::UnknownVariable(name("a"), name("y"))
+        );
+    }
+
+    #[test]
+    fn params_shadow_globals_test() {
+        let results = interpret(b"x = 100;\nf(x) = x + 1;\ng(y) = f(y * 2) + y;\nb = g(x);").unwrap();
+        assert_eq!(variable(&results, "b"), 301);
+        assert_eq!(variable(&results, "x"), 100);
+    }
+
+    #[test]
+    fn comparison_test() {
+        let results = interpret(b"a = 1 + 2 == 3;\nb = 2 * 3 < 5;\nc = 4 >= 4;\nd = 4 != 4;").unwrap();
+        assert_eq!(variable(&results, "a"), 1);
+        assert_eq!(variable(&results, "b"), 0);
+        assert_eq!(variable(&results, "c"), 1);
+        assert_eq!(variable(&results, "d"), 0);
+    }
+
+    #[test]
+    fn conditional_only_evaluates_chosen_branch_test() {
+        let results = interpret(b"a = if 1 < 2 then 10 else 1 / 0;\nb = 1 + if a then 2 else 3;").unwrap();
+        assert_eq!(variable(&results, "a"), 10);
+        assert_eq!(variable(&results, "b"), 3);
+    }
+
+    #[test]
+    fn recursion_test() {
+        let results = interpret(
+            b"fact(n) = if n <= 1 then 1 else n * fact(n - 1);
+              fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2);
+              gcd(a, b) = if b == 0 then a else gcd(b, a - b * (a / b));
+              a = fact(10);
+              b = fib(15);
+              c = gcd(1071, 462);",
+        ).unwrap();
+        assert_eq!(variable(&results, "a"), 3628800);
+        assert_eq!(variable(&results, "b"), 610);
+        assert_eq!(variable(&results, "c"), 21);
+    }
+
+    #[test]
+    fn call_depth_test() {
+        assert_eq!(
+            error(b"f(n) = f(n + 1);\na = f(0);"),
+            Error::CallDepthExceeded(name("a"), name("f"))
+        );
+
+        let statements = ::math::parser::parse(b"f(n) = if n == 0 then 0 else f(n - 1);\na = f(10);").unwrap();
+        let mut executor: Executor = Executor::new();
+        executor.limits.max_call_depth = 10;
+        assert_eq!(
+            executor.run(statements),
+            Err(Error::CallDepthExceeded(name("a"), name("f")))
+        );
+    }
+
+    #[test]
+    fn big_integer_test() {
+        let source = b"fact(n) = if n <= 1 then 1 else n * fact(n - 1);\na = fact(30);\nb = -7 / 2;";
+        assert_eq!(error(source), Error::Overflow(name("a"), Operator::Multiply));
+        let results = ::math::interpret_as::<BigInt>(source).unwrap();
+        assert_eq!(
+            results[&name("a")].to_string(),
+            "265252859812191058636308480000000"
+        );
+        assert_eq!(results[&name("b")], BigInt::from(-3));
+    }
+
+    #[test]
+    fn rational_test() {
+        let results = ::math::interpret_as::<BigRational>(b"a = 89 / 9;\nb = a * 9;\nc = 1 / 3 + 1 / 6 == 1 / 2;").unwrap();
+        assert_eq!(results[&name("a")].to_string(), "89/9");
+        assert_eq!(results[&name("b")], BigRational::from_i64(89));
+        assert_eq!(results[&name("c")], BigRational::from_i64(1));
+        assert_eq!(
+            ::math::interpret_as::<BigRational>(b"a = 1 / (1 / 2 - 1 / 2);"),
+            Err(::math::Error::ExecutionError(Error::DivisionByZero(name("a"))))
+        );
+    }
+
+    #[test]
+    fn builtins_test() {
+        let results = interpret(
+            b"a = abs(-3) + min(4, 2) * max(4, 2);
+              b = pow(2, 10) + sqrt(17) + floor(9);
+              c = mod(-7, 3) + gcd(-12, 18);
+              abs(x) = 0;
+              d = abs(-3);",
+        ).unwrap();
+        assert_eq!(variable(&results, "a"), 11);
+        assert_eq!(variable(&results, "b"), 1037);
+        assert_eq!(variable(&results, "c"), 5);
+        assert_eq!(variable(&results, "d"), 0);
+        assert_eq!(error(b"a = min(1);"), Error::ArityMismatch(name("a"), name("min"), 2, 1));
+        assert_eq!(
+            error(b"a = mod(1, 0);"),
+            Error::BuiltinFailed(name("a"), name("mod"), "division by zero".to_string())
+        );
+        assert_eq!(
+            error(b"a = pow(2, 64);"),
+            Error::BuiltinFailed(
+                name("a"),
+                name("pow"),
+                "2 to the power of 64 is not representable".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn register_builtin_test() {
+        let statements = ::math::parser::parse(b"a = clamp(15, 0, 10) + clamp(-5, 0, 10);\nb = clamp(1, 2, 0);").unwrap();
+        let mut executor: Executor = Executor::new();
+        executor.register_builtin("clamp", 3, |args| {
+            if args[1] > args[2] {
+                return Err(format!("empty range {}..{}", args[1], args[2]));
+            }
+            Ok(args[0].max(args[1]).min(args[2]))
+        });
+        assert_eq!(
+            executor.run(statements),
+            Err(Error::BuiltinFailed(name("b"), name("clamp"), "empty range 2..0".to_string()))
+        );
+        assert_eq!(executor.variables[&name("a")], 10);
+    }
+
+    #[test]
+    fn higher_order_functions_test() {
+        let results = interpret(b"apply_twice(f, x) = f(f(x));\n\
+                                  double(x) = x * 2;\n\
+                                  a = apply_twice(double, 3);\n\
+                                  b = apply_twice(abs, -3) + apply_twice(\\x -> x - 1, 0);\n\
+                                  compose(f, g) = \\x -> f(g(x));\n\
+                                  h = compose(double, \\x -> x + 1);\n\
+                                  c = h(4);")
+            .unwrap();
+        assert_eq!(variable(&results, "a"), 12);
+        assert_eq!(variable(&results, "b"), 1);
+        assert_eq!(variable(&results, "c"), 10);
+    }
+
+    #[test]
+    fn closures_capture_values_test() {
+        let mut executor: Executor = Executor::new();
+        let statements = ::math::parser::parse(b"adder(n) = \\x -> x + n;\n\
+                                                 add2 = adder(2);\n\
+                                                 n = 100;\n\
+                                                 a = add2(5);").unwrap();
+        executor.run(statements).unwrap();
+        assert_eq!(executor.variables[&name("a")], 7);
+        assert_eq!(executor.values[&name("add2")].kind(), Kind::Function);
+        assert_eq!(executor.values[&name("add2")].to_string(), "\\x -> x + n");
+    }
+
+    #[test]
+    fn call_results_test() {
+        let results = interpret(b"adder(n) = \\x -> x + n;\n\
+                                  a = adder(5)(1);\n\
+                                  f(x) = adder(x * 2);\n\
+                                  g(y) = f(1)(y);\n\
+                                  b = g(3) + (f)(2)(1);\n\
+                                  c = (\\x -> x + 1)(4);\n\
+                                  d = (if a > 1 then adder(1) else abs)(-3);")
+            .unwrap();
+        assert_eq!(variable(&results, "a"), 6);
+        assert_eq!(variable(&results, "b"), 10);
+        assert_eq!(variable(&results, "c"), 5);
+        assert_eq!(variable(&results, "d"), -2);
+    }
+
+    #[test]
+    fn wrong_type_test() {
+        assert_eq!(
+            error(b"f(x) = x(1);\na = f(2);"),
+            Error::WrongType(name("a"), Kind::Function, Kind::Number)
+        );
+        assert_eq!(
+            error(b"f(x) = x + 1;\na = f(abs);"),
+            Error::WrongType(name("a"), Kind::Number, Kind::Function)
+        );
+        assert_eq!(
+            error(b"a = 1;\nb = a(2);"),
+            Error::WrongType(name("b"), Kind::Function, Kind::Number)
+        );
+        assert_eq!(
+            error(b"f = \\x, y -> x;\na = f(1);"),
+            Error::ArityMismatch(name("a"), name("f"), 2, 1)
+        );
+        assert_eq!(
+            error(b"a = (1)(2);"),
+            Error::WrongType(name("a"), Kind::Function, Kind::Number)
+        );
+        assert_eq!(
+            error(b"adder(n) = \\x -> x + n;\na = adder(1)(2, 3);"),
+            Error::ArityMismatch(name("a"), name("adder(1)"), 1, 2)
+        );
+    }
+
+    #[test]
+    fn float_test() {
+        let source = b"r = 2.5;\narea = 3.14159 * pow(r, 2);\nroot = sqrt(2) * 1e3;";
+        assert_eq!(error(source), Error::NotRepresentable(name("r"), "2.5".to_string()));
+        let results = ::math::interpret_as::<f64>(source).unwrap();
+        assert_eq!(results[&name("area")], 3.14159 * 6.25);
+        assert_eq!(results[&name("root")], 2f64.sqrt() * 1000.0);
+        let results = ::math::interpret_as::<BigRational>(b"a = 0.1 + 0.2 == 0.3;\nb = 7.0 / 2;").unwrap();
+        assert_eq!(results[&name("a")], BigRational::from_i64(1));
+        assert_eq!(results[&name("b")].to_string(), "7/2");
+        assert_eq!(
+            ::math::interpret_as::<f64>(b"a = 1e300 * 1e300;"),
+            Err(::math::Error::ExecutionError(Error::Overflow(name("a"), Operator::Multiply)))
+        );
+    }
 }
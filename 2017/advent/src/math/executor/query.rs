@@ -0,0 +1,190 @@
+use super::*;
+
+/// Something a program has defined, as `Executor::definitions` gives it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Definition<'a, N: 'a = i64> {
+    /// A variable assigned a number.
+    Number(&'a N),
+    /// A variable assigned a function or a list.
+    Value(&'a Value<N>),
+    Function(&'a Function<N>),
+}
+
+impl<N: Number> Executor<N> {
+    /// Evaluates `expr` against the variables and functions defined so far,
+    /// without assigning it to anything. Errors name the expression as their
+    /// statement.
+    pub fn eval(&mut self, expr: &Expression) -> Result<Value<N>, Error> {
+        let statement = Name(expr.to_string());
+        self.evaluate_expression(&statement, expr)
+    }
+
+    /// Parses and evaluates a single expression, such as `a * 2 + f(3)`, as
+    /// `eval` does.
+    pub fn eval_str(&mut self, s: &str) -> Result<Value<N>, ::math::Error> {
+        let expr = parser::parse_expression(s.as_bytes()).map_err(::math::Error::ParseError)?;
+        self.eval(&expr).map_err(::math::Error::ExecutionError)
+    }
+
+    /// The number that the variable `name` holds, if it has been assigned one.
+    /// A variable holding a function or a list is in `values` instead.
+    pub fn get_var(&self, name: &str) -> Option<&N> {
+        self.variables.get(&Name(name.to_string()))
+    }
+
+    /// Calls the function `name` with `args`, just as the program could. It
+    /// may be defined by the program, held by a variable, or a builtin, but it
+    /// must give a number.
+    pub fn call_fn(&mut self, name: &str, args: &[N]) -> Result<N, Error> {
+        let name = Name(name.to_string());
+        // The call is evaluated in a frame binding each arg to a param, named
+        // so that no program could use them. The frame captures the variable
+        // called `name` unless a function or builtin takes precedence over it,
+        // so that the call resolves as it would at the top level of a program.
+        let params: Vec<Name> = (0..args.len()).map(|i| Name(i.to_string())).collect();
+        let mut captures = HashMap::new();
+        let callable = self.functions.contains_key(&name)
+            || self.builtins.contains_key(&name)
+            || list_builtin_arity(&name).is_some();
+        if !callable {
+            if let Some(value) = self.lookup_variable(&name) {
+                captures.insert(name.clone(), value);
+            }
+        }
+        let arg_exprs = params
+            .iter()
+            .map(|param| Expression::Operand(Operand::VarSubstitution(param.clone()), Span::default()))
+            .collect();
+        let caller = Rc::new(Function {
+            params,
+            captures,
+            body: Expression::Operand(Operand::FnApplication(name.clone(), arg_exprs), Span::default()),
+        });
+        self.frames.push(Frame {
+            function: caller.clone(),
+            args: args.iter().cloned().map(Value::Number).collect(),
+        });
+        let result = self.evaluate_expression(&name, &caller.body);
+        self.frames.pop();
+        result?.number(&name)
+    }
+
+    /// Every variable and function defined so far, in the order they were
+    /// first defined. A variable and a function may share a name, and are
+    /// then given separately. Anything inserted into `variables`, `values` or
+    /// `functions` directly rather than by running a statement comes last, in
+    /// name order.
+    pub fn definitions(&self) -> Vec<(&Name, Definition<'_, N>)> {
+        let numbers = self.variables.iter().map(|(name, n)| (name, Definition::Number(n)));
+        let values = self.values.iter().map(|(name, value)| (name, Definition::Value(value)));
+        let functions = self
+            .functions
+            .iter()
+            .map(|(name, function)| (name, Definition::Function(&**function)));
+        let mut definitions: Vec<_> = numbers.chain(values).chain(functions).collect();
+        definitions.sort_by_key(|&(name, ref definition)| {
+            let dependency = match *definition {
+                Definition::Function(_) => Dependency::Function(name.clone()),
+                _ => Dependency::Variable(name.clone()),
+            };
+            (self.order.get(&dependency).cloned().unwrap_or(usize::max_value()), name)
+        });
+        return definitions;
+    }
+
+    // Notes that `dependency` has been defined, for `definitions`.
+    pub(super) fn record_definition(&mut self, dependency: Dependency) {
+        let next = self.order.len();
+        self.order.entry(dependency).or_insert(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+    use math::number::BigRational;
+
+    fn run<N: Number>(s: &[u8]) -> Executor<N> {
+        let mut executor = Executor::new();
+        executor.run(parser::parse(s).unwrap()).unwrap();
+        return executor;
+    }
+
+    #[test]
+    fn eval_test() {
+        let mut executor = run::<i64>(b"a = 5;\nf(x) = x * x;\nxs = [1, 2, 3];");
+        assert_eq!(executor.eval_str("a * 2 + f(3)"), Ok(Value::Number(19)));
+        assert_eq!(executor.eval_str("fold(\\sum, x -> sum + x, a, xs)"), Ok(Value::Number(11)));
+        assert_eq!(executor.eval_str("len(map(f, xs))"), Ok(Value::Number(3)));
+        match executor.eval_str("\\x -> x + a") {
+            Ok(Value::Function(function)) => assert_eq!(function.captures[&name("a")], Value::Number(5)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // Nothing is assigned.
+        assert_eq!(executor.variables.len(), 1);
+        assert_eq!(
+            executor.eval_str("a + b"),
+            Err(::math::Error::ExecutionError(Error::UnknownVariable(name("a + b"), name("b"))))
+        );
+        match executor.eval_str("a = 1") {
+            Err(::math::Error::ParseError(e)) => assert_eq!(e.kind, parser::ErrorKind::UnexpectedInput),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut executor = run::<BigRational>(b"a = 1 / 3;");
+        let third = executor.get_var("a").unwrap().clone();
+        assert_eq!(executor.eval_str("a * 3"), Ok(Value::Number(third * BigRational::from_i64(3))));
+    }
+
+    #[test]
+    fn get_var_test() {
+        let executor = run::<i64>(b"a = 5;\nf(x) = x;\ng = f;");
+        assert_eq!(executor.get_var("a"), Some(&5));
+        assert_eq!(executor.get_var("f"), None);
+        assert_eq!(executor.get_var("g"), None);
+        assert_eq!(executor.get_var("b"), None);
+    }
+
+    #[test]
+    fn call_fn_test() {
+        let mut executor = run::<i64>(b"k = 10;\nf(x, y) = k * x + y;\ng = \\x -> f(x, 1);\nmax = 1;");
+        assert_eq!(executor.call_fn("f", &[2, 3]), Ok(23));
+        assert_eq!(executor.call_fn("g", &[2]), Ok(21));
+        assert_eq!(executor.call_fn("max", &[2, 3]), Ok(3));
+        assert_eq!(executor.call_fn("range", &[1, 3]), Err(Error::WrongType(name("range"), Kind::Number, Kind::List)));
+        assert_eq!(executor.call_fn("f", &[1]), Err(Error::ArityMismatch(name("f"), name("f"), 2, 1)));
+        assert_eq!(executor.call_fn("h", &[]), Err(Error::UnknownFunction(name("h"), name("h"))));
+        assert_eq!(executor.call_fn("k", &[]), Err(Error::WrongType(name("k"), Kind::Function, Kind::Number)));
+        assert_eq!(executor.call_fn("f", &[i64::max_value(), 0]), Err(Error::Overflow(name("f"), Operator::Multiply)));
+
+        // Calls are memoized as they would be from a program.
+        executor.memoize(&name("f"));
+        executor.call_fn("f", &[2, 3]).unwrap();
+        executor.call_fn("f", &[2, 3]).unwrap();
+        let stats = executor.memo_stats(&name("f")).unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn definitions_test() {
+        let mut executor = run::<i64>(b"z = 1;\nf(x) = x;\na = [z];\nf = 2;\nz = 3;\nf(x, y) = y;");
+        executor.variables.insert(name("c"), 4);
+        executor.variables.insert(name("b"), 5);
+        let definitions: Vec<_> = executor
+            .definitions()
+            .into_iter()
+            .map(|(name, definition)| match definition {
+                Definition::Number(n) => format!("{} = {}", name, n),
+                Definition::Value(value) => format!("{} = {}", name, value),
+                Definition::Function(function) => format!("{}/{}", name, function.params.len()),
+            })
+            .collect();
+        assert_eq!(definitions, vec!["z = 3", "f/2", "a = [1]", "f = 2", "b = 5", "c = 4"]);
+
+        let mut executor = Executor::<i64>::reactive();
+        executor.run(parser::parse(b"b = 1;\na = b + 1;\nb = 2;").unwrap()).unwrap();
+        let names: Vec<_> = executor.definitions().into_iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["b", "a"]);
+    }
+}
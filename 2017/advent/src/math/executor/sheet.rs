@@ -0,0 +1,528 @@
+use super::*;
+use math::symbolic;
+use std::collections::{BTreeSet, HashSet};
+
+/// Something a variable's defining expression uses, in reactive mode. Calling
+/// a function also counts as using every function its body calls in turn,
+/// since calls are resolved when they happen.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Dependency {
+    Variable(Name),
+    Function(Name),
+}
+
+// A variable's defining expression and everything it uses.
+#[derive(Clone)]
+struct Cell {
+    expression: Expression,
+    dependencies: BTreeSet<Dependency>,
+}
+
+// What a reactive executor remembers in order to recompute variables when what
+// they use changes.
+#[derive(Clone)]
+pub(super) struct Sheet {
+    cells: im_rc::HashMap<Name, Cell>,
+    // The variables whose cells use each dependency.
+    dependents: im_rc::HashMap<Dependency, BTreeSet<Name>>,
+}
+
+impl Sheet {
+    pub(super) fn new() -> Sheet {
+        Sheet {
+            cells: im_rc::HashMap::new(),
+            dependents: im_rc::HashMap::new(),
+        }
+    }
+
+    // Sets or removes the cell of `name`, keeping `dependents` in step, and
+    // returns the cell it replaces.
+    fn replace(&mut self, name: Name, cell: Option<Cell>) -> Option<Cell> {
+        let previous = match cell {
+            Some(cell) => self.cells.insert(name.clone(), cell),
+            None => self.cells.remove(&name),
+        };
+        if let Some(ref previous) = previous {
+            for dependency in &previous.dependencies {
+                let now_unused = match self.dependents.get_mut(dependency) {
+                    Some(names) => {
+                        names.remove(&name);
+                        names.is_empty()
+                    }
+                    None => false,
+                };
+                if now_unused {
+                    self.dependents.remove(dependency);
+                }
+            }
+        }
+        if let Some(cell) = self.cells.get(&name) {
+            for dependency in &cell.dependencies {
+                self.dependents
+                    .entry(dependency.clone())
+                    .or_insert_with(BTreeSet::new)
+                    .insert(name.clone());
+            }
+        }
+        return previous;
+    }
+
+    // Every variable that uses `dependency`, directly or through other
+    // variables.
+    fn affected(&self, dependency: &Dependency) -> BTreeSet<Name> {
+        let mut affected = BTreeSet::new();
+        let mut pending = vec![dependency.clone()];
+        while let Some(dependency) = pending.pop() {
+            if let Some(names) = self.dependents.get(&dependency) {
+                for name in names {
+                    if affected.insert(name.clone()) {
+                        pending.push(Dependency::Variable(name.clone()));
+                    }
+                }
+            }
+        }
+        return affected;
+    }
+
+    // `names` ordered so that each comes after every other one it uses, and
+    // otherwise alphabetically.
+    fn order(&self, names: &BTreeSet<Name>) -> Vec<Name> {
+        let mut waiting: HashMap<&Name, usize> = names
+            .iter()
+            .map(|name| {
+                let count = self.cells[name]
+                    .dependencies
+                    .iter()
+                    .filter(|dependency| match **dependency {
+                        Dependency::Variable(ref used) => names.contains(used),
+                        Dependency::Function(_) => false,
+                    })
+                    .count();
+                (name, count)
+            })
+            .collect();
+        let mut ready: BTreeSet<&Name> = names.iter().filter(|name| waiting[name] == 0).collect();
+        let mut order = vec![];
+        while let Some(name) = ready.iter().next().cloned() {
+            ready.remove(name);
+            order.push(name.clone());
+            if let Some(dependents) = self.dependents.get(&Dependency::Variable(name.clone())) {
+                for dependent in dependents {
+                    if let Some(count) = waiting.get_mut(dependent) {
+                        *count -= 1;
+                        if *count == 0 {
+                            ready.insert(dependent);
+                        }
+                    }
+                }
+            }
+        }
+        return order;
+    }
+}
+
+// Everything an update has changed so far, so that it can be put back if a
+// recomputation fails.
+struct Undo<N> {
+    variables: Vec<(Name, Option<N>)>,
+    function: Option<(Name, Option<Rc<Function<N>>>)>,
+    cells: Vec<(Name, Option<Cell>)>,
+    // The order of definition, which a persistent map makes cheap to keep.
+    order: im_rc::HashMap<Dependency, usize>,
+}
+
+impl<N: Number> Executor<N> {
+    /// An executor in reactive mode, which works like a spreadsheet. Each
+    /// variable keeps the expression that last assigned it, and is recomputed
+    /// whenever a variable or function that expression uses changes.
+    ///
+    /// Functions still capture globals when they are defined, so a function
+    /// has to be redefined to see new values of them. A variable cannot use
+    /// itself, even through other variables, so `a = a + 1` fails with
+    /// `Error::CircularReference`. Variables can only be assigned numbers, not
+    /// functions or lists.
+    pub fn reactive() -> Executor<N> {
+        let mut executor = Executor::new();
+        executor.sheet = Some(Sheet::new());
+        return executor;
+    }
+
+    pub fn is_reactive(&self) -> bool {
+        self.sheet.is_some()
+    }
+
+    /// The expression that last assigned `name`, in reactive mode.
+    pub fn expression(&self, name: &Name) -> Option<&Expression> {
+        self.sheet
+            .as_ref()
+            .and_then(|sheet| sheet.cells.get(name))
+            .map(|cell| &cell.expression)
+    }
+
+    /// The variables whose expressions use `dependency`, directly or through
+    /// a function, in reactive mode.
+    pub fn dependents(&self, dependency: &Dependency) -> Vec<&Name> {
+        self.sheet
+            .as_ref()
+            .and_then(|sheet| sheet.dependents.get(dependency))
+            .map_or(vec![], |names| names.iter().collect())
+    }
+
+    // `update` in reactive mode.
+    pub(super) fn update_reactive(&mut self, statement: Statement) -> Result<Vec<Name>, Error> {
+        let mut undo = Undo {
+            variables: vec![],
+            function: None,
+            cells: vec![],
+            order: self.order.clone(),
+        };
+        let mut changed = HashSet::new();
+        let mut updated = vec![];
+        let root = match statement {
+            Statement::VarAssignment(name, expr, _) => {
+                let dependencies = self.dependencies(&expr);
+                if let Some(variable) = self.circular(&name, &dependencies) {
+                    return Err(Error::CircularReference(name, variable));
+                }
+                let value = self.evaluate_expression(&name, &expr)?.number(&name)?;
+                let cell = Cell {
+                    expression: expr,
+                    dependencies,
+                };
+                let previous = self.sheet_mut().replace(name.clone(), Some(cell));
+                undo.cells.push((name.clone(), previous));
+                self.set_variable(name.clone(), value, &mut changed, &mut updated, &mut undo);
+                Dependency::Variable(name)
+            }
+            Statement::FnDefinition(name, params, expr, _) => {
+                let previous = self.define_function(name.clone(), params, expr);
+                undo.function = Some((name.clone(), previous));
+                changed.insert(Dependency::Function(name.clone()));
+                Dependency::Function(name)
+            }
+            Statement::Import(_, namespace, _) => return Err(Error::UnresolvedImport(namespace)),
+        };
+        match self.propagate(&root, &mut changed, &mut updated, &mut undo) {
+            Ok(()) => Ok(updated),
+            Err(e) => {
+                self.roll_back(undo);
+                Err(e)
+            }
+        }
+    }
+
+    fn sheet(&self) -> &Sheet {
+        self.sheet.as_ref().expect("only used in reactive mode")
+    }
+
+    fn sheet_mut(&mut self) -> &mut Sheet {
+        self.sheet.as_mut().expect("only used in reactive mode")
+    }
+
+    // Recomputes whatever uses `root` that needs to be.
+    fn propagate(
+        &mut self,
+        root: &Dependency,
+        changed: &mut HashSet<Dependency>,
+        updated: &mut Vec<Name>,
+        undo: &mut Undo<N>,
+    ) -> Result<(), Error> {
+        let affected = self.sheet().affected(root);
+        // A redefined function may call different functions from before, and
+        // so change what its callers use.
+        if let Dependency::Function(_) = *root {
+            for name in &affected {
+                let cell = self.sheet().cells[name].clone();
+                let dependencies = self.dependencies(&cell.expression);
+                if dependencies != cell.dependencies {
+                    let cell = Cell {
+                        expression: cell.expression,
+                        dependencies,
+                    };
+                    let previous = self.sheet_mut().replace(name.clone(), Some(cell));
+                    undo.cells.push((name.clone(), previous));
+                }
+            }
+        }
+        for name in self.sheet().order(&affected) {
+            let expression = {
+                let cell = &self.sheet().cells[&name];
+                if !cell.dependencies.iter().any(|dependency| changed.contains(dependency)) {
+                    continue;
+                }
+                cell.expression.clone()
+            };
+            let value = self.evaluate_expression(&name, &expression)?.number(&name)?;
+            self.set_variable(name, value, changed, updated, undo);
+        }
+        return Ok(());
+    }
+
+    fn set_variable(
+        &mut self,
+        name: Name,
+        value: N,
+        changed: &mut HashSet<Dependency>,
+        updated: &mut Vec<Name>,
+        undo: &mut Undo<N>,
+    ) {
+        self.record_definition(Dependency::Variable(name.clone()));
+        let previous = self.variables.insert(name.clone(), value);
+        if previous.as_ref() != self.variables.get(&name) {
+            changed.insert(Dependency::Variable(name.clone()));
+            updated.push(name.clone());
+        }
+        undo.variables.push((name, previous));
+    }
+
+    fn roll_back(&mut self, undo: Undo<N>) {
+        for (name, value) in undo.variables.into_iter().rev() {
+            match value {
+                Some(value) => self.variables.insert(name, value),
+                None => self.variables.remove(&name),
+            };
+        }
+        for (name, cell) in undo.cells.into_iter().rev() {
+            self.sheet_mut().replace(name, cell);
+        }
+        if let Some((name, function)) = undo.function {
+            self.invalidate_memos(&name);
+            match function {
+                Some(function) => self.functions.insert(name, function),
+                None => self.functions.remove(&name),
+            };
+        }
+        self.order = undo.order;
+    }
+
+    // A variable through which `name` would come to use itself, were it
+    // assigned an expression with these dependencies.
+    fn circular(&self, name: &Name, dependencies: &BTreeSet<Dependency>) -> Option<Name> {
+        let affected = self.sheet().affected(&Dependency::Variable(name.clone()));
+        dependencies
+            .iter()
+            .filter_map(|dependency| match *dependency {
+                Dependency::Variable(ref used) if used == name || affected.contains(used) => {
+                    Some(used.clone())
+                }
+                _ => None,
+            })
+            .next()
+    }
+
+    fn dependencies(&self, expr: &Expression) -> BTreeSet<Dependency> {
+        let mut dependencies = BTreeSet::new();
+        self.collect_dependencies(false, expr, &mut dependencies);
+        return dependencies;
+    }
+
+    // Function bodies only read their params and the globals they captured,
+    // so only the calls they make count.
+    fn collect_dependencies(
+        &self,
+        in_body: bool,
+        expr: &Expression,
+        dependencies: &mut BTreeSet<Dependency>,
+    ) {
+        match *expr {
+            Expression::Operand(Operand::I64(_), _) | Expression::Operand(Operand::F64(_), _) => {}
+            Expression::Operand(Operand::VarSubstitution(ref name), _) => {
+                if !in_body {
+                    dependencies.insert(Dependency::Variable(name.clone()));
+                }
+                // Naming a function passes it, to be called later.
+                if self.functions.contains_key(name) {
+                    self.collect_call(name, dependencies);
+                }
+            }
+            Expression::Operand(Operand::FnApplication(ref name, ref args), _) => {
+                self.collect_call(name, dependencies);
+                // `d(f, x)` uses `f`, and without args of its own, whichever
+                // variables are named like `f`'s params.
+                let derivative = match self.functions.get(name) {
+                    Some(_) => None,
+                    None => symbolic::derivative_call(name, args),
+                };
+                if let Some((function, _, point)) = derivative {
+                    self.collect_call(function, dependencies);
+                    match self.functions.get(function) {
+                        Some(function) if point.is_empty() && !in_body => {
+                            for param in &function.params {
+                                dependencies.insert(Dependency::Variable(param.clone()));
+                            }
+                        }
+                        _ => {}
+                    }
+                    for arg in point {
+                        self.collect_dependencies(in_body, arg, dependencies);
+                    }
+                    return;
+                }
+                for arg in args {
+                    self.collect_dependencies(in_body, arg, dependencies);
+                }
+            }
+            // A lambda's params are not variables, even where it reads the
+            // variables around it.
+            Expression::Operand(Operand::Lambda(ref params, ref body), _) => {
+                let mut used = BTreeSet::new();
+                self.collect_dependencies(in_body, body, &mut used);
+                for param in params {
+                    used.remove(&Dependency::Variable(param.clone()));
+                }
+                dependencies.extend(used);
+            }
+            Expression::Operand(Operand::List(ref items), _) => {
+                for item in items {
+                    self.collect_dependencies(in_body, item, dependencies);
+                }
+            }
+            Expression::Operand(Operand::Call(ref callee, ref args), _) => {
+                self.collect_dependencies(in_body, callee, dependencies);
+                for arg in args {
+                    self.collect_dependencies(in_body, arg, dependencies);
+                }
+            }
+            Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+                self.collect_dependencies(in_body, condition, dependencies);
+                self.collect_dependencies(in_body, consequent, dependencies);
+                self.collect_dependencies(in_body, alternative, dependencies);
+            }
+            Expression::Operation(ref lhs, _, ref rhs, _) => {
+                self.collect_dependencies(in_body, lhs, dependencies);
+                self.collect_dependencies(in_body, rhs, dependencies);
+            }
+        }
+    }
+
+    fn collect_call(&self, name: &Name, dependencies: &mut BTreeSet<Dependency>) {
+        if dependencies.insert(Dependency::Function(name.clone())) {
+            if let Some(function) = self.functions.get(name) {
+                self.collect_dependencies(true, &function.body, dependencies);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+
+    fn names(names: &[&str]) -> Vec<Name> {
+        names.iter().map(|n| name(n)).collect()
+    }
+
+    fn update(executor: &mut Executor, s: &str) -> Result<Vec<Name>, Error> {
+        let mut changed = vec![];
+        for statement in parser::parse(s.as_bytes()).unwrap() {
+            changed.extend(executor.update(statement)?);
+        }
+        return Ok(changed);
+    }
+
+    #[test]
+    fn recompute_test() {
+        let mut executor = Executor::reactive();
+        update(&mut executor, "a = 1;\nb = a * 10;\nc = b + a;\nd = 7;\ne = if d then c else a;").unwrap();
+        assert_eq!(update(&mut executor, "a = 2;").unwrap(), names(&["a", "b", "c", "e"]));
+        assert_eq!(executor.variables[&name("e")], 22);
+        // `b` and `c` are unaffected, so nothing that uses them is recomputed.
+        assert_eq!(update(&mut executor, "d = 8;").unwrap(), names(&["d"]));
+        assert_eq!(update(&mut executor, "a = 2;").unwrap(), names(&[]));
+        assert_eq!(update(&mut executor, "b = 0;").unwrap(), names(&["b", "c", "e"]));
+        assert_eq!(update(&mut executor, "a = 3;").unwrap(), names(&["a", "c", "e"]));
+        assert_eq!(executor.variables[&name("e")], 3);
+        assert_eq!(
+            executor.dependents(&Dependency::Variable(name("a"))),
+            vec![&name("c"), &name("e")]
+        );
+    }
+
+    #[test]
+    fn function_redefinition_test() {
+        let mut executor = Executor::reactive();
+        update(&mut executor, "g(x) = x + 1;\nf(x) = g(x) * 2;\na = 1;\nb = f(a);\nc = abs(-1);").unwrap();
+        assert_eq!(update(&mut executor, "g(x) = x + 2;").unwrap(), names(&["b"]));
+        assert_eq!(executor.variables[&name("b")], 6);
+        assert_eq!(update(&mut executor, "h(x) = x;\nf(x) = h(x);").unwrap(), names(&["b"]));
+        assert_eq!(update(&mut executor, "g(x) = x;").unwrap(), names(&[]));
+        assert_eq!(update(&mut executor, "h(x) = x * 5;").unwrap(), names(&["b"]));
+        assert_eq!(update(&mut executor, "abs(x) = 7;").unwrap(), names(&["c"]));
+    }
+
+    #[test]
+    fn function_values_test() {
+        let mut executor = Executor::reactive();
+        let source = "g(x) = x + 1;\napply(f, x) = f(x);\nn = 2;\na = apply(g, 1);\n\
+                      b = fold(\\s, x -> s + x * n, 0, [1, 2]);";
+        update(&mut executor, source).unwrap();
+        assert_eq!(update(&mut executor, "g(x) = x * 10;").unwrap(), names(&["a"]));
+        assert_eq!(executor.variables[&name("a")], 10);
+        assert_eq!(update(&mut executor, "n = 3;").unwrap(), names(&["n", "b"]));
+        assert_eq!(executor.variables[&name("b")], 9);
+        assert_eq!(
+            update(&mut executor, "n = [1];"),
+            Err(Error::WrongType(name("n"), Kind::Number, Kind::List))
+        );
+    }
+
+    #[test]
+    fn derivative_test() {
+        let mut executor = Executor::reactive();
+        update(&mut executor, "f(x) = x * x;\nx = 3;\na = d(f, x);\nb = d(f, x, 1);").unwrap();
+        assert_eq!(update(&mut executor, "x = 4;").unwrap(), names(&["x", "a"]));
+        assert_eq!(update(&mut executor, "f(x) = x * x * x;").unwrap(), names(&["a", "b"]));
+        assert_eq!(executor.variables[&name("a")], 48);
+    }
+
+    #[test]
+    fn failed_update_has_no_effect_test() {
+        let mut executor = Executor::reactive();
+        update(&mut executor, "a = 1;\nb = 10 / a;\nf(x) = 1;\nc = f(0);").unwrap();
+        assert_eq!(update(&mut executor, "a = 0;"), Err(Error::DivisionByZero(name("b"))));
+        assert_eq!(update(&mut executor, "f(x) = 1 / x;"), Err(Error::DivisionByZero(name("c"))));
+        assert_eq!(
+            (executor.variables[&name("a")], executor.variables[&name("b")]),
+            (1, 10)
+        );
+        assert_eq!(update(&mut executor, "a = 5;").unwrap(), names(&["a", "b"]));
+        assert_eq!(update(&mut executor, "c = 3;").unwrap(), names(&["c"]));
+        assert_eq!(update(&mut executor, "f(x) = 2;").unwrap(), names(&[]));
+        // Nor does what it defined count as defined then rather than later,
+        // here the variable `y` beside the function.
+        let mut executor = Executor::reactive();
+        update(&mut executor, "y(x) = x;\nb = len(map(y, [1]));").unwrap();
+        assert_eq!(
+            update(&mut executor, "y = 0;"),
+            Err(Error::WrongType(name("b"), Kind::Function, Kind::Number))
+        );
+        update(&mut executor, "z = 1;\nb = 2;\ny = 3;").unwrap();
+        let order: Vec<_> = executor.definitions().into_iter().map(|(name, _)| name.clone()).collect();
+        assert_eq!(order, names(&["y", "b", "z", "y"]));
+    }
+
+    #[test]
+    fn circular_reference_test() {
+        let mut executor = Executor::reactive();
+        update(&mut executor, "a = 1;\nb = a + 1;\nc = b + 1;").unwrap();
+        assert_eq!(
+            update(&mut executor, "a = a + 1;"),
+            Err(Error::CircularReference(name("a"), name("a")))
+        );
+        assert_eq!(
+            update(&mut executor, "a = c;"),
+            Err(Error::CircularReference(name("a"), name("c")))
+        );
+        assert_eq!(
+            executor.expression(&name("a")),
+            Some(&Expression::Operand(Operand::I64(1), Span::default()))
+        );
+        // Outside of reactive mode, only the assigned variable can change.
+        let mut executor = Executor::new();
+        assert_eq!(
+            update(&mut executor, "a = 1;\nb = a;\na = 2;\na = 2;").unwrap(),
+            names(&["a", "b", "a"])
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use super::*;
+use std::collections::VecDeque;
+
+/// The default for `Executor::undo_limit`.
+pub const DEFAULT_UNDO_LIMIT: usize = 100;
+
+/// The variables and functions of an executor at some point, which `restore`
+/// returns it to. Taking one copies no more than a few pointers, however much
+/// has been defined, as the persistent maps it holds share their structure
+/// with the executor's.
+///
+/// Builtins, limits and the steps taken towards them, and which functions are
+/// memoized are not part of it.
+#[derive(Clone)]
+pub struct Snapshot<N = i64> {
+    variables: im_rc::HashMap<Name, N>,
+    values: im_rc::HashMap<Name, Value<N>>,
+    functions: im_rc::HashMap<Name, Rc<Function<N>>>,
+    sheet: Option<Sheet>,
+    order: im_rc::HashMap<Dependency, usize>,
+}
+
+impl<N: Number> Executor<N> {
+    pub fn snapshot(&self) -> Snapshot<N> {
+        Snapshot {
+            variables: self.variables.clone(),
+            values: self.values.clone(),
+            functions: self.functions.clone(),
+            sheet: self.sheet.clone(),
+            order: self.order.clone(),
+        }
+    }
+
+    /// Returns to the state that `snapshot` was taken in, clearing the cache
+    /// of any memoized function that going back redefines, or that calls one
+    /// that it redefines.
+    pub fn restore(&mut self, snapshot: &Snapshot<N>) {
+        let snapshot = snapshot.clone();
+        let redefined: Vec<Name> = if self.memos.is_empty() {
+            vec![]
+        } else {
+            let changed = self
+                .functions
+                .iter()
+                .filter(|&(name, function)| match snapshot.functions.get(name) {
+                    Some(restored) => !Rc::ptr_eq(function, restored),
+                    None => true,
+                })
+                .map(|(name, _)| name);
+            let added = snapshot
+                .functions
+                .keys()
+                .filter(|&name| !self.functions.contains_key(name));
+            // Calls of functions held in variables look them up when made.
+            let reassigned = self
+                .values
+                .iter()
+                .filter(|&(name, value)| snapshot.values.get(name) != Some(value))
+                .map(|(name, _)| name);
+            let assigned = snapshot
+                .values
+                .keys()
+                .filter(|&name| !self.values.contains_key(name));
+            changed.chain(added).chain(reassigned).chain(assigned).cloned().collect()
+        };
+        self.variables = snapshot.variables;
+        self.values = snapshot.values;
+        self.functions = snapshot.functions;
+        self.sheet = snapshot.sheet;
+        self.order = snapshot.order;
+        for name in &redefined {
+            self.invalidate_memos(name);
+        }
+    }
+
+    /// Like `run`, but all or nothing: if any statement fails then the
+    /// executor is left as it was before the first.
+    pub fn run_atomic(&mut self, statements: Vec<Statement>) -> Result<(), Error> {
+        let snapshot = self.snapshot();
+        let result = self.run(statements);
+        if result.is_err() {
+            self.restore(&snapshot);
+        }
+        return result;
+    }
+
+    /// Saves the state as it is now for `undo` to return to. Only the latest
+    /// `undo_limit` checkpoints are kept.
+    pub fn checkpoint(&mut self) {
+        if self.undo_limit == 0 {
+            return;
+        }
+        while self.undo_stack.len() >= self.undo_limit {
+            self.undo_stack.pop_front();
+        }
+        let snapshot = self.snapshot();
+        self.undo_stack.push_back(snapshot);
+    }
+
+    /// Returns to the latest checkpoint, and forgets it. Returns false, doing
+    /// nothing, when there are no checkpoints left.
+    pub fn undo(&mut self) -> bool {
+        match self.undo_stack.pop_back() {
+            Some(snapshot) => {
+                self.restore(&snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many checkpoints `undo` can go back through.
+    pub fn undo_depth(&self) -> usize {
+        self.undo_stack.len()
+    }
+}
+
+// The checkpoints that `undo` returns to, newest last.
+pub(super) type UndoStack<N> = VecDeque<Snapshot<N>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+
+    fn parse(s: &[u8]) -> Vec<Statement> {
+        parser::parse(s).unwrap()
+    }
+
+    #[test]
+    fn restore_test() {
+        let mut executor = Executor::<i64>::new();
+        executor.run(parse(b"a = 1;\nf(x) = x + a;\nxs = [a];")).unwrap();
+        let snapshot = executor.snapshot();
+        executor.run(parse(b"a = 2;\nb = 3;\nf(x) = x;\nxs = f;\ng(x) = x;")).unwrap();
+        executor.restore(&snapshot);
+        assert_eq!(executor.eval_str("a + f(10)"), Ok(Value::Number(12)));
+        assert_eq!(executor.get_var("b"), None);
+        assert_eq!(executor.values[&name("xs")], Value::List(Rc::new(vec![1])));
+        assert!(!executor.functions.contains_key(&name("g")));
+        let names: Vec<_> = executor.definitions().into_iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["a", "f", "xs"]);
+        // A snapshot can be restored more than once.
+        executor.run(parse(b"a = 5;")).unwrap();
+        executor.restore(&snapshot);
+        assert_eq!(executor.get_var("a"), Some(&1));
+    }
+
+    #[test]
+    fn run_atomic_test() {
+        let mut executor = Executor::<i64>::new();
+        executor.run(parse(b"a = 1;")).unwrap();
+        assert_eq!(
+            executor.run_atomic(parse(b"a = 2;\nf(x) = x;\nb = 1 / 0;")),
+            Err(Error::DivisionByZero(name("b")))
+        );
+        assert_eq!(executor.get_var("a"), Some(&1));
+        assert!(executor.functions.is_empty());
+        executor.run_atomic(parse(b"a = 2;\nb = a * 2;")).unwrap();
+        assert_eq!((executor.get_var("a"), executor.get_var("b")), (Some(&2), Some(&4)));
+
+        // Unlike `run`, which keeps what ran before the failure.
+        assert!(executor.run(parse(b"c = 1;\nd = e;")).is_err());
+        assert_eq!(executor.get_var("c"), Some(&1));
+    }
+
+    #[test]
+    fn reactive_test() {
+        let mut executor = Executor::<i64>::reactive();
+        executor.run(parse(b"a = 1;\nb = a + 1;")).unwrap();
+        let snapshot = executor.snapshot();
+        executor.run(parse(b"a = 10;\nb = a * 2;\nc = b;")).unwrap();
+        executor.restore(&snapshot);
+        executor.run(parse(b"a = 5;")).unwrap();
+        assert_eq!((executor.get_var("b"), executor.get_var("c")), (Some(&6), None));
+        assert_eq!(executor.dependents(&Dependency::Variable(name("b"))), Vec::<&Name>::new());
+    }
+
+    #[test]
+    fn memos_test() {
+        let mut executor = Executor::<i64>::new();
+        executor.run(parse(b"g(x) = x;\nf(x) = g(x) * 2;")).unwrap();
+        executor.memoize(&name("f"));
+        let snapshot = executor.snapshot();
+        executor.run(parse(b"g(x) = x + 1;\na = f(1);")).unwrap();
+        assert_eq!(executor.get_var("a"), Some(&4));
+        executor.restore(&snapshot);
+        // The cached `f(1)` used the other `g`.
+        assert_eq!(executor.call_fn("f", &[1]), Ok(2));
+        assert_eq!(executor.memo_stats(&name("f")).unwrap().hits, 0);
+    }
+
+    #[test]
+    fn undo_test() {
+        let mut executor = Executor::<i64>::new();
+        executor.undo_limit = 2;
+        assert!(!executor.undo());
+        for source in &[&b"a = 1;"[..], b"a = 2;", b"a = 3;", b"a = 4;"] {
+            executor.checkpoint();
+            executor.run(parse(source)).unwrap();
+        }
+        assert_eq!(executor.undo_depth(), 2);
+        assert!(executor.undo());
+        assert_eq!(executor.get_var("a"), Some(&3));
+        assert!(executor.undo());
+        assert_eq!(executor.get_var("a"), Some(&2));
+        assert!(!executor.undo());
+        assert_eq!(executor.get_var("a"), Some(&2));
+    }
+
+    // Snapshots share structure rather than copying, so taking one for each
+    // of many statements stays fast.
+    #[test]
+    fn many_snapshots_test() {
+        let mut executor = Executor::<i64>::new();
+        executor.undo_limit = 10_000;
+        for i in 0..5_000 {
+            executor.checkpoint();
+            executor.run(parse(format!("v{} = {};", i, i).as_bytes())).unwrap();
+        }
+        for _ in 0..4_000 {
+            executor.undo();
+        }
+        assert_eq!(executor.variables.len(), 1_000);
+        assert_eq!(executor.get_var("v999"), Some(&999));
+    }
+}
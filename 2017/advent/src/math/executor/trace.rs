@@ -0,0 +1,159 @@
+use super::*;
+use std::cell::RefCell;
+use std::io::Write;
+
+/// Receives an event for each step of evaluation, as set in
+/// `Executor::observer`. Every method does nothing by default.
+///
+/// Events nest: each `statement_start` is followed by a `statement_end`, and
+/// each `function_entry` by a `function_return`, whether or not evaluation
+/// succeeds in between. Builtins are reported like any other function, and so
+/// are functions passed as values, by the name they are called by. In
+/// reactive mode, variables recomputed because of a statement are evaluated
+/// between its start and end.
+pub trait Observer<N> {
+    fn statement_start(&mut self, _statement: &Statement) {}
+
+    /// `value` is the value assigned, if the statement was an assignment of a
+    /// number that succeeded.
+    fn statement_end(&mut self, _statement: &Name, _value: Result<Option<&N>, &Error>) {}
+
+    /// Called once the args have been evaluated, before the body is.
+    fn function_entry(&mut self, _function: &Name, _args: &[Value<N>]) {}
+
+    fn function_return(&mut self, _function: &Name, _result: Result<&Value<N>, &Error>) {}
+
+    fn operation(&mut self, _operator: Operator, _lhs: &N, _rhs: &N, _result: Result<&N, &Error>) {}
+}
+
+// So that whoever sets up an observer can keep a handle on it, to look at what
+// it saw afterwards.
+impl<N, O: Observer<N>> Observer<N> for Rc<RefCell<O>> {
+    fn statement_start(&mut self, statement: &Statement) {
+        self.borrow_mut().statement_start(statement)
+    }
+
+    fn statement_end(&mut self, statement: &Name, value: Result<Option<&N>, &Error>) {
+        self.borrow_mut().statement_end(statement, value)
+    }
+
+    fn function_entry(&mut self, function: &Name, args: &[Value<N>]) {
+        self.borrow_mut().function_entry(function, args)
+    }
+
+    fn function_return(&mut self, function: &Name, result: Result<&Value<N>, &Error>) {
+        self.borrow_mut().function_return(function, result)
+    }
+
+    fn operation(&mut self, operator: Operator, lhs: &N, rhs: &N, result: Result<&N, &Error>) {
+        self.borrow_mut().operation(operator, lhs, rhs, result)
+    }
+}
+
+/// Writes every event to `output` as it happens, one per line and indented by
+/// how deep in function calls it is:
+///
+/// ```text
+/// b = f(2) + 1;
+///   f(2)
+///     2 * 3 -> 6
+///   f -> 6
+///   6 + 1 -> 7
+/// b -> 7
+/// ```
+///
+/// Failures to write are ignored, so that tracing cannot change the result.
+pub struct Tracer<W> {
+    output: W,
+    depth: usize,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W) -> Tracer<W> {
+        Tracer { output, depth: 0 }
+    }
+
+    fn line(&mut self, line: fmt::Arguments) {
+        let _ = writeln!(self.output, "{:indent$}{}", "", line, indent = 2 * self.depth);
+    }
+}
+
+impl<N: Number, W: Write> Observer<N> for Tracer<W> {
+    fn statement_start(&mut self, statement: &Statement) {
+        self.line(format_args!("{}", statement));
+        self.depth += 1;
+    }
+
+    fn statement_end(&mut self, statement: &Name, value: Result<Option<&N>, &Error>) {
+        self.depth -= 1;
+        match value {
+            Ok(Some(value)) => self.line(format_args!("{} -> {}", statement, value)),
+            Ok(None) => {}
+            Err(e) => self.line(format_args!("{} -> error: {}", statement, e)),
+        }
+    }
+
+    fn function_entry(&mut self, function: &Name, args: &[Value<N>]) {
+        let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+        self.line(format_args!("{}({})", function, args.join(", ")));
+        self.depth += 1;
+    }
+
+    fn function_return(&mut self, function: &Name, result: Result<&Value<N>, &Error>) {
+        self.depth -= 1;
+        match result {
+            Ok(value) => self.line(format_args!("{} -> {}", function, value)),
+            Err(e) => self.line(format_args!("{} -> error: {}", function, e)),
+        }
+    }
+
+    fn operation(&mut self, operator: Operator, lhs: &N, rhs: &N, result: Result<&N, &Error>) {
+        match result {
+            Ok(value) => self.line(format_args!("{} {} {} -> {}", lhs, operator, rhs, value)),
+            Err(e) => self.line(format_args!("{} {} {} -> error: {}", lhs, operator, rhs, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(s: &[u8]) -> String {
+        let tracer = Rc::new(RefCell::new(Tracer::new(vec![])));
+        let mut executor: Executor = Executor::new();
+        executor.observer = Some(Box::new(tracer.clone()));
+        let _ = executor.run(parser::parse(s).unwrap());
+        let output = tracer.borrow().output.clone();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn tracer_test() {
+        assert_eq!(
+            trace(b"f(x) = x * 3;\nb = f(2) + abs(-1);"),
+            "f(x) = x * 3;\n\
+             b = f(2) + abs(-1);\n\
+             \x20 f(2)\n\
+             \x20   2 * 3 -> 6\n\
+             \x20 f -> 6\n\
+             \x20 abs(-1)\n\
+             \x20 abs -> 1\n\
+             \x20 6 + 1 -> 7\n\
+             b -> 7\n"
+        );
+    }
+
+    #[test]
+    fn failure_test() {
+        assert_eq!(
+            trace(b"f(x) = 1 / x;\na = f(0);\nb = 1;"),
+            "f(x) = 1 / x;\n\
+             a = f(0);\n\
+             \x20 f(0)\n\
+             \x20   1 / 0 -> error: in `a`: division by zero\n\
+             \x20 f -> error: in `a`: division by zero\n\
+             a -> error: in `a`: division by zero\n"
+        );
+    }
+}
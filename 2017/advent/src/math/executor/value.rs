@@ -0,0 +1,67 @@
+use super::*;
+
+/// What an expression evaluates to. Variables hold numbers, but functions are
+/// values too, so that they can be passed to other functions and returned
+/// from them, and so are lists of numbers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<N = i64> {
+    Number(N),
+    /// A lambda, or a function passed by name. Either way it keeps the
+    /// captures it was created with.
+    Function(Rc<Function<N>>),
+    List(Rc<Vec<N>>),
+}
+
+/// The kinds of `Value`, for reporting a value of the wrong kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Number,
+    Function,
+    List,
+}
+
+impl<N> Value<N> {
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Value::Number(_) => Kind::Number,
+            Value::Function(_) => Kind::Function,
+            Value::List(_) => Kind::List,
+        }
+    }
+
+    // The number this is, where `statement` needed one.
+    pub(super) fn number(self, statement: &Name) -> Result<N, Error> {
+        match self {
+            Value::Number(n) => Ok(n),
+            other => Err(Error::WrongType(statement.clone(), Kind::Number, other.kind())),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Kind::Number => "a number",
+            Kind::Function => "a function",
+            Kind::List => "a list",
+        })
+    }
+}
+
+// Functions are written as the lambda they would be, leaving out what they
+// captured.
+impl<N: fmt::Display> fmt::Display for Value<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Number(ref n) => write!(f, "{}", n),
+            Value::Function(ref function) => {
+                let params = function.params.iter().map(Name::as_str).collect::<Vec<_>>();
+                write!(f, "\\{} -> {}", params.join(", "), function.body)
+            }
+            Value::List(ref items) => {
+                let items = items.iter().map(|item| item.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use super::*;
+
+/// Formats a program as canonical math source, one statement per line.
+///
+/// Formatting is the inverse of parsing: `parser::parse(format(ast)) == ast`
+/// for any AST whose names are ones the parser accepts. Parentheses are only
+/// written where the AST could not be recovered without them.
+pub fn format(statements: &[Statement]) -> String {
+    statements.iter().map(|s| format!("{}\n", s)).collect()
+}
+
+/// Like `format`, but keeps the `#` comments of `source`, which `statements`
+/// were parsed from. A comment after a statement on the line it ends on stays
+/// there, and any other is put on a line of its own before the statement that
+/// follows it, so that the comments within a statement come before it.
+pub fn format_with_comments(statements: &[Statement], source: &[u8]) -> Result<String, parser::Error> {
+    let mut comments = parser::comments(source)?.into_iter().peekable();
+    let mut formatted = String::new();
+    for (i, statement) in statements.iter().enumerate() {
+        let span = statement.span();
+        let next_start = statements.get(i + 1).map_or(source.len(), |next| next.span().start);
+        while let Some((text, _)) = comments.next_if(|&(_, comment)| comment.start < span.end) {
+            formatted.push_str(&format!("#{}\n", text));
+        }
+        formatted.push_str(&statement.to_string());
+        let end_line = span.line + source[span.start..span.end].iter().filter(|&&b| b == b'\n').count();
+        if let Some((text, _)) = comments.next_if(|&(_, comment)| comment.line == end_line && comment.start < next_start) {
+            formatted.push_str(&format!(" #{}", text));
+        }
+        formatted.push('\n');
+    }
+    for (text, _) in comments {
+        formatted.push_str(&format!("#{}\n", text));
+    }
+    return Ok(formatted);
+}
+
+impl Operator {
+    pub const ALL: [Operator; 10] = [
+        Operator::Add,
+        Operator::Subtract,
+        Operator::Multiply,
+        Operator::Divide,
+        Operator::Equal,
+        Operator::NotEqual,
+        Operator::Less,
+        Operator::LessEqual,
+        Operator::Greater,
+        Operator::GreaterEqual,
+    ];
+
+    /// How tightly the operator binds; higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        match self {
+            Operator::Equal
+            | Operator::NotEqual
+            | Operator::Less
+            | Operator::LessEqual
+            | Operator::Greater
+            | Operator::GreaterEqual => 1,
+            Operator::Add | Operator::Subtract => 2,
+            Operator::Multiply | Operator::Divide => 3,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Statement::VarAssignment(ref name, ref expr, _) => write!(f, "{} = {};", name, expr),
+            Statement::FnDefinition(ref name, ref params, ref expr, _) => {
+                write!(f, "{}(", name)?;
+                write_list(f, params)?;
+                write!(f, ") = {};", expr)
+            }
+            Statement::Import(ref path, _, _) => write!(f, "import \"{}\";", path),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_expression(f, self, true)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_operand(f, self, true)
+    }
+}
+
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    return Ok(());
+}
+
+// An `if` or a lambda extends as far to the right as it can, so it only goes
+// without parentheses when nothing follows it. `last` says whether that is the
+// case.
+fn write_expression(f: &mut fmt::Formatter, expr: &Expression, last: bool) -> fmt::Result {
+    match *expr {
+        Expression::Operand(ref operand, _) => write_operand(f, operand, last),
+        Expression::Operation(ref lhs, operator, ref rhs, _) => {
+            // Arithmetic is left-associative and comparisons do not chain, so
+            // an operand of equal precedence needs parentheses on the right,
+            // and on the left too for comparisons.
+            let precedence = operator.precedence();
+            let lhs_parenthesised = match **lhs {
+                Expression::Operation(_, inner, _, _) => {
+                    inner.precedence() < precedence || (inner.precedence() == 1 && precedence == 1)
+                }
+                _ => false,
+            };
+            let rhs_parenthesised = match **rhs {
+                Expression::Operation(_, inner, _, _) => inner.precedence() <= precedence,
+                _ => false,
+            };
+            write_operation_side(f, lhs, lhs_parenthesised, false)?;
+            write!(f, " {} ", operator)?;
+            write_operation_side(f, rhs, rhs_parenthesised, last)
+        }
+    }
+}
+
+fn write_operation_side(
+    f: &mut fmt::Formatter,
+    expr: &Expression,
+    parenthesised: bool,
+    last: bool,
+) -> fmt::Result {
+    if parenthesised {
+        write!(f, "({})", expr)
+    } else {
+        write_expression(f, expr, last)
+    }
+}
+
+// `{:?}` always keeps a `.` or an exponent, so the literal reads back as a
+// float rather than as an integer. It writes infinity as `inf`, though, which
+// would read back as a name, so that is written as an exponent too large for
+// any float instead, which the lexer reads as infinity. No literal is NaN.
+pub(super) fn write_float(f: &mut fmt::Formatter, value: f64) -> fmt::Result {
+    match value {
+        value if value == ::std::f64::INFINITY => f.write_str("1e999"),
+        value if value == ::std::f64::NEG_INFINITY => f.write_str("-1e999"),
+        value => write!(f, "{:?}", value),
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter, operand: &Operand, last: bool) -> fmt::Result {
+    match *operand {
+        Operand::I64(value) => write!(f, "{}", value),
+        Operand::F64(value) => write_float(f, value),
+        Operand::VarSubstitution(ref name) => write!(f, "{}", name),
+        Operand::FnApplication(ref name, ref args) => {
+            write!(f, "{}(", name)?;
+            write_list(f, args)?;
+            f.write_str(")")
+        }
+        // Only a call goes without parentheses as a callee, since an argument
+        // list after anything else would not be a call of all of it.
+        Operand::Call(ref callee, ref args) => {
+            match **callee {
+                Expression::Operand(Operand::FnApplication(..), _)
+                | Expression::Operand(Operand::Call(..), _) => write!(f, "{}(", callee)?,
+                _ => write!(f, "({})(", callee)?,
+            }
+            write_list(f, args)?;
+            f.write_str(")")
+        }
+        Operand::If(ref condition, ref consequent, ref alternative) => {
+            let (open, close) = if last { ("", "") } else { ("(", ")") };
+            write!(
+                f,
+                "{}if {} then {} else {}{}",
+                open, condition, consequent, alternative, close
+            )
+        }
+        Operand::Lambda(ref params, ref body) => {
+            let (open, close) = if last { ("", "") } else { ("(", ")") };
+            write!(f, "{}\\", open)?;
+            write_list(f, params)?;
+            write!(f, " -> {}{}", body, close)
+        }
+        Operand::List(ref items) => {
+            f.write_str("[")?;
+            write_list(f, items)?;
+            f.write_str("]")
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_spacing_test() {
+        let statements = parser::parse(b"a=1+2*  3;\n\n f( x,y )=x  <  y ;import\"lib/g.math\" ;").unwrap();
+        assert_eq!(format(&statements), "a = 1 + 2 * 3;\nf(x, y) = x < y;\nimport \"lib/g.math\";\n");
+    }
+
+    #[test]
+    fn infinity_test() {
+        let statements = parser::parse(b"a = 1e400 - -2e999;").unwrap();
+        assert_eq!(format(&statements), "a = 1e999 - -1e999;\n");
+        assert_eq!(::math::format_sexpr(&statements), "(define a (- 1e999 -1e999))\n");
+    }
+
+    #[test]
+    fn comments_test() {
+        let source = b"# constants\nrate = 2; # per hour\nf(x) =\n  # doubled\n  x * rate;   \na=1;b=2;#both\n\n# end\n";
+        let statements = parser::parse(source).unwrap();
+        let formatted = format_with_comments(&statements, source).unwrap();
+        assert_eq!(
+            formatted,
+            "# constants\nrate = 2; # per hour\n# doubled\nf(x) = x * rate;\na = 1;\nb = 2; #both\n# end\n"
+        );
+        assert_eq!(parser::parse(formatted.as_bytes()), Ok(statements));
+        assert_eq!(format_with_comments(&[], b"#a\n\n#b").unwrap(), "#a\n#b\n");
+    }
+
+    #[test]
+    fn minimal_parentheses_test() {
+        let source = "a = ((1 + 2)) * (3 - (4 - 5)) - (6 * 7);\n\
+                      b = (1 < 2) == (3 + 4 >= 5);\n\
+                      c = (if a then b else c) + 1 - (if a then 2 else 3);\n\
+                      d = 1 + (if a then b else c);\n\
+                      e = f(if a then b else c, (1 + 2) / 3, g());\n\
+                      g = map((\\x -> x * 2), [1, (2)]) + (\\ -> 1);\n\
+                      h = (f(1))(2)((3)) + (\\x -> x)((\\ -> 1)()) + (a + b)(1);\n";
+        assert_eq!(
+            format(&parser::parse(source.as_bytes()).unwrap()),
+            "a = (1 + 2) * (3 - (4 - 5)) - 6 * 7;\n\
+             b = (1 < 2) == (3 + 4 >= 5);\n\
+             c = (if a then b else c) + 1 - if a then 2 else 3;\n\
+             d = 1 + if a then b else c;\n\
+             e = f(if a then b else c, (1 + 2) / 3, g());\n\
+             g = map(\\x -> x * 2, [1, 2]) + \\ -> 1;\n\
+             h = f(1)(2)(3) + (\\x -> x)((\\ -> 1)()) + (a + b)(1);\n"
+        );
+    }
+
+    // A small deterministic xorshift generator, so that failures reproduce.
+    pub(crate) struct Rng(pub(crate) u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        fn name(&mut self) -> Name {
+            let names = ["a", "b", "x", "fib", "rate_2", "iffy", "thenx"];
+            Name(names[self.below(names.len() as u64) as usize].to_string())
+        }
+
+        fn expression(&mut self, depth: u32) -> Expression {
+            let operators = [
+                Operator::Add,
+                Operator::Subtract,
+                Operator::Multiply,
+                Operator::Divide,
+                Operator::Equal,
+                Operator::NotEqual,
+                Operator::Less,
+                Operator::LessEqual,
+                Operator::Greater,
+                Operator::GreaterEqual,
+            ];
+            if depth == 0 || self.below(3) == 0 {
+                return Expression::Operand(self.operand(depth), Span::default());
+            }
+            let lhs = self.expression(depth - 1);
+            let operator = operators[self.below(operators.len() as u64) as usize];
+            let rhs = self.expression(depth - 1);
+            Expression::Operation(Box::new(lhs), operator, Box::new(rhs), Span::default())
+        }
+
+        fn operand(&mut self, depth: u32) -> Operand {
+            match if depth == 0 { self.below(2) } else { self.below(7) } {
+                0 => Operand::I64(match self.below(4) {
+                    0 => ::std::i64::MIN,
+                    1 => ::std::i64::MAX,
+                    _ => self.below(2000) as i64 - 1000,
+                }),
+                1 if self.below(4) == 0 => Operand::F64(match self.below(5) {
+                    0 => 1e300,
+                    1 => 2.5e-10,
+                    2 if self.below(2) == 0 => ::std::f64::INFINITY,
+                    2 => ::std::f64::NEG_INFINITY,
+                    _ => (self.below(2000) as f64 - 1000.0) / 8.0,
+                }),
+                1 => Operand::VarSubstitution(self.name()),
+                2 => {
+                    let args = (0..self.below(3)).map(|_| self.expression(depth - 1)).collect();
+                    Operand::FnApplication(self.name(), args)
+                }
+                3 => Operand::If(
+                    Box::new(self.expression(depth - 1)),
+                    Box::new(self.expression(depth - 1)),
+                    Box::new(self.expression(depth - 1)),
+                ),
+                4 => {
+                    let params = (0..self.below(3)).map(|_| self.name()).collect();
+                    Operand::Lambda(params, Box::new(self.expression(depth - 1)))
+                }
+                5 => {
+                    let callee = match self.operand(depth - 1) {
+                        Operand::VarSubstitution(name) => Operand::FnApplication(name, vec![]),
+                        callee => callee,
+                    };
+                    let args = (0..self.below(3)).map(|_| self.expression(depth - 1)).collect();
+                    Operand::Call(Box::new(Expression::Operand(callee, Span::default())), args)
+                }
+                _ => Operand::List((0..self.below(3)).map(|_| self.expression(depth - 1)).collect()),
+            }
+        }
+
+        pub(crate) fn statement(&mut self) -> Statement {
+            if self.below(2) == 0 {
+                Statement::VarAssignment(self.name(), self.expression(4), Span::default())
+            } else {
+                let params = (0..self.below(3)).map(|_| self.name()).collect();
+                Statement::FnDefinition(self.name(), params, self.expression(4), Span::default())
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_property_test() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let statements: Vec<_> = (0..3).map(|_| rng.statement()).collect();
+            let formatted = format(&statements);
+            assert_eq!(
+                parser::parse(formatted.as_bytes()),
+                Ok(statements),
+                "{:?} did not round trip",
+                formatted
+            );
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use super::*;
+use super::symbolic;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Reads programs, replacing each `import "path";` with the statements of the
+/// file it names, so that the result can be run, checked or compiled like any
+/// other program.
+///
+/// An imported file's definitions are put in the namespace named after it, so
+/// that `import "lib/geometry.math";` defines `geometry.area` for a function
+/// `area` that `geometry.math` defines. Its statements are qualified to match,
+/// and read just what they did in the file, apart from builtins, which are
+/// shared. The files that an imported file imports are nested in its
+/// namespace in turn, as in `shapes.geometry.area`.
+///
+/// An import is looked for beside the file that imports it, and then in each
+/// directory of `search_path` in order. Statements from imported files have
+/// `Span::default()`, as their spans would be positions in other files.
+#[derive(Debug, Clone, Default)]
+pub struct Loader {
+    pub search_path: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// The file the error is in, or `None` for source given to `load_source`.
+    pub file: Option<PathBuf>,
+    /// What that file holds, which the spans of `kind` are positions in.
+    pub source: Vec<u8>,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The file could not be read, for the reason given.
+    ReadError(String),
+    ParseError(parser::Error),
+    /// No file could be found for the path that the import at the span gives.
+    NotFound(String, Span),
+    /// The import at the span imports a file that is still being imported,
+    /// through the files listed, which start and end with that file.
+    Cycle(Vec<PathBuf>, Span),
+}
+
+impl Loader {
+    pub fn new() -> Loader {
+        Loader::default()
+    }
+
+    /// Reads the program at `path` and everything it imports.
+    pub fn load(&self, path: &Path) -> Result<Vec<Statement>, Error> {
+        let mut importing = vec![];
+        self.load_file(path, &mut importing)
+    }
+
+    /// Like `load`, but for a program that was not read from a file. Its
+    /// imports are looked for in `directory` before `search_path`.
+    pub fn load_source(&self, source: &[u8], directory: &Path) -> Result<Vec<Statement>, Error> {
+        self.resolve(None, directory, source, &mut vec![])
+    }
+
+    /// Like `load_source`, for a statement already parsed from `source`, such
+    /// as one read from a stream. It gives just the statement, unless it is an
+    /// import.
+    pub fn load_statement(&self, statement: Statement, source: &[u8], directory: &Path) -> Result<Vec<Statement>, Error> {
+        self.resolve_statements(None, directory, source, vec![statement], &mut vec![])
+    }
+
+    /// The files that the program at `path` imports, directly or through the
+    /// files it imports, each once and in the order they are first imported,
+    /// for looking at each of them on its own. Fails as `load` does.
+    pub fn imports(&self, path: &Path) -> Result<Vec<PathBuf>, Error> {
+        self.load(path)?;
+        let mut imports = vec![];
+        let mut seen = HashSet::new();
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(file) = pending.pop() {
+            let source = read(&file)?;
+            let statements = parse(Some(&file), &source)?;
+            let directory = file.parent().unwrap_or_else(|| Path::new(""));
+            // Loading found every file, so finding them again cannot fail.
+            let found: Vec<PathBuf> = statements
+                .iter()
+                .filter_map(|statement| match *statement {
+                    Statement::Import(ref path, ..) => self.find(directory, path),
+                    _ => None,
+                })
+                .filter(|found| seen.insert(found.canonicalize().unwrap_or_else(|_| found.clone())))
+                .collect();
+            imports.extend(found.iter().cloned());
+            pending.extend(found.into_iter().rev());
+        }
+        return Ok(imports);
+    }
+
+    // `importing` holds the files being imported, outermost first, both as
+    // canonical paths, to tell when one imports itself, and as found, to
+    // report it.
+    fn load_file(&self, path: &Path, importing: &mut Vec<(PathBuf, PathBuf)>) -> Result<Vec<Statement>, Error> {
+        let source = read(path)?;
+        let canonical = path.canonicalize().map_err(|e| read_error(path, e))?;
+        importing.push((canonical, path.to_path_buf()));
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let statements = self.resolve(Some(path), directory, &source, importing);
+        importing.pop();
+        return statements;
+    }
+
+    fn resolve(
+        &self,
+        file: Option<&Path>,
+        directory: &Path,
+        source: &[u8],
+        importing: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Result<Vec<Statement>, Error> {
+        let statements = parse(file, source)?;
+        self.resolve_statements(file, directory, source, statements, importing)
+    }
+
+    // Replaces each import among `statements`, which were parsed from
+    // `source`, with what it imports.
+    fn resolve_statements(
+        &self,
+        file: Option<&Path>,
+        directory: &Path,
+        source: &[u8],
+        statements: Vec<Statement>,
+        importing: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Result<Vec<Statement>, Error> {
+        let error = |kind| Error {
+            file: file.map(Path::to_path_buf),
+            source: source.to_vec(),
+            kind,
+        };
+        let mut resolved = Vec::with_capacity(statements.len());
+        for statement in statements {
+            let (path, namespace, span) = match statement {
+                Statement::Import(path, namespace, span) => (path, namespace, span),
+                statement => {
+                    resolved.push(statement);
+                    continue;
+                }
+            };
+            let found = match self.find(directory, &path) {
+                Some(found) => found,
+                None => return Err(error(ErrorKind::NotFound(path, span))),
+            };
+            let canonical = found.canonicalize().unwrap_or_else(|_| found.clone());
+            if let Some(i) = importing.iter().position(|&(ref importing, _)| *importing == canonical) {
+                let mut cycle: Vec<PathBuf> = importing[i..].iter().map(|&(_, ref path)| path.clone()).collect();
+                cycle.push(found);
+                return Err(error(ErrorKind::Cycle(cycle, span)));
+            }
+            let statements = self.load_file(&found, importing)?;
+            resolved.extend(qualify(statements, &namespace));
+        }
+        return Ok(resolved);
+    }
+
+    fn find(&self, directory: &Path, path: &str) -> Option<PathBuf> {
+        ::std::iter::once(directory)
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(path))
+            .find(|candidate| candidate.is_file())
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut source = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut source))
+        .map_err(|e| read_error(path, e))?;
+    return Ok(source);
+}
+
+fn read_error(path: &Path, e: ::std::io::Error) -> Error {
+    Error {
+        file: Some(path.to_path_buf()),
+        source: vec![],
+        kind: ErrorKind::ReadError(e.to_string()),
+    }
+}
+
+fn parse(file: Option<&Path>, source: &[u8]) -> Result<Vec<Statement>, Error> {
+    parser::parse(source).map_err(|e| Error {
+        file: file.map(Path::to_path_buf),
+        source: source.to_vec(),
+        kind: ErrorKind::ParseError(e),
+    })
+}
+
+impl Error {
+    pub fn message(&self) -> String {
+        match self.kind {
+            ErrorKind::ReadError(ref e) => format!("could not read {}: {}", self.path(), e),
+            ErrorKind::ParseError(ref e) => e.kind.message().to_string(),
+            ErrorKind::NotFound(ref path, _) => format!("cannot find `{}` to import", path),
+            ErrorKind::Cycle(ref cycle, _) => {
+                let cycle: Vec<_> = cycle.iter().map(|path| path.display().to_string()).collect();
+                format!("import cycle: {}", cycle.join(" -> "))
+            }
+        }
+    }
+
+    /// Formats the error for display to a user, naming the file it is in and
+    /// quoting the offending part of it.
+    pub fn render(&self) -> String {
+        let heading = format!("error: {}", self.message());
+        let span = match self.kind {
+            ErrorKind::ReadError(_) => return format!("{}\n", heading),
+            ErrorKind::ParseError(ref e) => e.span,
+            ErrorKind::NotFound(_, span) | ErrorKind::Cycle(_, span) => span,
+        };
+        match self.file {
+            Some(_) => span.render_in(&self.source, &self.path(), &heading),
+            None => span.render(&self.source, &heading),
+        }
+    }
+
+    fn path(&self) -> String {
+        match self.file {
+            Some(ref file) => file.display().to_string(),
+            None => "<input>".to_string(),
+        }
+    }
+}
+
+// Puts what an imported file defines in `namespace`, along with every use of
+// it in the file.
+fn qualify(statements: Vec<Statement>, namespace: &Name) -> Vec<Statement> {
+    let qualifier = Qualifier {
+        namespace,
+        defined: statements.iter().map(|statement| statement.name().clone()).collect(),
+    };
+    statements
+        .into_iter()
+        .map(|statement| qualifier.statement(statement))
+        .collect()
+}
+
+struct Qualifier<'a> {
+    namespace: &'a Name,
+    defined: HashSet<Name>,
+}
+
+impl<'a> Qualifier<'a> {
+    fn statement(&self, statement: Statement) -> Statement {
+        let span = Span::default();
+        match statement {
+            Statement::VarAssignment(name, expr, _) => {
+                Statement::VarAssignment(self.name(name, &[]), self.expression(expr, &[]), span)
+            }
+            Statement::FnDefinition(name, params, expr, _) => {
+                let expr = self.expression(expr, &params);
+                Statement::FnDefinition(self.name(name, &[]), params, expr, span)
+            }
+            Statement::Import(path, namespace, _) => Statement::Import(path, namespace, span),
+        }
+    }
+
+    // `bound` holds the params in scope, which are never qualified.
+    fn expression(&self, expr: Expression, bound: &[Name]) -> Expression {
+        let span = Span::default();
+        match expr {
+            Expression::Operand(operand, _) => Expression::Operand(self.operand(operand, bound), span),
+            Expression::Operation(lhs, operator, rhs, _) => {
+                let lhs = self.expression(*lhs, bound);
+                let rhs = self.expression(*rhs, bound);
+                Expression::Operation(Box::new(lhs), operator, Box::new(rhs), span)
+            }
+        }
+    }
+
+    fn operand(&self, operand: Operand, bound: &[Name]) -> Operand {
+        let expression = |expr| self.expression(expr, bound);
+        match operand {
+            Operand::VarSubstitution(name) => Operand::VarSubstitution(self.name(name, bound)),
+            // The second arg of `d(f, x)` names a param of `f`, not something
+            // the file defines.
+            Operand::FnApplication(name, args) => {
+                let name = self.name(name, bound);
+                let derivative = symbolic::derivative_call(&name, &args).is_some();
+                let args = args
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, arg)| if derivative && i == 1 { arg.with_span(Span::default()) } else { expression(arg) })
+                    .collect();
+                Operand::FnApplication(name, args)
+            }
+            Operand::If(condition, consequent, alternative) => Operand::If(
+                Box::new(expression(*condition)),
+                Box::new(expression(*consequent)),
+                Box::new(expression(*alternative)),
+            ),
+            Operand::Lambda(params, body) => {
+                let body = {
+                    let bound: Vec<Name> = bound.iter().chain(&params).cloned().collect();
+                    self.expression(*body, &bound)
+                };
+                Operand::Lambda(params, Box::new(body))
+            }
+            Operand::List(items) => Operand::List(items.into_iter().map(expression).collect()),
+            Operand::Call(callee, args) => {
+                Operand::Call(Box::new(expression(*callee)), args.into_iter().map(expression).collect())
+            }
+            operand => operand,
+        }
+    }
+
+    fn name(&self, name: Name, bound: &[Name]) -> Name {
+        if bound.contains(&name) || !self.defined.contains(&name) {
+            return name;
+        }
+        Name(format!("{}.{}", self.namespace, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::executor::Executor;
+    use math::name;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // Writes `files` to a new directory, returning it.
+    fn write(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = env::temp_dir().join(format!("math-import-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        for &(path, source) in files {
+            let path = directory.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, source).unwrap();
+        }
+        return directory;
+    }
+
+    fn run(statements: Vec<Statement>) -> HashMap<Name, i64> {
+        let mut executor = Executor::new();
+        executor.run(statements).unwrap();
+        return executor.variables.into_iter().collect();
+    }
+
+    #[test]
+    fn namespace_test() {
+        let directory = write(
+            "namespace",
+            &[
+                ("main.math", "import \"lib/geometry.math\";\npi = 4;\na = geometry.area(2);\nb = geometry.twice(pi);"),
+                (
+                    "lib/geometry.math",
+                    "pi = 3;\nsquare(x) = x * x;\narea(r) = pi * square(r);\nx = 5;\n\
+                     twice(pi) = fold(\\sum, x -> sum + x, 0, [pi, pi]);\nslope = d(square, x, pi);",
+                ),
+            ],
+        );
+        let statements = Loader::new().load(&directory.join("main.math")).unwrap();
+        assert_eq!(
+            format(&statements),
+            "geometry.pi = 3;\n\
+             geometry.square(x) = x * x;\n\
+             geometry.area(r) = geometry.pi * geometry.square(r);\n\
+             geometry.x = 5;\n\
+             geometry.twice(pi) = fold(\\sum, x -> sum + x, 0, [pi, pi]);\n\
+             geometry.slope = d(geometry.square, x, geometry.pi);\n\
+             pi = 4;\n\
+             a = geometry.area(2);\n\
+             b = geometry.twice(pi);\n"
+        );
+        let variables = run(statements);
+        let value = |s: &str| variables[&name(s)];
+        assert_eq!((value("a"), value("b"), value("geometry.slope"), value("pi")), (12, 8, 6, 4));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn nested_test() {
+        let directory = write(
+            "nested",
+            &[
+                ("main.math", "import \"lib/shapes.math\";\na = shapes.circle(1);\nb = shapes.geometry.pi;"),
+                ("lib/shapes.math", "import \"geometry.math\";\ncircle(r) = geometry.pi * r * r;"),
+                ("lib/geometry.math", "pi = 3;"),
+            ],
+        );
+        let variables = run(Loader::new().load(&directory.join("main.math")).unwrap());
+        assert_eq!((variables[&name("a")], variables[&name("b")]), (3, 3));
+        assert_eq!(
+            Loader::new().imports(&directory.join("main.math")),
+            Ok(vec![directory.join("lib/shapes.math"), directory.join("lib/geometry.math")])
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn search_path_test() {
+        let directory = write(
+            "search-path",
+            &[("main.math", "import \"geometry.math\";\na = geometry.pi;"), ("lib/geometry.math", "pi = 3;")],
+        );
+        let main = directory.join("main.math");
+        let e = Loader::new().load(&main).unwrap_err();
+        assert_eq!(
+            (e.file.as_ref(), e.kind),
+            (Some(&main), ErrorKind::NotFound("geometry.math".to_string(), Span::default()))
+        );
+        let loader = Loader {
+            search_path: vec![directory.join("missing"), directory.join("lib")],
+        };
+        assert_eq!(run(loader.load(&main).unwrap())[&name("a")], 3);
+        let source = b"import \"geometry.math\";\nb = geometry.pi + 1;";
+        assert_eq!(run(loader.load_source(source, &directory).unwrap())[&name("b")], 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn cycle_test() {
+        let directory = write(
+            "cycle",
+            &[("a.math", "import \"b.math\";"), ("b.math", "x = 1;\nimport \"a.math\";")],
+        );
+        let e = Loader::new().load(&directory.join("a.math")).unwrap_err();
+        assert_eq!(e.file, Some(directory.join("b.math")));
+        let cycle = vec![directory.join("a.math"), directory.join("b.math"), directory.join("a.math")];
+        assert_eq!(e.kind, ErrorKind::Cycle(cycle, Span::default()));
+        assert!(e.message().ends_with("a.math"), "{}", e.message());
+        assert!(e.render().contains("b.math:2:1\n"), "{}", e.render());
+        fs::remove_dir_all(&directory).unwrap();
+
+        let directory = write("self-cycle", &[("a.math", "import \"a.math\";")]);
+        let e = Loader::new().load(&directory.join("a.math")).unwrap_err();
+        let cycle = vec![directory.join("a.math"), directory.join("a.math")];
+        assert_eq!(e.kind, ErrorKind::Cycle(cycle, Span::default()));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn errors_name_their_file_test() {
+        let directory = write(
+            "errors",
+            &[("main.math", "import \"geometry.math\";"), ("geometry.math", "pi = 3;\narea(r) = pi *;")],
+        );
+        let e = Loader::new().load(&directory.join("main.math")).unwrap_err();
+        let path = directory.join("geometry.math");
+        assert_eq!(e.file.as_ref(), Some(&path));
+        assert_eq!(
+            e.render(),
+            format!(
+                "error: expected an expression\n --> {}:2:15\n  |\n2 | area(r) = pi *;\n  |               ^\n",
+                path.display()
+            )
+        );
+
+        let e = Loader::new().load(&directory.join("missing.math")).unwrap_err();
+        assert_eq!(e.file, Some(directory.join("missing.math")));
+        assert!(e.render().starts_with("error: could not read"), "{}", e.render());
+
+        let e = Loader::new().load_source(b"import \"shapes.math\";", &directory).unwrap_err();
+        assert_eq!(e.render(), "error: cannot find `shapes.math` to import\n --> 1:1\n  |\n1 | import \"shapes.math\";\n  | ^^^^^^^^^^^^^^^^^^^^^\n");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
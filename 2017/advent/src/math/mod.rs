@@ -1,31 +1,156 @@
 pub mod parser;
 pub mod executor;
+pub mod vm;
+pub mod number;
+mod check;
+mod compiled;
+mod format;
+pub mod import;
+mod optimizer;
+#[cfg(feature = "serde")]
+pub mod schema;
+mod sexpr;
+mod span;
+mod stream;
+pub mod symbolic;
+#[cfg(test)]
+mod programs;
+
+pub use self::check::{check, reorder, Diagnostic, DiagnosticKind};
+pub use self::compiled::{compile, CompiledProgram};
+pub use self::format::{format, format_with_comments};
+pub use self::number::Number;
+pub use self::optimizer::optimize;
+pub use self::sexpr::format_sexpr;
+pub use self::span::Span;
+pub use self::stream::{interpret_stream, statements, Assignments, Statements};
 
 use std::collections::HashMap;
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     ParseError(parser::Error),
     ExecutionError(executor::Error),
+    /// Reading a streamed program failed, for the reason given.
+    ReadError(String),
+    /// A streamed import could not be loaded.
+    ImportError(import::Error),
 }
 
 pub fn interpret(s: &[u8]) -> Result<HashMap<Name, i64>, Error> {
+    interpret_as(s)
+}
+
+/// Like `interpret`, but fails rather than going beyond `limits`, for programs
+/// that cannot be trusted to finish. Parsing is bounded regardless of the
+/// limits, as expressions nested too deeply to walk safely fail to parse.
+pub fn interpret_with_limits(s: &[u8], limits: executor::Limits) -> Result<HashMap<Name, i64>, Error> {
+    let statements = parser::parse(s).map_err(Error::ParseError)?;
+    let mut executor = executor::Executor::with_limits(limits);
+    executor.run(statements).map_err(Error::ExecutionError)?;
+    return Ok(executor.variables.into_iter().collect());
+}
+
+/// Like `interpret`, but computes with `N` rather than with checked `i64`s.
+/// `interpret_as::<BigRational>` gives exact answers to programs that divide.
+pub fn interpret_as<N: Number>(s: &[u8]) -> Result<HashMap<Name, N>, Error> {
     let statements = parser::parse(s).map_err(Error::ParseError)?;
     let results = executor::execute(statements).map_err(Error::ExecutionError)?;
     return Ok(results);
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+impl Error {
+    /// Formats the error for display to a user, quoting the offending part of
+    /// `source` where the error knows its position.
+    pub fn render(&self, source: &[u8]) -> String {
+        match *self {
+            Error::ParseError(ref e) => e.render(source),
+            Error::ExecutionError(ref e) => format!("error: {}\n", e),
+            Error::ReadError(ref e) => format!("error: {}\n", e),
+            // It holds the source it quotes.
+            Error::ImportError(ref e) => e.render(),
+        }
+    }
+}
+
+/// The name of a variable, function or param. A name qualified by the
+/// namespace of an import, such as `geometry.area`, names something that the
+/// imported file defined.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Name(String);
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl Name {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Spares tests writing out `Name(name.to_string())`.
+#[cfg(test)]
+pub(crate) fn name(name: &str) -> Name {
+    Name(name.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
-    VarAssignment(Name, Expression),
-    FnDefinition(Name, Vec<Name>, Expression),
+    VarAssignment(Name, Expression, Span),
+    FnDefinition(Name, Vec<Name>, Expression, Span),
+    /// `import "lib/geometry.math";`, with the path as written and the
+    /// namespace it is imported into, which is named after the file. An
+    /// `import::Loader` replaces it with the file's statements before anything
+    /// runs it.
+    Import(String, Name, Span),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Expression(Operand, Vec<(Operator, Operand)>);
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Operand(Operand, Span),
+    Operation(Box<Expression>, Operator, Box<Expression>, Span),
+}
+
+impl Statement {
+    /// The variable, function or namespace the statement defines.
+    pub fn name(&self) -> &Name {
+        match *self {
+            Statement::VarAssignment(ref name, _, _) => name,
+            Statement::FnDefinition(ref name, _, _, _) => name,
+            Statement::Import(_, ref namespace, _) => namespace,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match *self {
+            Statement::VarAssignment(_, _, span) => span,
+            Statement::FnDefinition(_, _, _, span) => span,
+            Statement::Import(_, _, span) => span,
+        }
+    }
+}
+
+impl Expression {
+    pub fn span(&self) -> Span {
+        match *self {
+            Expression::Operand(_, span) => span,
+            Expression::Operation(_, _, _, span) => span,
+        }
+    }
+
+    fn with_span(self, span: Span) -> Expression {
+        match self {
+            Expression::Operand(operand, _) => Expression::Operand(operand, span),
+            Expression::Operation(lhs, operator, rhs, _) => {
+                Expression::Operation(lhs, operator, rhs, span)
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operator {
@@ -33,11 +158,33 @@ pub enum Operator {
     Subtract,
     Multiply,
     Divide,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operand {
     I64(i64),
+    /// A literal written with a fractional part or an exponent, such as `2.5`
+    /// or `1e-3`. Number types that cannot represent it exactly fail to
+    /// evaluate it.
+    F64(f64),
     VarSubstitution(Name),
     FnApplication(Name, Vec<Expression>),
+    /// `callee(args...)` where the callee is not just a name, as in `f(1)(2)`
+    /// or `(\x -> x + 1)(4)`. It calls whatever function the callee gives.
+    Call(Box<Expression>, Vec<Expression>),
+    /// `if condition then consequent else alternative`. Conditions are true
+    /// when they evaluate to anything other than 0, just as comparisons
+    /// evaluate to 1 when they hold and to 0 when they do not.
+    If(Box<Expression>, Box<Expression>, Box<Expression>),
+    /// `\x, y -> body`, an anonymous function. It captures what it uses from
+    /// where it is evaluated, just as a definition captures globals.
+    Lambda(Vec<Name>, Box<Expression>),
+    /// `[a, b, c]`, a list of numbers.
+    List(Vec<Expression>),
 }
@@ -0,0 +1,382 @@
+use std::fmt;
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, FromPrimitive, Signed, ToPrimitive,
+                 Zero};
+
+pub use num_bigint::BigInt;
+pub use num_rational::BigRational;
+
+/// How many bits a `BigInt`, or the numerator or the denominator of a
+/// `BigRational`, may have. A result any larger is not representable, so that
+/// no single operation, however few steps it counts as, can take unbounded
+/// time or memory.
+pub const MAX_BITS: u64 = 1 << 20;
+
+/// The values a program computes with. The executor is generic over this, so
+/// the same program can be run with checked `i64`s, with integers that never
+/// overflow (`BigInt`), with exact fractions (`BigRational`), or with `f64`s.
+///
+/// Comparisons evaluate to `from_i64(1)` or `from_i64(0)`, and any non-zero
+/// value counts as true.
+pub trait Number: 'static + Clone + fmt::Debug + fmt::Display + PartialOrd {
+    fn from_i64(value: i64) -> Self;
+    /// The value of a float literal, or `None` if this type cannot represent
+    /// it exactly. Integer types only accept whole numbers.
+    fn from_f64(value: f64) -> Option<Self>;
+    fn is_zero(&self) -> bool;
+    /// `None` if the result is not representable.
+    fn checked_add(&self, rhs: &Self) -> Option<Self>;
+    fn checked_sub(&self, rhs: &Self) -> Option<Self>;
+    fn checked_mul(&self, rhs: &Self) -> Option<Self>;
+    /// Never called with a zero `rhs`: division by zero is reported before the
+    /// operands reach the number type. The same goes for `checked_rem`.
+    fn checked_div(&self, rhs: &Self) -> Option<Self>;
+    /// The remainder of truncating division, which has the sign of `self`.
+    fn checked_rem(&self, rhs: &Self) -> Option<Self>;
+    fn checked_pow(&self, exponent: &Self) -> Option<Self>;
+    /// The square root, rounded down for integer types.
+    fn sqrt(&self) -> Option<Self>;
+    fn floor(&self) -> Self;
+}
+
+impl Number for i64 {
+    fn from_i64(value: i64) -> i64 {
+        value
+    }
+
+    fn from_f64(value: f64) -> Option<i64> {
+        if value.fract() == 0.0 {
+            value.to_i64()
+        } else {
+            None
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == 0
+    }
+
+    fn checked_add(&self, rhs: &i64) -> Option<i64> {
+        i64::checked_add(*self, *rhs)
+    }
+
+    fn checked_sub(&self, rhs: &i64) -> Option<i64> {
+        i64::checked_sub(*self, *rhs)
+    }
+
+    fn checked_mul(&self, rhs: &i64) -> Option<i64> {
+        i64::checked_mul(*self, *rhs)
+    }
+
+    fn checked_div(&self, rhs: &i64) -> Option<i64> {
+        i64::checked_div(*self, *rhs)
+    }
+
+    fn checked_rem(&self, rhs: &i64) -> Option<i64> {
+        i64::checked_rem(*self, *rhs)
+    }
+
+    fn checked_pow(&self, exponent: &i64) -> Option<i64> {
+        exponent.to_u32().and_then(|exponent| i64::checked_pow(*self, exponent))
+    }
+
+    fn sqrt(&self) -> Option<i64> {
+        if *self < 0 {
+            return None;
+        }
+        // The float estimate can be off by one either way for large values.
+        let mut root = (*self as f64).sqrt() as i64;
+        while root.checked_mul(root).map_or(true, |square| square > *self) {
+            root -= 1;
+        }
+        while (root + 1).checked_mul(root + 1).map_or(false, |square| square <= *self) {
+            root += 1;
+        }
+        Some(root)
+    }
+
+    fn floor(&self) -> i64 {
+        *self
+    }
+}
+
+// Division truncates towards zero, as it does for `i64`.
+impl Number for BigInt {
+    fn from_i64(value: i64) -> BigInt {
+        BigInt::from(value)
+    }
+
+    fn from_f64(value: f64) -> Option<BigInt> {
+        if value.fract() == 0.0 {
+            FromPrimitive::from_f64(value)
+        } else {
+            None
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        Zero::is_zero(self)
+    }
+
+    fn checked_add(&self, rhs: &BigInt) -> Option<BigInt> {
+        CheckedAdd::checked_add(self, rhs).and_then(within_max_bits)
+    }
+
+    fn checked_sub(&self, rhs: &BigInt) -> Option<BigInt> {
+        CheckedSub::checked_sub(self, rhs).and_then(within_max_bits)
+    }
+
+    fn checked_mul(&self, rhs: &BigInt) -> Option<BigInt> {
+        if self.bits() + rhs.bits() > MAX_BITS + 1 {
+            return None;
+        }
+        CheckedMul::checked_mul(self, rhs).and_then(within_max_bits)
+    }
+
+    fn checked_div(&self, rhs: &BigInt) -> Option<BigInt> {
+        CheckedDiv::checked_div(self, rhs)
+    }
+
+    fn checked_rem(&self, rhs: &BigInt) -> Option<BigInt> {
+        Some(self % rhs)
+    }
+
+    fn checked_pow(&self, exponent: &BigInt) -> Option<BigInt> {
+        let exponent = exponent.to_u32()?;
+        if pow_is_too_large(self.bits(), exponent) {
+            return None;
+        }
+        within_max_bits(self.pow(exponent))
+    }
+
+    fn sqrt(&self) -> Option<BigInt> {
+        if self.is_negative() {
+            None
+        } else {
+            Some(BigInt::sqrt(self))
+        }
+    }
+
+    fn floor(&self) -> BigInt {
+        self.clone()
+    }
+}
+
+// Division is exact, so `7 / 2` is `7/2` rather than `3`.
+impl Number for BigRational {
+    fn from_i64(value: i64) -> BigRational {
+        BigRational::from_integer(BigInt::from(value))
+    }
+
+    // Literals are read as the decimal fractions they were written as, so
+    // `0.1` is exactly `1/10` rather than the nearest binary fraction.
+    fn from_f64(value: f64) -> Option<BigRational> {
+        if !value.is_finite() {
+            return None;
+        }
+        let scientific = format!("{:e}", value);
+        let mut parts = scientific.split('e');
+        let mantissa = parts.next().unwrap();
+        let exponent: i32 = parts.next().unwrap().parse().unwrap();
+        let fraction_digits = mantissa.find('.').map_or(0, |i| mantissa.len() - i - 1) as i32;
+        let digits: BigInt = mantissa.replace('.', "").parse().unwrap();
+        let scale = BigRational::from_integer(BigInt::from(10)).pow(exponent - fraction_digits);
+        Some(BigRational::from_integer(digits) * scale)
+    }
+
+    fn is_zero(&self) -> bool {
+        Zero::is_zero(self)
+    }
+
+    // Each of these at most doubles the size of its operands, so checking the
+    // result is enough to keep sizes bounded.
+    fn checked_add(&self, rhs: &BigRational) -> Option<BigRational> {
+        CheckedAdd::checked_add(self, rhs).and_then(rational_within_max_bits)
+    }
+
+    fn checked_sub(&self, rhs: &BigRational) -> Option<BigRational> {
+        CheckedSub::checked_sub(self, rhs).and_then(rational_within_max_bits)
+    }
+
+    fn checked_mul(&self, rhs: &BigRational) -> Option<BigRational> {
+        CheckedMul::checked_mul(self, rhs).and_then(rational_within_max_bits)
+    }
+
+    fn checked_div(&self, rhs: &BigRational) -> Option<BigRational> {
+        CheckedDiv::checked_div(self, rhs).and_then(rational_within_max_bits)
+    }
+
+    fn checked_rem(&self, rhs: &BigRational) -> Option<BigRational> {
+        Some(self % rhs)
+    }
+
+    // Only whole exponents give exact results.
+    fn checked_pow(&self, exponent: &BigRational) -> Option<BigRational> {
+        if !exponent.is_integer() || (Zero::is_zero(self) && exponent.is_negative()) {
+            return None;
+        }
+        let exponent = exponent.to_integer().to_i32()?;
+        let bits = self.numer().bits().max(self.denom().bits());
+        if pow_is_too_large(bits, exponent.unsigned_abs()) {
+            return None;
+        }
+        rational_within_max_bits(self.pow(exponent))
+    }
+
+    // Only perfect squares have exact roots.
+    fn sqrt(&self) -> Option<BigRational> {
+        if self.is_negative() {
+            return None;
+        }
+        let numer = self.numer().sqrt();
+        let denom = self.denom().sqrt();
+        if &(&numer * &numer) == self.numer() && &(&denom * &denom) == self.denom() {
+            Some(BigRational::new(numer, denom))
+        } else {
+            None
+        }
+    }
+
+    fn floor(&self) -> BigRational {
+        BigRational::floor(self)
+    }
+}
+
+// Results that are infinite or not a number count as not representable, so a
+// program never goes on computing with them.
+impl Number for f64 {
+    fn from_i64(value: i64) -> f64 {
+        value as f64
+    }
+
+    fn from_f64(value: f64) -> Option<f64> {
+        finite(value)
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == 0.0
+    }
+
+    fn checked_add(&self, rhs: &f64) -> Option<f64> {
+        finite(self + rhs)
+    }
+
+    fn checked_sub(&self, rhs: &f64) -> Option<f64> {
+        finite(self - rhs)
+    }
+
+    fn checked_mul(&self, rhs: &f64) -> Option<f64> {
+        finite(self * rhs)
+    }
+
+    fn checked_div(&self, rhs: &f64) -> Option<f64> {
+        finite(self / rhs)
+    }
+
+    fn checked_rem(&self, rhs: &f64) -> Option<f64> {
+        finite(self % rhs)
+    }
+
+    fn checked_pow(&self, exponent: &f64) -> Option<f64> {
+        finite(self.powf(*exponent))
+    }
+
+    fn sqrt(&self) -> Option<f64> {
+        finite(f64::sqrt(*self))
+    }
+
+    fn floor(&self) -> f64 {
+        f64::floor(*self)
+    }
+}
+
+fn finite(value: f64) -> Option<f64> {
+    if value.is_finite() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn within_max_bits(n: BigInt) -> Option<BigInt> {
+    if n.bits() > MAX_BITS {
+        None
+    } else {
+        Some(n)
+    }
+}
+
+fn rational_within_max_bits(n: BigRational) -> Option<BigRational> {
+    if n.numer().bits() > MAX_BITS || n.denom().bits() > MAX_BITS {
+        None
+    } else {
+        Some(n)
+    }
+}
+
+// Whether raising a number of `bits` bits to `exponent` certainly gives more
+// than `MAX_BITS` bits. A result that is not rejected here has at most twice
+// that many, so computing it before checking takes bounded time.
+fn pow_is_too_large(bits: u64, exponent: u32) -> bool {
+    bits > 1 && (bits - 1).saturating_mul(u64::from(exponent)) > MAX_BITS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_f64_test() {
+        assert_eq!(<i64 as Number>::from_f64(3.0), Some(3));
+        assert_eq!(<i64 as Number>::from_f64(3.5), None);
+        assert_eq!(<i64 as Number>::from_f64(1e300), None);
+        assert_eq!(<BigInt as Number>::from_f64(1e20), Some("100000000000000000000".parse().unwrap()));
+        assert_eq!(
+            <BigRational as Number>::from_f64(0.1),
+            Some(BigRational::new(BigInt::from(1), BigInt::from(10)))
+        );
+        assert_eq!(
+            <BigRational as Number>::from_f64(-2.5e3),
+            Some(BigRational::from_integer(BigInt::from(-2500)))
+        );
+        assert_eq!(<f64 as Number>::from_f64(::std::f64::INFINITY), None);
+    }
+
+    #[test]
+    fn sqrt_test() {
+        assert_eq!(Number::sqrt(&15i64), Some(3));
+        assert_eq!(Number::sqrt(&16i64), Some(4));
+        assert_eq!(Number::sqrt(&::std::i64::MAX), Some(3037000499));
+        assert_eq!(Number::sqrt(&-1i64), None);
+        let quarter = BigRational::new(BigInt::from(1), BigInt::from(4));
+        assert_eq!(Number::sqrt(&quarter), Some(BigRational::new(BigInt::from(1), BigInt::from(2))));
+        assert_eq!(Number::sqrt(&<BigRational as Number>::from_i64(2)), None);
+        assert_eq!(Number::sqrt(&-1.0), None);
+    }
+
+    #[test]
+    fn max_bits_test() {
+        let two = <BigInt as Number>::from_i64(2);
+        let largest = Number::checked_pow(&two, &<BigInt as Number>::from_i64(MAX_BITS as i64 - 1)).unwrap();
+        assert_eq!(largest.bits(), MAX_BITS);
+        assert_eq!(Number::checked_mul(&largest, &two), None);
+        assert_eq!(Number::checked_pow(&two, &<BigInt as Number>::from_i64(MAX_BITS as i64)), None);
+        assert_eq!(Number::checked_pow(&two, &<BigInt as Number>::from_i64(4_000_000_000)), None);
+        // Doubling by adding stops where multiplying does.
+        let mut n = Number::checked_pow(&two, &<BigInt as Number>::from_i64(MAX_BITS as i64 - 4)).unwrap();
+        let mut doublings = 0;
+        while let Some(doubled) = Number::checked_add(&n, &n) {
+            n = doubled;
+            doublings += 1;
+        }
+        assert_eq!((doublings, n.bits()), (3, MAX_BITS));
+        assert_eq!(Number::checked_sub(&-n.clone(), &n), None);
+        let one = <BigInt as Number>::from_i64(1);
+        assert_eq!(Number::checked_pow(&one, &<BigInt as Number>::from_i64(4_000_000_000)), Some(one));
+
+        let third = BigRational::new(BigInt::from(1), BigInt::from(3));
+        assert_eq!(Number::checked_pow(&third, &<BigRational as Number>::from_i64(-3_000_000)), None);
+        let small = BigRational::new(BigInt::from(1), largest);
+        assert_eq!(Number::checked_mul(&small, &third), None);
+        assert_eq!(Number::checked_add(&small, &third), None);
+    }
+}
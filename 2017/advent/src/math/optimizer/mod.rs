@@ -0,0 +1,386 @@
+use super::*;
+use super::executor::evaluate_operation;
+use super::symbolic;
+use std::collections::{HashMap, HashSet};
+
+/// Simplifies a program without changing what it computes. Constant
+/// sub-expressions are folded, variables whose values are known are inlined,
+/// and identities such as `x * 1` and `x + 0` are removed.
+///
+/// Anything that would fail at runtime is left in place so that it still
+/// fails, naming the same statement. `1 / 0` is not folded, and `x * 0` only
+/// becomes `0` when evaluating `x` cannot fail. Arithmetic on a function or a
+/// list fails too, so `x * 1` only becomes `x` when `x` is sure to be a
+/// number, which a param never is, since it might be passed a function.
+///
+/// The result means the same under every `Number` type, so `7 / 2` is not
+/// folded either: it is `3` with integers but `7/2` with rationals. The one
+/// exception is `f64`, which cannot tell apart integers beyond 2^53.
+pub fn optimize(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut optimizer = Optimizer {
+        constants: HashMap::new(),
+        numbers: HashSet::new(),
+    };
+    statements
+        .into_iter()
+        .map(|statement| optimizer.statement(statement))
+        .collect()
+}
+
+struct Optimizer {
+    // Globals whose current value is known.
+    constants: HashMap<Name, i64>,
+    // Globals that have been assigned numbers, so reading them cannot fail and
+    // gives a number.
+    numbers: HashSet<Name>,
+}
+
+// Function bodies see their parameters, and whichever globals were defined
+// when the function was, as captured at that point. The names a body cannot
+// take to be globals are bound in it: its parameters, and the globals it
+// calls, since a body that reads a global it also calls calls what it
+// captured rather than the function of that name, and inlining the read would
+// change which is called.
+enum Scope<'a> {
+    Global,
+    Function(&'a [Name]),
+}
+
+impl Optimizer {
+    fn statement(&mut self, statement: Statement) -> Statement {
+        match statement {
+            Statement::VarAssignment(name, expr, span) => {
+                let expr = self.expression(expr, &Scope::Global);
+                match expr {
+                    Expression::Operand(Operand::I64(value), _) => {
+                        self.constants.insert(name.clone(), value);
+                    }
+                    _ => {
+                        self.constants.remove(&name);
+                    }
+                }
+                if self.number(&expr, &Scope::Global) {
+                    self.numbers.insert(name.clone());
+                } else {
+                    self.numbers.remove(&name);
+                }
+                Statement::VarAssignment(name, expr, span)
+            }
+            Statement::FnDefinition(name, params, expr, span) => {
+                let mut bound = params.clone();
+                called_names(&expr, &mut bound);
+                let expr = self.expression(expr, &Scope::Function(&bound));
+                Statement::FnDefinition(name, params, expr, span)
+            }
+            // An import that has not been resolved fails, so nothing after it
+            // runs and there is nothing to forget.
+            import @ Statement::Import(..) => import,
+        }
+    }
+
+    fn expression(&self, expr: Expression, scope: &Scope) -> Expression {
+        match expr {
+            Expression::Operand(operand, span) => self.operand(operand, span, scope),
+            Expression::Operation(lhs, operator, rhs, span) => {
+                let lhs = self.expression(*lhs, scope);
+                let rhs = self.expression(*rhs, scope);
+                self.operation(lhs, operator, rhs, span, scope)
+            }
+        }
+    }
+
+    fn operand(&self, operand: Operand, span: Span, scope: &Scope) -> Expression {
+        match operand {
+            Operand::VarSubstitution(name) => match self.constant(&name, scope) {
+                Some(value) => Expression::Operand(Operand::I64(value), span),
+                None => Expression::Operand(Operand::VarSubstitution(name), span),
+            },
+            // The function and param that `d(f, x)` is given are names rather
+            // than values.
+            Operand::FnApplication(name, args) => {
+                let skip = if symbolic::derivative_call(&name, &args).is_some() { 2 } else { 0 };
+                let args = args
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, arg)| if i < skip { arg } else { self.expression(arg, scope) })
+                    .collect();
+                Expression::Operand(Operand::FnApplication(name, args), span)
+            }
+            Operand::If(condition, consequent, alternative) => {
+                let condition = self.expression(*condition, scope);
+                let consequent = self.expression(*consequent, scope);
+                let alternative = self.expression(*alternative, scope);
+                match condition {
+                    Expression::Operand(Operand::I64(0), _) => alternative,
+                    Expression::Operand(Operand::I64(_), _) => consequent,
+                    condition => Expression::Operand(
+                        Operand::If(Box::new(condition), Box::new(consequent), Box::new(alternative)),
+                        span,
+                    ),
+                }
+            }
+            Operand::Lambda(params, body) => {
+                let body = {
+                    let outer = match *scope {
+                        Scope::Global => &[][..],
+                        Scope::Function(outer) => outer,
+                    };
+                    let mut bound: Vec<Name> = outer.iter().chain(&params).cloned().collect();
+                    called_names(&body, &mut bound);
+                    self.expression(*body, &Scope::Function(&bound))
+                };
+                Expression::Operand(Operand::Lambda(params, Box::new(body)), span)
+            }
+            Operand::List(items) => {
+                let items = items.into_iter().map(|item| self.expression(item, scope)).collect();
+                Expression::Operand(Operand::List(items), span)
+            }
+            Operand::Call(callee, args) => {
+                let callee = self.expression(*callee, scope);
+                let args = args.into_iter().map(|arg| self.expression(arg, scope)).collect();
+                Expression::Operand(Operand::Call(Box::new(callee), args), span)
+            }
+            operand => Expression::Operand(operand, span),
+        }
+    }
+
+    fn operation(
+        &self,
+        lhs: Expression,
+        operator: Operator,
+        rhs: Expression,
+        span: Span,
+        scope: &Scope,
+    ) -> Expression {
+        let statement = Name(String::new());
+        match (constant(&lhs), operator, constant(&rhs)) {
+            (Some(lhs), _, Some(rhs)) if !inexact_division(lhs, operator, rhs) => {
+                if let Ok(value) = evaluate_operation(&statement, operator, lhs, rhs) {
+                    return Expression::Operand(Operand::I64(value), span);
+                }
+            }
+            (_, Operator::Add, Some(0))
+            | (_, Operator::Subtract, Some(0))
+            | (_, Operator::Multiply, Some(1))
+            | (_, Operator::Divide, Some(1))
+                if self.number(&lhs, scope) =>
+            {
+                return lhs
+            }
+            (Some(0), Operator::Add, _) | (Some(1), Operator::Multiply, _) if self.number(&rhs, scope) => {
+                return rhs
+            }
+            (_, Operator::Multiply, Some(0)) if self.infallible(&lhs, scope) => {
+                return Expression::Operand(Operand::I64(0), span);
+            }
+            (Some(0), Operator::Multiply, _) if self.infallible(&rhs, scope) => {
+                return Expression::Operand(Operand::I64(0), span);
+            }
+            _ => {}
+        }
+        Expression::Operation(Box::new(lhs), operator, Box::new(rhs), span)
+    }
+
+    fn constant(&self, name: &Name, scope: &Scope) -> Option<i64> {
+        match *scope {
+            Scope::Function(params) if params.contains(name) => None,
+            _ => self.constants.get(name).cloned(),
+        }
+    }
+
+    // Whether an expression can only evaluate to a number, if it evaluates at
+    // all.
+    fn number(&self, expr: &Expression, scope: &Scope) -> bool {
+        match *expr {
+            Expression::Operand(Operand::I64(_), _) | Expression::Operand(Operand::F64(_), _) => true,
+            Expression::Operand(Operand::VarSubstitution(ref name), _) => self.global_number(name, scope),
+            Expression::Operand(Operand::If(_, ref consequent, ref alternative), _) => {
+                self.number(consequent, scope) && self.number(alternative, scope)
+            }
+            Expression::Operation(..) => true,
+            _ => false,
+        }
+    }
+
+    fn global_number(&self, name: &Name, scope: &Scope) -> bool {
+        match *scope {
+            Scope::Function(params) if params.contains(name) => false,
+            _ => self.numbers.contains(name),
+        }
+    }
+
+    // Whether evaluating an expression is certain to succeed and give a
+    // number, so that it can be dropped without losing an error. Arithmetic
+    // can overflow and calls can fail in any number of ways, but reading a
+    // variable holding a number and comparing numbers cannot.
+    fn infallible(&self, expr: &Expression, scope: &Scope) -> bool {
+        match *expr {
+            Expression::Operand(Operand::I64(_), _) => true,
+            // Integer number types cannot represent most floats.
+            Expression::Operand(Operand::F64(_), _) => false,
+            Expression::Operand(Operand::VarSubstitution(ref name), _) => self.global_number(name, scope),
+            Expression::Operand(Operand::FnApplication(..), _)
+            | Expression::Operand(Operand::Call(..), _)
+            | Expression::Operand(Operand::Lambda(..), _)
+            | Expression::Operand(Operand::List(_), _) => false,
+            Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+                self.infallible(condition, scope)
+                    && self.infallible(consequent, scope)
+                    && self.infallible(alternative, scope)
+            }
+            Expression::Operation(ref lhs, operator, ref rhs, _) => match operator {
+                Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => false,
+                _ => self.infallible(lhs, scope) && self.infallible(rhs, scope),
+            },
+        }
+    }
+}
+
+fn called_names(expr: &Expression, names: &mut Vec<Name>) {
+    match *expr {
+        Expression::Operand(Operand::FnApplication(ref name, ref args), _) => {
+            names.push(name.clone());
+            for arg in args {
+                called_names(arg, names);
+            }
+        }
+        Expression::Operand(Operand::Call(ref callee, ref args), _) => {
+            called_names(callee, names);
+            for arg in args {
+                called_names(arg, names);
+            }
+        }
+        Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+            called_names(condition, names);
+            called_names(consequent, names);
+            called_names(alternative, names);
+        }
+        Expression::Operand(Operand::Lambda(_, ref body), _) => called_names(body, names),
+        Expression::Operand(Operand::List(ref items), _) => {
+            for item in items {
+                called_names(item, names);
+            }
+        }
+        Expression::Operand(_, _) => {}
+        Expression::Operation(ref lhs, _, ref rhs, _) => {
+            called_names(lhs, names);
+            called_names(rhs, names);
+        }
+    }
+}
+
+fn constant(expr: &Expression) -> Option<i64> {
+    match *expr {
+        Expression::Operand(Operand::I64(value), _) => Some(value),
+        _ => None,
+    }
+}
+
+fn inexact_division(lhs: i64, operator: Operator, rhs: i64) -> bool {
+    operator == Operator::Divide && rhs != 0 && lhs.checked_rem(rhs).map_or(false, |r| r != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimized(s: &[u8]) -> Vec<Statement> {
+        optimize(parser::parse(s).unwrap())
+    }
+
+    #[test]
+    fn constant_folding_test() {
+        assert_eq!(
+            optimized(b"a = 1 + 2 * 3;\nb = if 2 > 1 then 4 else c;"),
+            parser::parse(b"a = 7;\nb = 4;").unwrap()
+        );
+    }
+
+    #[test]
+    fn inlining_test() {
+        assert_eq!(
+            optimized(b"a = 2;\nb = a * 3;\nf(x) = x * b + a;\na = f(1);\nc = a + 1;"),
+            parser::parse(b"a = 2;\nb = 6;\nf(x) = x * 6 + 2;\na = f(1);\nc = a + 1;").unwrap()
+        );
+    }
+
+    #[test]
+    fn params_are_not_inlined_test() {
+        assert_eq!(
+            optimized(b"x = 1;\nf(x) = x + 1;"),
+            parser::parse(b"x = 1;\nf(x) = x + 1;").unwrap()
+        );
+    }
+
+    // `f` calls the `g` it captures, which is a number, not the function `g`.
+    #[test]
+    fn called_globals_are_not_inlined_test() {
+        let source = b"g = 2;\nf(x) = g + g(x);\nh = \\x -> g * g(x);\ng(x) = x;";
+        assert_eq!(optimized(source), parser::parse(source).unwrap());
+    }
+
+    #[test]
+    fn identities_test() {
+        assert_eq!(
+            optimized(b"x = f(1) + 0;\ny = x;\na = (x * 1 + 0) / 1 - 0 + 0 * y + (x < y) * 0;"),
+            parser::parse(b"x = f(1) + 0;\ny = x;\na = x;").unwrap()
+        );
+        assert_eq!(
+            optimized(b"x = f(1) + 0;\ny = x;\na = x + y * (x < y) * 0;"),
+            parser::parse(b"x = f(1) + 0;\ny = x;\na = x + y * (x < y) * 0;").unwrap()
+        );
+    }
+
+    // Params might be passed functions, and globals assigned them, either of
+    // which would make the arithmetic fail.
+    #[test]
+    fn functions_are_not_numbers_test() {
+        let source = b"f(x, y) = (x * 1 + 0) / 1 - 0 + 0 * y;\ng = \\x -> x;\na = g + 0;\nb = f(g, 1);";
+        assert_eq!(
+            optimized(source),
+            parser::parse(b"f(x, y) = x * 1 + 0 * y;\ng = \\x -> x;\na = g + 0;\nb = f(g, 1);").unwrap()
+        );
+    }
+
+    #[test]
+    fn derivative_names_are_kept_test() {
+        assert_eq!(
+            optimized(b"f = 2;\nx = 3;\nf(x) = x * x;\na = d(f, x, x);"),
+            parser::parse(b"f = 2;\nx = 3;\nf(x) = x * x;\na = d(f, x, 3);").unwrap()
+        );
+    }
+
+    #[test]
+    fn failures_are_kept_test() {
+        let source = b"f(x, y) = (x / y) * 0;\na = 1 / 0;\nb = c * 0;\nd = 9223372036854775807 + 1;";
+        assert_eq!(optimized(source), parser::parse(source).unwrap());
+    }
+
+    #[test]
+    fn inexact_division_is_kept_test() {
+        assert_eq!(
+            optimized(b"a = 8 / 2;\nb = 7 / 2;"),
+            parser::parse(b"a = 4;\nb = 7 / 2;").unwrap()
+        );
+    }
+
+    fn assert_same_semantics<N: Number>() {
+        for program in ::math::programs::PROGRAMS {
+            let statements = parser::parse(program.as_bytes()).unwrap();
+            assert_eq!(
+                executor::execute::<N>(optimize(statements.clone())),
+                executor::execute::<N>(statements),
+                "optimizing changed the result of {:?}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn semantics_test() {
+        assert_same_semantics::<i64>();
+        assert_same_semantics::<number::BigInt>();
+        assert_same_semantics::<number::BigRational>();
+        assert_same_semantics::<f64>();
+    }
+}
@@ -0,0 +1,204 @@
+use super::super::Span;
+use super::lexer::Lexeme;
+use nom::{self, ErrorKind as NomErrorKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub span: Span,
+}
+
+// The discriminants double as the `nom::ErrorKind::Custom` codes that the
+// grammar reports through `return_error!`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    ExpectedName = 1,
+    ExpectedEquals,
+    ExpectedExpression,
+    ExpectedSemicolon,
+    ExpectedClosingParen,
+    ExpectedThen,
+    ExpectedElse,
+    UnexpectedEnd,
+    UnexpectedInput,
+    UnexpectedCharacter,
+    IntegerTooLarge,
+    ReservedKeyword,
+    ExpectedOpenParen,
+    ExpectedDefine,
+    ExpectedArrow,
+    ExpectedClosingBracket,
+    UnterminatedString,
+    ExpectedPath,
+    InvalidNamespace,
+    NestedTooDeeply,
+}
+
+impl ErrorKind {
+    const ALL: [ErrorKind; 20] = [
+        ErrorKind::ExpectedName,
+        ErrorKind::ExpectedEquals,
+        ErrorKind::ExpectedExpression,
+        ErrorKind::ExpectedSemicolon,
+        ErrorKind::ExpectedClosingParen,
+        ErrorKind::ExpectedThen,
+        ErrorKind::ExpectedElse,
+        ErrorKind::UnexpectedEnd,
+        ErrorKind::UnexpectedInput,
+        ErrorKind::UnexpectedCharacter,
+        ErrorKind::IntegerTooLarge,
+        ErrorKind::ReservedKeyword,
+        ErrorKind::ExpectedOpenParen,
+        ErrorKind::ExpectedDefine,
+        ErrorKind::ExpectedArrow,
+        ErrorKind::ExpectedClosingBracket,
+        ErrorKind::UnterminatedString,
+        ErrorKind::ExpectedPath,
+        ErrorKind::InvalidNamespace,
+        ErrorKind::NestedTooDeeply,
+    ];
+
+    pub fn code(self) -> NomErrorKind {
+        NomErrorKind::Custom(self as u32)
+    }
+
+    fn from_code(code: &NomErrorKind) -> Option<ErrorKind> {
+        match *code {
+            NomErrorKind::Custom(n) => ErrorKind::ALL.iter().cloned().find(|&k| k as u32 == n),
+            _ => None,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            ErrorKind::ExpectedName => "expected a variable or function name",
+            ErrorKind::ExpectedEquals => "expected `=` after name",
+            ErrorKind::ExpectedExpression => "expected an expression",
+            ErrorKind::ExpectedSemicolon => "expected `;` after expression",
+            ErrorKind::ExpectedClosingParen => "expected `)`",
+            ErrorKind::ExpectedThen => "expected `then` after condition",
+            ErrorKind::ExpectedElse => "expected `else` after `then` branch",
+            ErrorKind::UnexpectedEnd => "unexpected end of input",
+            ErrorKind::UnexpectedInput => "unexpected input",
+            ErrorKind::UnexpectedCharacter => "unexpected character",
+            ErrorKind::IntegerTooLarge => "integer literal does not fit in 64 bits",
+            ErrorKind::ReservedKeyword => "keywords cannot be used as names",
+            ErrorKind::ExpectedOpenParen => "expected `(`",
+            ErrorKind::ExpectedDefine => "expected `define`",
+            ErrorKind::ExpectedArrow => "expected `->` after lambda params",
+            ErrorKind::ExpectedClosingBracket => "expected `]`",
+            ErrorKind::UnterminatedString => "unterminated string",
+            ErrorKind::ExpectedPath => "expected a quoted path after `import`",
+            ErrorKind::InvalidNamespace => "an imported file must be named like a variable, as in `geometry.math`",
+            ErrorKind::NestedTooDeeply => "expression is nested too deeply",
+        }
+    }
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, span: Span) -> Error {
+        Error {
+            kind,
+            span,
+        }
+    }
+
+    // nom records errors from the innermost parser outwards, so the first
+    // error with one of our codes is the most specific thing that went wrong.
+    pub fn from_nom(source: &[u8], tokens: &[Lexeme], e: nom::Err<&[Lexeme]>) -> Error {
+        fn flatten<'a>(
+            e: nom::Err<&'a [Lexeme]>,
+            errors: &mut Vec<(NomErrorKind, Option<&'a [Lexeme]>)>,
+        ) {
+            match e {
+                nom::Err::Code(code) => errors.push((code, None)),
+                nom::Err::Node(code, next) => {
+                    for e in next {
+                        flatten(e, errors);
+                    }
+                    errors.push((code, None));
+                }
+                nom::Err::Position(code, position) => errors.push((code, Some(position))),
+                nom::Err::NodePosition(code, position, next) => {
+                    for e in next {
+                        flatten(e, errors);
+                    }
+                    errors.push((code, Some(position)));
+                }
+            }
+        }
+        let mut errors = vec![];
+        flatten(e, &mut errors);
+        let (kind, position) = errors
+            .iter()
+            .filter_map(|&(ref code, position)| match (ErrorKind::from_code(code), position) {
+                (Some(kind), Some(position)) => Some((kind, position)),
+                _ => None,
+            })
+            .next()
+            .unwrap_or_else(|| {
+                let position = errors.iter().filter_map(|e| e.1).next().unwrap_or(tokens);
+                (ErrorKind::UnexpectedInput, position)
+            });
+        let index = tokens.len() - position.len();
+        let previous = index.checked_sub(1).map(|i| tokens[i].span);
+        // Something missing from the end of a line, such as a `;`, is reported
+        // just after the last token on that line rather than at whatever
+        // happens to come next.
+        let span = match (previous, tokens.get(index)) {
+            (Some(previous), next) if next.map_or(true, |n| n.span.line != previous.line) => Span {
+                start: previous.end,
+                column: previous.column + previous.end - previous.start,
+                ..previous
+            },
+            (_, Some(next)) => next.span,
+            (_, None) => Span::new(source, source.len(), source.len()),
+        };
+        Error::new(kind, span)
+    }
+
+    /// Formats the error in the style of rustc, quoting the source line that
+    /// the error points into and underlining the offending input:
+    ///
+    /// ```text
+    /// error: expected `;` after expression
+    ///  --> 1:10
+    ///   |
+    /// 1 | a = 1 + 2
+    ///   |          ^
+    /// ```
+    pub fn render(&self, source: &[u8]) -> String {
+        self.span.render(source, &format!("error: {}", self.kind.message()))
+    }
+
+    /// Like `render`, but names the file at `path` that `source` was read
+    /// from, as in ` --> lib/geometry.math:2:10`.
+    pub fn render_in(&self, source: &[u8], path: &str) -> String {
+        self.span.render_in(source, path, &format!("error: {}", self.kind.message()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parse;
+
+    #[test]
+    fn render_test() {
+        let source = b"a = 1;\nb = 1 + 2\nc = 3;";
+        let rendered = parse(source).unwrap_err().render(source);
+        assert_eq!(
+            rendered,
+            "error: expected `;` after expression\n --> 2:10\n  |\n2 | b = 1 + 2\n  |          ^\n"
+        );
+    }
+
+    #[test]
+    fn render_unclosed_paren_test() {
+        let source = b"a = (1 + 2;";
+        let rendered = parse(source).unwrap_err().render(source);
+        assert_eq!(
+            rendered,
+            "error: expected `)`\n --> 1:11\n  |\n1 | a = (1 + 2;\n  |           ^\n"
+        );
+    }
+}
@@ -1,59 +1,536 @@
 use super::*;
-use math::*;
-use nom::is_digit;
-
-named!(pub expressions<&[u8], Vec<Expression>>,
-  separated_list!(ws!(tag!(",")), call!(expression)));
-
-named!(pub expression<&[u8], Expression>,
-  map!(
-    pair!(call!(operand), many0!(pair!(
-      ws!(call!(operator)),
-      call!(operand)))),
-    |t| Expression(t.0, t.1)));
-
-named!(operator<&[u8], Operator>,
-  map!(one_of!("+-*/"), |o| match o {
-    '+' => Operator::Add,
-    '-' => Operator::Subtract,
-    '*' => Operator::Multiply,
-    '/' => Operator::Divide,
-    _ => unreachable!()
-  }));
-
-named!(operand<&[u8], Operand>,
-  alt_complete!(
-    map!(i64, Operand::I64) |
-    map!(variable_substitution, Operand::VarSubstitution) |
-    map!(function_application, |t| Operand::FnApplication(t.0, t.1))));
-
-named!(i64<&[u8], i64>,
-  map!(
-    take_while1!(|b: u8| is_digit(b) || b == b'-'),
-    |i| to_str(i).unwrap().parse().unwrap()));
-
-named!(variable_substitution<&[u8], Name>,
+use super::lexer::{Lexeme, Token};
+use nom::IResult;
+use std::cell::Cell;
+
+/// How deeply parentheses, calls, lists, conditionals and lambdas may nest
+/// within an expression. Each level takes tens of KiB of the parser's stack in
+/// debug builds, so this keeps clear of overflowing even a 2MiB thread.
+pub const MAX_NESTING: usize = 32;
+
+/// How deep the AST of an expression may be, each operation also being one
+/// level, so that whatever walks it after parsing does not overflow the stack.
+pub const MAX_DEPTH: usize = 1000;
+
+// Going over either limit fails to parse with `ErrorKind::NestedTooDeeply`.
+
+thread_local! {
+    // How many calls of `expression` are in progress.
+    static DEPTH: Cell<usize> = Cell::new(0);
+}
+
+// Precedence climbs from `expression` (comparisons) through `sum` (`+ -`) and
+// `term` (`* /`) down to `factor`, so that tighter-binding operators end up
+// deeper in the tree. Arithmetic levels fold left to keep operators of equal
+// precedence left-associative; comparisons do not chain, so `a < b < c` is an
+// error rather than a comparison of a boolean with `c`.
+//
+// Wherever the grammar has committed to a construct, such as after an operator
+// or an opening parenthesis, the rest of it is wrapped in `return_error!` and
+// alternatives are picked with `switch!` rather than `alt!`. Both keep the
+// innermost error intact so that `Error::from_nom` can report it.
+pub fn expression(i: &[Lexeme]) -> IResult<&[Lexeme], Expression> {
+    let depth = DEPTH.with(|depth| {
+        depth.set(depth.get() + 1);
+        depth.get()
+    });
+    let result = if depth > MAX_NESTING {
+        IResult::Error(error_position!(ErrorKind::NestedTooDeeply.code(), i))
+    } else {
+        comparison(i)
+    };
+    DEPTH.with(|depth| depth.set(depth.get() - 1));
+    return result;
+}
+
+named!(comparison<&[Lexeme], Expression>,
+  do_parse!(
+    lhs: call!(sum) >>
+    comparison: call!(compared, lhs) >>
+    (comparison)));
+
+// `lhs` compared with whatever follows it, if a comparison operator does.
+fn compared(i: &[Lexeme], lhs: Expression) -> IResult<&[Lexeme], Expression> {
+  do_parse!(i,
+    rhs: switch!(opt!(call!(comparison_operator)),
+      Some(operator) => map!(
+        return_error!(ErrorKind::ExpectedExpression.code(), call!(sum)),
+        |rhs| Some((operator, rhs))) |
+      None => value!(None)) >>
+    (match rhs {
+      Some(rhs) => fold_operation(lhs, rhs),
+      None => lhs,
+    }))
+}
+
+named!(sum<&[Lexeme], Expression>,
   do_parse!(
-    name: call!(variable_name) >>
-    ws!(peek!(none_of!("("))) >>
-    (name)));
+    first: call!(term) >>
+    sum: fold_many0!(
+      pair!(
+        call!(additive_operator),
+        return_error!(ErrorKind::ExpectedExpression.code(), call!(term))),
+      first,
+      fold_operation) >>
+    (sum)));
 
-named!(function_application<&[u8], (Name, Vec<Expression>)>,
+named!(term<&[Lexeme], Expression>,
   do_parse!(
-    name: call!(function_name) >>
-    ws!(tag!("(")) >>
-    expressions: call!(expressions) >>
-    ws!(tag!(")")) >>
-    (name, expressions)));
+    first: call!(factor) >>
+    term: fold_many0!(
+      pair!(
+        call!(multiplicative_operator),
+        return_error!(ErrorKind::ExpectedExpression.code(), call!(factor))),
+      first,
+      fold_operation) >>
+    (term)));
+
+named!(factor<&[Lexeme], Expression>,
+  switch!(opt!(call!(token, Token::OpenParen)),
+    Some(open) => do_parse!(
+      callee: call!(parenthesised, open) >>
+      calls: call!(calls, callee) >>
+      (calls)) |
+    None => call!(operand)));
+
+fn parenthesised(i: &[Lexeme], open: Span) -> IResult<&[Lexeme], Expression> {
+  do_parse!(i,
+    expression: return_error!(ErrorKind::ExpectedExpression.code(), call!(expression)) >>
+    close: return_error!(ErrorKind::ExpectedClosingParen.code(), call!(token, Token::CloseParen)) >>
+    (expression.with_span(open.to(close))))
+}
+
+fn fold_operation(lhs: Expression, (operator, rhs): (Operator, Expression)) -> Expression {
+    let span = lhs.span().to(rhs.span());
+    Expression::Operation(Box::new(lhs), operator, Box::new(rhs), span)
+}
+
+// Matches an operator token if it is one of `operators`.
+fn operator<'a>(i: &'a [Lexeme], operators: &[Operator]) -> IResult<&'a [Lexeme], Operator> {
+    match i.first() {
+        Some(&Lexeme { token: Token::Operator(operator), .. }) if operators.contains(&operator) => {
+            IResult::Done(&i[1..], operator)
+        }
+        _ => IResult::Error(error_position!(nom::ErrorKind::Tag, i)),
+    }
+}
+
+named!(additive_operator<&[Lexeme], Operator>,
+  call!(operator, &[Operator::Add, Operator::Subtract]));
+
+named!(comparison_operator<&[Lexeme], Operator>,
+  call!(operator, &[
+    Operator::Equal,
+    Operator::NotEqual,
+    Operator::LessEqual,
+    Operator::GreaterEqual,
+    Operator::Less,
+    Operator::Greater,
+  ]));
+
+named!(multiplicative_operator<&[Lexeme], Operator>,
+  call!(operator, &[Operator::Multiply, Operator::Divide]));
+
+named!(operand<&[Lexeme], Expression>,
+  switch!(opt!(call!(literal)),
+    Some(literal) => value!(literal) |
+    None => switch!(opt!(call!(token, Token::If)),
+      Some(start) => call!(conditional, start) |
+      None => switch!(opt!(call!(token, Token::Backslash)),
+        Some(start) => call!(lambda, start) |
+        None => switch!(opt!(call!(token, Token::OpenBracket)),
+          Some(start) => map!(
+            call!(items, Token::CloseBracket, ErrorKind::ExpectedClosingBracket),
+            |(items, end)| Expression::Operand(Operand::List(items), start.to(end))) |
+          None => call!(named_operand))))));
+
+fn literal(i: &[Lexeme]) -> IResult<&[Lexeme], Expression> {
+    let operand = match i.first().map(|l| &l.token) {
+        Some(&Token::Integer(value)) => Operand::I64(value),
+        Some(&Token::Float(value)) => Operand::F64(value),
+        _ => return IResult::Error(error_position!(nom::ErrorKind::Tag, i)),
+    };
+    IResult::Done(&i[1..], Expression::Operand(operand, i[0].span))
+}
+
+// An `else if` chain is parsed in a loop rather than by recursing, so that
+// however long it is, it only counts once against `MAX_NESTING`. The tree is
+// the one recursing would give: each `if` after an `else` is the start of
+// that alternative, which may then be compared with what follows the chain,
+// as in `if a then 1 else if b then 2 else c < d < e`, whose first
+// alternative is `(if b then 2 else c < d) < e`.
+fn conditional(mut i: &[Lexeme], mut start: Span) -> IResult<&[Lexeme], Expression> {
+    let mut branches = vec![];
+    loop {
+        match branch(i) {
+            IResult::Done(rest, (condition, consequent)) => {
+                i = rest;
+                branches.push((start, condition, consequent));
+            }
+            IResult::Error(e) => return IResult::Error(e),
+            IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+        }
+        match token(i, Token::If) {
+            IResult::Done(rest, span) => {
+                i = rest;
+                start = span;
+            }
+            _ => break,
+        }
+    }
+    let mut alternative = match return_error!(i, ErrorKind::ExpectedExpression.code(), call!(expression)) {
+        IResult::Done(rest, alternative) => {
+            i = rest;
+            alternative
+        }
+        other => return other,
+    };
+    let (start, condition, consequent) = branches.remove(0);
+    while let Some((start, condition, consequent)) = branches.pop() {
+        alternative = match compared(i, if_operand(start, condition, consequent, alternative)) {
+            IResult::Done(rest, alternative) => {
+                i = rest;
+                alternative
+            }
+            other => return other,
+        };
+    }
+    return IResult::Done(i, if_operand(start, condition, consequent, alternative));
+}
+
+fn if_operand(start: Span, condition: Expression, consequent: Expression, alternative: Expression) -> Expression {
+    let span = start.to(alternative.span());
+    Expression::Operand(
+        Operand::If(Box::new(condition), Box::new(consequent), Box::new(alternative)),
+        span,
+    )
+}
+
+// The condition and consequent of a conditional, up to its `else`.
+fn branch(i: &[Lexeme]) -> IResult<&[Lexeme], (Expression, Expression)> {
+  do_parse!(i,
+    condition: return_error!(ErrorKind::ExpectedExpression.code(), call!(expression)) >>
+    return_error!(ErrorKind::ExpectedThen.code(), call!(token, Token::Then)) >>
+    consequent: return_error!(ErrorKind::ExpectedExpression.code(), call!(expression)) >>
+    return_error!(ErrorKind::ExpectedElse.code(), call!(token, Token::Else)) >>
+    ((condition, consequent)))
+}
+
+// The body of a lambda extends as far to the right as it can, just like the
+// alternative of a conditional.
+fn lambda(i: &[Lexeme], start: Span) -> IResult<&[Lexeme], Expression> {
+  do_parse!(i,
+    params: call!(parameter_names) >>
+    return_error!(ErrorKind::ExpectedArrow.code(), call!(token, Token::Arrow)) >>
+    body: return_error!(ErrorKind::ExpectedExpression.code(), call!(expression)) >>
+    ({
+      let span = start.to(body.span());
+      Expression::Operand(Operand::Lambda(params, Box::new(body)), span)
+    }))
+}
+
+// A name is a function application if an argument list follows it, and a
+// variable substitution otherwise.
+named!(named_operand<&[Lexeme], Expression>,
+  do_parse!(
+    start: call!(next_span) >>
+    name: call!(name) >>
+    operand: call!(calls, Expression::Operand(Operand::VarSubstitution(name), start)) >>
+    (operand)));
+
+// `callee` followed by any number of argument lists, each calling what the
+// ones before it give, as in `f(1)(2)`. Calling a bare name, even one in
+// parentheses, is a `FnApplication` of it. A loop rather than recursion, like
+// the arithmetic levels, so that a long chain cannot overflow the stack.
+fn calls(mut i: &[Lexeme], mut callee: Expression) -> IResult<&[Lexeme], Expression> {
+    while let IResult::Done(rest, _) = token(i, Token::OpenParen) {
+        let (args, end) = match arguments(rest) {
+            IResult::Done(rest, arguments) => {
+                i = rest;
+                arguments
+            }
+            IResult::Error(e) => return IResult::Error(e),
+            IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+        };
+        let span = callee.span().to(end);
+        let operand = match callee {
+            Expression::Operand(Operand::VarSubstitution(name), _) => {
+                Operand::FnApplication(name, args)
+            }
+            callee => Operand::Call(Box::new(callee), args),
+        };
+        callee = Expression::Operand(operand, span);
+    }
+    return IResult::Done(i, callee);
+}
+
+// The arguments after a `(`, and the span of the `)` that closes them.
+named!(arguments<&[Lexeme], (Vec<Expression>, Span)>,
+  call!(items, Token::CloseParen, ErrorKind::ExpectedClosingParen));
+
+// Comma-separated expressions up to `close`, and the span of `close`.
+fn items(i: &[Lexeme], close: Token, missing_close: ErrorKind) -> IResult<&[Lexeme], (Vec<Expression>, Span)> {
+  switch!(i, opt!(call!(token, close.clone())),
+    Some(end) => value!((vec![], end)) |
+    None => do_parse!(
+      first: return_error!(ErrorKind::ExpectedExpression.code(), call!(expression)) >>
+      rest: many0!(preceded!(
+        call!(token, Token::Comma),
+        return_error!(ErrorKind::ExpectedExpression.code(), call!(expression)))) >>
+      end: return_error!(missing_close.code(), call!(token, close.clone())) >>
+      ({
+        let mut items = vec![first];
+        items.extend(rest);
+        (items, end)
+      })))
+}
 
+// Fails if any part of `expr` is more than `MAX_DEPTH` deep. A long chain of
+// operations such as `1 + 1 + ...` is parsed in a loop rather than by
+// recursing, so `MAX_NESTING` does not bound it, and it is checked here
+// instead, without recursing either.
+pub fn check_depth(expr: &Expression) -> Result<(), Error> {
+    let mut pending = vec![(expr, 1)];
+    while let Some((expr, depth)) = pending.pop() {
+        if depth > MAX_DEPTH {
+            return Err(Error::new(ErrorKind::NestedTooDeeply, expr.span()));
+        }
+        match *expr {
+            Expression::Operation(ref lhs, _, ref rhs, _) => {
+                pending.push((lhs, depth + 1));
+                pending.push((rhs, depth + 1));
+            }
+            Expression::Operand(ref operand, _) => match *operand {
+                Operand::I64(_) | Operand::F64(_) | Operand::VarSubstitution(_) => {}
+                Operand::If(ref condition, ref consequent, ref alternative) => {
+                    pending.push((condition, depth + 1));
+                    pending.push((consequent, depth + 1));
+                    pending.push((alternative, depth + 1));
+                }
+                Operand::Lambda(_, ref body) => pending.push((body, depth + 1)),
+                Operand::Call(ref callee, ref args) => {
+                    pending.push((callee, depth + 1));
+                    pending.extend(args.iter().map(|arg| (arg, depth + 1)));
+                }
+                Operand::List(ref items) | Operand::FnApplication(_, ref items) => {
+                    pending.extend(items.iter().map(|item| (item, depth + 1)));
+                }
+            },
+        }
+    }
+    return Ok(());
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use nom::IResult;
 
+    fn i64(value: i64) -> Expression {
+        Expression::Operand(Operand::I64(value), Span::default())
+    }
+
+    fn f64(value: f64) -> Expression {
+        Expression::Operand(Operand::F64(value), Span::default())
+    }
+
+    fn operation(lhs: Expression, operator: Operator, rhs: Expression) -> Expression {
+        Expression::Operation(Box::new(lhs), operator, Box::new(rhs), Span::default())
+    }
+
+    // Reports what is left over as the part of `s` it was lexed from.
+    fn parse_expression(s: &[u8]) -> IResult<&[u8], Expression> {
+        let tokens = tokenize(s).unwrap();
+        match expression(&tokens) {
+            IResult::Done(rest, expression) => {
+                let start = rest.first().map_or(s.len(), |l| l.span.start);
+                IResult::Done(&s[start..], expression)
+            }
+            other => panic!("could not parse {:?}: {:?}", s, other),
+        }
+    }
+
     #[test]
     fn operator_test() {
-        assert_eq!(operator(b"+"), IResult::Done(&b""[..], Operator::Add));
+        let tokens = tokenize(b"+ / <=").unwrap();
+        assert_eq!(additive_operator(&tokens), IResult::Done(&tokens[1..], Operator::Add));
+        assert_eq!(multiplicative_operator(&tokens[1..]), IResult::Done(&tokens[2..], Operator::Divide));
+        assert_eq!(comparison_operator(&tokens[2..]), IResult::Done(&tokens[3..], Operator::LessEqual));
+    }
+
+    #[test]
+    fn precedence_test() {
+        assert_eq!(
+            parse_expression(b"1 + 3 * 6;"),
+            IResult::Done(
+                &b";"[..],
+                operation(i64(1), Operator::Add, operation(i64(3), Operator::Multiply, i64(6)))
+            )
+        );
+    }
+
+    #[test]
+    fn left_associativity_test() {
+        assert_eq!(
+            parse_expression(b"8 - 4 - 2;"),
+            IResult::Done(
+                &b";"[..],
+                operation(operation(i64(8), Operator::Subtract, i64(4)), Operator::Subtract, i64(2))
+            )
+        );
+    }
+
+    #[test]
+    fn comparison_precedence_test() {
+        assert_eq!(
+            parse_expression(b"1 + 2 <= 3 * 4;"),
+            IResult::Done(
+                &b";"[..],
+                operation(
+                    operation(i64(1), Operator::Add, i64(2)),
+                    Operator::LessEqual,
+                    operation(i64(3), Operator::Multiply, i64(4))
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn conditional_test() {
+        let n = || Expression::Operand(Operand::VarSubstitution(Name("n".to_string())), Span::default());
+        assert_eq!(
+            parse_expression(b"if n == 0 then 1 else n * 2;"),
+            IResult::Done(
+                &b";"[..],
+                Expression::Operand(
+                    Operand::If(
+                        Box::new(operation(n(), Operator::Equal, i64(0))),
+                        Box::new(i64(1)),
+                        Box::new(operation(n(), Operator::Multiply, i64(2)))
+                    ),
+                    Span::default()
+                )
+            )
+        );
+        assert_eq!(
+            parse_expression(b"iffy + 1;"),
+            IResult::Done(
+                &b";"[..],
+                operation(
+                    Expression::Operand(Operand::VarSubstitution(Name("iffy".to_string())), Span::default()),
+                    Operator::Add,
+                    i64(1)
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn lambda_test() {
+        let variable = |name: &str| {
+            Expression::Operand(Operand::VarSubstitution(Name(name.to_string())), Span::default())
+        };
+        let list = |items| Expression::Operand(Operand::List(items), Span::default());
+        assert_eq!(
+            parse_expression(b"\\x, y -> x + y * 2, [1, [], y];"),
+            IResult::Done(
+                &b", [1, [], y];"[..],
+                Expression::Operand(
+                    Operand::Lambda(
+                        vec![Name("x".to_string()), Name("y".to_string())],
+                        Box::new(operation(
+                            variable("x"),
+                            Operator::Add,
+                            operation(variable("y"), Operator::Multiply, i64(2))
+                        ))
+                    ),
+                    Span::default()
+                )
+            )
+        );
+        assert_eq!(
+            parse_expression(b"[1, [], y] + 1;"),
+            IResult::Done(&b";"[..], operation(list(vec![i64(1), list(vec![]), variable("y")]), Operator::Add, i64(1)))
+        );
+    }
+
+    #[test]
+    fn call_test() {
+        let variable = |name: &str| {
+            Expression::Operand(Operand::VarSubstitution(Name(name.to_string())), Span::default())
+        };
+        let apply = |name: &str, args| {
+            Expression::Operand(Operand::FnApplication(Name(name.to_string()), args), Span::default())
+        };
+        let call = |callee, args| {
+            Expression::Operand(Operand::Call(Box::new(callee), args), Span::default())
+        };
+        assert_eq!(
+            parse_expression(b"f(1)(2)() * 3;"),
+            IResult::Done(
+                &b";"[..],
+                operation(
+                    call(call(apply("f", vec![i64(1)]), vec![i64(2)]), vec![]),
+                    Operator::Multiply,
+                    i64(3)
+                )
+            )
+        );
+        let body = operation(variable("x"), Operator::Add, i64(1));
+        let increment = Expression::Operand(
+            Operand::Lambda(vec![Name("x".to_string())], Box::new(body)),
+            Span::default(),
+        );
+        assert_eq!(
+            parse_expression(b"(\\x -> x + 1)(4);"),
+            IResult::Done(&b";"[..], call(increment, vec![i64(4)]))
+        );
+        assert_eq!(
+            parse_expression(b"(f)(x);"),
+            IResult::Done(&b";"[..], apply("f", vec![variable("x")]))
+        );
+    }
+
+    #[test]
+    fn float_literal_test() {
+        assert_eq!(
+            parse_expression(b"2.5 * -1e3 + 4E-2 - 7.0;"),
+            IResult::Done(
+                &b";"[..],
+                operation(
+                    operation(operation(f64(2.5), Operator::Multiply, f64(-1e3)), Operator::Add, f64(0.04)),
+                    Operator::Subtract,
+                    f64(7.0)
+                )
+            )
+        );
+        assert_eq!(
+            parse_expression(b"if 2 then 3else 4;"),
+            parse_expression(b"if 2 then 3 else 4;")
+        );
+    }
+
+    #[test]
+    fn parentheses_test() {
+        assert_eq!(
+            parse_expression(b"(1 + 3) * 6;"),
+            IResult::Done(
+                &b";"[..],
+                operation(operation(i64(1), Operator::Add, i64(3)), Operator::Multiply, i64(6))
+            )
+        );
+        assert_eq!(
+            parse_expression(b"f((1), 2 * (3 - 4));"),
+            IResult::Done(
+                &b";"[..],
+                Expression::Operand(Operand::FnApplication(
+                    Name("f".to_string()),
+                    vec![
+                        i64(1),
+                        operation(i64(2), Operator::Multiply, operation(i64(3), Operator::Subtract, i64(4))),
+                    ]
+                ), Span::default())
+            )
+        );
     }
 }
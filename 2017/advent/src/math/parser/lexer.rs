@@ -0,0 +1,376 @@
+use super::*;
+use std::str;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Integer(i64),
+    Float(f64),
+    /// `[A-Za-z_][A-Za-z0-9_]*`, other than a keyword, or several of them
+    /// joined by `.` to qualify a name with a namespace.
+    Identifier(Name),
+    /// `"..."`, which cannot span lines and has no escapes.
+    String(String),
+    If,
+    Then,
+    Else,
+    Import,
+    Operator(Operator),
+    Equals,
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+    Backslash,
+    Arrow,
+    Comma,
+    Semicolon,
+}
+
+/// A token along with where it was found in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Span,
+}
+
+impl Token {
+    // Whether this token can end an operand, in which case a `-` after it is
+    // subtraction rather than the sign of a literal.
+    fn ends_operand(&self) -> bool {
+        match *self {
+            Token::Integer(_)
+            | Token::Float(_)
+            | Token::Identifier(_)
+            | Token::CloseParen
+            | Token::CloseBracket => true,
+            _ => false,
+        }
+    }
+}
+
+/// Splits `source` into tokens, dropping whitespace and `#` comments, which run
+/// to the end of the line.
+///
+/// A `-` directly followed by a digit is the sign of a literal unless it comes
+/// straight after an operand, so `5-3` is a subtraction but `5 * -3` multiplies
+/// by the literal `-3`.
+pub fn tokenize(source: &[u8]) -> Result<Vec<Lexeme>, Error> {
+    tokenize_at(source, 1, 1)
+}
+
+/// Like `tokenize`, for a source that is the rest of a larger one from the
+/// given line and column, which its spans are numbered from.
+pub fn tokenize_at(source: &[u8], line: usize, column: usize) -> Result<Vec<Lexeme>, Error> {
+    lex(source, line, column).map(|(lexemes, _)| lexemes)
+}
+
+/// The `#` comments in `source`, each with the text after its `#`, in order.
+/// A `#` within a string does not start one.
+pub fn comments(source: &[u8]) -> Result<Vec<(String, Span)>, Error> {
+    lex(source, 1, 1).map(|(_, comments)| comments)
+}
+
+fn lex(source: &[u8], line: usize, column: usize) -> Result<(Vec<Lexeme>, Vec<(String, Span)>), Error> {
+    let mut lexer = Lexer {
+        source,
+        position: 0,
+        line,
+        line_start: 0,
+        column_offset: column - 1,
+    };
+    let mut comments = vec![];
+    let mut lexemes: Vec<Lexeme> = vec![];
+    while let Some(b) = lexer.peek(0) {
+        let start = lexer.position;
+        let after_operand = lexemes.last().map_or(false, |l| l.token.ends_operand());
+        let token = match b {
+            b'\n' => {
+                lexer.position += 1;
+                lexer.line += 1;
+                lexer.line_start = lexer.position + lexer.column_offset;
+                continue;
+            }
+            b' ' | b'\t' | b'\r' => {
+                lexer.position += 1;
+                continue;
+            }
+            b'#' => {
+                while lexer.peek(0).map_or(false, |b| b != b'\n') {
+                    lexer.position += 1;
+                }
+                let text = String::from_utf8_lossy(&source[start + 1..lexer.position]);
+                comments.push((text.trim_end().to_string(), lexer.span(start)));
+                continue;
+            }
+            b'0'..=b'9' => lexer.number()?,
+            b'-' if !after_operand && lexer.peek(1).map_or(false, is_digit) => lexer.number()?,
+            b'A'..=b'Z' | b'a'..=b'z' | b'_' => lexer.word(),
+            b'"' => lexer.string()?,
+            _ => lexer.symbol()?,
+        };
+        lexemes.push(Lexeme {
+            token,
+            span: lexer.span(start),
+        });
+    }
+    return Ok((lexemes, comments));
+}
+
+struct Lexer<'a> {
+    source: &'a [u8],
+    position: usize,
+    line: usize,
+    // Where the current line starts, plus `column_offset`, the number of
+    // columns of the first line before `source`.
+    line_start: usize,
+    column_offset: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.get(self.position + offset).cloned()
+    }
+
+    fn span(&self, start: usize) -> Span {
+        Span {
+            start,
+            end: self.position,
+            line: self.line,
+            column: 1 + start + self.column_offset - self.line_start,
+        }
+    }
+
+    fn skip_digits(&mut self) {
+        while self.peek(0).map_or(false, is_digit) {
+            self.position += 1;
+        }
+    }
+
+    // A literal with a fractional part or an exponent is a float, and any other
+    // is an integer. Both need a digit after the `.` or the `e`, so that `1.`
+    // is an error and `2else` is `2` followed by `else`.
+    fn number(&mut self) -> Result<Token, Error> {
+        let start = self.position;
+        if self.peek(0) == Some(b'-') {
+            self.position += 1;
+        }
+        self.skip_digits();
+        let mut float = false;
+        if self.peek(0) == Some(b'.') && self.peek(1).map_or(false, is_digit) {
+            float = true;
+            self.position += 1;
+            self.skip_digits();
+        }
+        if let Some(b'e') | Some(b'E') = self.peek(0) {
+            let sign = match self.peek(1) {
+                Some(b'+') | Some(b'-') => 1,
+                _ => 0,
+            };
+            if self.peek(1 + sign).map_or(false, is_digit) {
+                float = true;
+                self.position += 1 + sign;
+                self.skip_digits();
+            }
+        }
+        let text = str::from_utf8(&self.source[start..self.position]).unwrap();
+        if float {
+            Ok(Token::Float(text.parse().unwrap()))
+        } else {
+            text.parse()
+                .map(Token::Integer)
+                .map_err(|_| Error::new(ErrorKind::IntegerTooLarge, self.span(start)))
+        }
+    }
+
+    // A `.` only joins words when another word follows it, so `a.` is `a`
+    // followed by an unexpected `.`.
+    fn word(&mut self) -> Token {
+        let start = self.position;
+        loop {
+            while self.peek(0).map_or(false, |b| b.is_ascii_alphanumeric() || b == b'_') {
+                self.position += 1;
+            }
+            match (self.peek(0), self.peek(1)) {
+                (Some(b'.'), Some(b)) if b.is_ascii_alphabetic() || b == b'_' => self.position += 1,
+                _ => break,
+            }
+        }
+        match &self.source[start..self.position] {
+            b"if" => Token::If,
+            b"then" => Token::Then,
+            b"else" => Token::Else,
+            b"import" => Token::Import,
+            word => Token::Identifier(Name(str::from_utf8(word).unwrap().to_string())),
+        }
+    }
+
+    fn string(&mut self) -> Result<Token, Error> {
+        let start = self.position;
+        self.position += 1;
+        loop {
+            match self.peek(0) {
+                Some(b'"') => break,
+                Some(b'\n') | None => return Err(Error::new(ErrorKind::UnterminatedString, self.span(start))),
+                Some(_) => self.position += 1,
+            }
+        }
+        self.position += 1;
+        let contents = &self.source[start + 1..self.position - 1];
+        Ok(Token::String(String::from_utf8_lossy(contents).into_owned()))
+    }
+
+    fn symbol(&mut self) -> Result<Token, Error> {
+        let start = self.position;
+        let (token, length) = match (self.peek(0).unwrap(), self.peek(1)) {
+            (b'=', Some(b'=')) => (Token::Operator(Operator::Equal), 2),
+            (b'!', Some(b'=')) => (Token::Operator(Operator::NotEqual), 2),
+            (b'<', Some(b'=')) => (Token::Operator(Operator::LessEqual), 2),
+            (b'>', Some(b'=')) => (Token::Operator(Operator::GreaterEqual), 2),
+            (b'-', Some(b'>')) => (Token::Arrow, 2),
+            (b'<', _) => (Token::Operator(Operator::Less), 1),
+            (b'>', _) => (Token::Operator(Operator::Greater), 1),
+            (b'+', _) => (Token::Operator(Operator::Add), 1),
+            (b'-', _) => (Token::Operator(Operator::Subtract), 1),
+            (b'*', _) => (Token::Operator(Operator::Multiply), 1),
+            (b'/', _) => (Token::Operator(Operator::Divide), 1),
+            (b'=', _) => (Token::Equals, 1),
+            (b'(', _) => (Token::OpenParen, 1),
+            (b')', _) => (Token::CloseParen, 1),
+            (b'[', _) => (Token::OpenBracket, 1),
+            (b']', _) => (Token::CloseBracket, 1),
+            (b'\\', _) => (Token::Backslash, 1),
+            (b',', _) => (Token::Comma, 1),
+            (b';', _) => (Token::Semicolon, 1),
+            _ => {
+                self.position += 1;
+                return Err(Error::new(ErrorKind::UnexpectedCharacter, self.span(start)));
+            }
+        };
+        self.position += length;
+        Ok(token)
+    }
+}
+
+fn is_digit(b: u8) -> bool {
+    b.is_ascii_digit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &[u8]) -> Vec<Token> {
+        tokenize(s).unwrap().into_iter().map(|l| l.token).collect()
+    }
+
+    fn identifier(name: &str) -> Token {
+        Token::Identifier(Name(name.to_string()))
+    }
+
+    #[test]
+    fn signed_literal_test() {
+        let subtract = Token::Operator(Operator::Subtract);
+        assert_eq!(
+            tokens(b"5-3"),
+            vec![Token::Integer(5), subtract.clone(), Token::Integer(3)]
+        );
+        assert_eq!(
+            tokens(b"x-1 * -2"),
+            vec![
+                identifier("x"),
+                subtract.clone(),
+                Token::Integer(1),
+                Token::Operator(Operator::Multiply),
+                Token::Integer(-2),
+            ]
+        );
+        assert_eq!(
+            tokens(b"f(-1.5e-3)-x"),
+            vec![
+                identifier("f"),
+                Token::OpenParen,
+                Token::Float(-1.5e-3),
+                Token::CloseParen,
+                subtract,
+                identifier("x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn identifier_test() {
+        assert_eq!(
+            tokens(b"x*(rate_2+_a) iffy if"),
+            vec![
+                identifier("x"),
+                Token::Operator(Operator::Multiply),
+                Token::OpenParen,
+                identifier("rate_2"),
+                Token::Operator(Operator::Add),
+                identifier("_a"),
+                Token::CloseParen,
+                identifier("iffy"),
+                Token::If,
+            ]
+        );
+    }
+
+    #[test]
+    fn lambda_test() {
+        assert_eq!(
+            tokens(b"\\x->[x-1]"),
+            vec![
+                Token::Backslash,
+                identifier("x"),
+                Token::Arrow,
+                Token::OpenBracket,
+                identifier("x"),
+                Token::Operator(Operator::Subtract),
+                Token::Integer(1),
+                Token::CloseBracket,
+            ]
+        );
+    }
+
+    #[test]
+    fn import_test() {
+        assert_eq!(
+            tokens(b"import \"lib/a b.math\"; geometry.area(r._x)"),
+            vec![
+                Token::Import,
+                Token::String("lib/a b.math".to_string()),
+                Token::Semicolon,
+                identifier("geometry.area"),
+                Token::OpenParen,
+                identifier("r._x"),
+                Token::CloseParen,
+            ]
+        );
+        // A `.` that is not followed by a name is not part of one.
+        assert_eq!(tokenize(b"a.").unwrap_err().kind, ErrorKind::UnexpectedCharacter);
+        let e = tokenize(b"import \"a.math;\nb = 1;").unwrap_err();
+        assert_eq!((e.kind, e.span.start, e.span.end), (ErrorKind::UnterminatedString, 7, 15));
+    }
+
+    #[test]
+    fn comment_test() {
+        let lexemes = tokenize(b"# a comment; with a semicolon\n\na = 1; # another\nb = 2;").unwrap();
+        assert_eq!(lexemes.len(), 8);
+        let span = lexemes[4].span;
+        assert_eq!((span.start, span.end, span.line, span.column), (48, 49, 4, 1));
+
+        let comments = comments(b"# a\na = \"#b\"; #  c  \r\n#").unwrap();
+        let comments: Vec<_> = comments.iter().map(|&(ref text, span)| (text.as_str(), span.line)).collect();
+        assert_eq!(comments, vec![(" a", 1), ("  c", 2), ("", 3)]);
+    }
+
+    #[test]
+    fn error_test() {
+        let e = tokenize(b"a = 1;\nb = 2 $ 3;").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::UnexpectedCharacter);
+        assert_eq!((e.span.start, e.span.line, e.span.column), (13, 2, 7));
+        let e = tokenize(b"a = 99999999999999999999;").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::IntegerTooLarge);
+        assert_eq!((e.span.start, e.span.end), (4, 24));
+    }
+}
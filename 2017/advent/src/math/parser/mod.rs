@@ -1,28 +1,232 @@
 #[cfg_attr(rustfmt, rustfmt_skip)]
 
+mod lexer;
 mod name;
 mod expression;
 mod statement;
+mod sexpr;
+mod error;
 
+pub use self::lexer::{comments, tokenize, tokenize_at, Lexeme, Token};
 pub use self::name::*;
 pub use self::expression::*;
 pub use self::statement::*;
+pub use self::sexpr::parse_sexpr;
+pub use self::error::{Error, ErrorKind};
 
 use super::*;
-use nom::{simple_errors, IResult, Needed};
+use nom::IResult;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Error {
-    RemainingInput(Vec<u8>, Vec<Statement>),
-    Nom(simple_errors::Err<u32>),
-    NomIncomplete(Needed),
+/// Parses a whole program. The source is split into tokens by `tokenize`
+/// first, so the grammar itself never sees whitespace or comments.
+pub fn parse(s: &[u8]) -> Result<Vec<Statement>, Error> {
+    parse_at(s, 1, 1)
 }
 
-pub fn parse(s: &[u8]) -> Result<Vec<Statement>, Error> {
-    match statements(s) {
-        IResult::Done(&[], statements) => Ok(statements),
-        IResult::Done(i, o) => Err(Error::RemainingInput(i.to_vec(), o)),
-        IResult::Error(e) => Err(Error::Nom(e)),
-        IResult::Incomplete(n) => Err(Error::NomIncomplete(n)),
+/// Like `parse`, for a source that is the rest of a larger one from the given
+/// line and column, which its spans are numbered from.
+pub fn parse_at(s: &[u8], line: usize, column: usize) -> Result<Vec<Statement>, Error> {
+    let tokens = tokenize_at(s, line, column)?;
+    let mut statements = vec![];
+    let mut input = &tokens[..];
+    while !input.is_empty() {
+        match statement(input) {
+            IResult::Done(i, statement) => {
+                match statement {
+                    Statement::VarAssignment(_, ref expr, _) | Statement::FnDefinition(_, _, ref expr, _) => {
+                        check_depth(expr)?
+                    }
+                    Statement::Import(..) => {}
+                }
+                statements.push(statement);
+                input = i;
+            }
+            IResult::Error(e) => return Err(Error::from_nom(s, &tokens, e)),
+            IResult::Incomplete(_) => {
+                let mut span = Span::new(s, s.len(), s.len());
+                if span.line == 1 {
+                    span.column += column - 1;
+                }
+                span.line += line - 1;
+                return Err(Error::new(ErrorKind::UnexpectedEnd, span));
+            }
+        }
+    }
+    return Ok(statements);
+}
+
+/// Parses a single expression, such as `a * 2 + f(3)`, with nothing after it.
+pub fn parse_expression(s: &[u8]) -> Result<Expression, Error> {
+    let tokens = tokenize(s)?;
+    if tokens.is_empty() {
+        return Err(Error::new(ErrorKind::ExpectedExpression, Span::new(s, s.len(), s.len())));
+    }
+    match expression(&tokens) {
+        IResult::Done(i, _) if !i.is_empty() => Err(Error::new(ErrorKind::UnexpectedInput, i[0].span)),
+        IResult::Done(_, expr) => check_depth(&expr).map(|()| expr),
+        IResult::Error(e) => Err(Error::from_nom(s, &tokens, e)),
+        IResult::Incomplete(_) => Err(Error::new(ErrorKind::UnexpectedEnd, Span::new(s, s.len(), s.len()))),
+    }
+}
+
+// Matches a token that carries no value, such as `(` or `then`, returning
+// where it was.
+fn token(i: &[Lexeme], expected: Token) -> IResult<&[Lexeme], Span> {
+    match i.first() {
+        Some(lexeme) if lexeme.token == expected => IResult::Done(&i[1..], lexeme.span),
+        _ => IResult::Error(error_position!(nom::ErrorKind::Tag, i)),
+    }
+}
+
+// The span of the next token, without consuming it, so that a parser can note
+// where a node starts.
+fn next_span(i: &[Lexeme]) -> IResult<&[Lexeme], Span> {
+    match i.first() {
+        Some(lexeme) => IResult::Done(i, lexeme.span),
+        None => IResult::Error(error_position!(nom::ErrorKind::Eof, i)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statement_span_test() {
+        let statements = parse(b"a = 1;\n  bc(x) =\n    x * 2;").unwrap();
+        let span = statements[1].span();
+        assert_eq!((span.start, span.end), (9, 27));
+        assert_eq!((span.line, span.column), (2, 3));
+    }
+
+    #[test]
+    fn expression_span_test() {
+        let statements = parse(b"a = (1 + 2) * b;").unwrap();
+        match statements[0] {
+            Statement::VarAssignment(_, Expression::Operation(ref lhs, _, ref rhs, span), _) => {
+                assert_eq!((span.start, span.end), (4, 15));
+                assert_eq!((lhs.span().start, lhs.span().end), (4, 11));
+                assert_eq!((rhs.span().start, rhs.span().end), (14, 15));
+            }
+            ref other => panic!("unexpected statement {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_semicolon_test() {
+        let e = parse(b"a = 1 + 2\nb = a * 3;").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::ExpectedSemicolon);
+        assert_eq!((e.span.start, e.span.line, e.span.column), (9, 1, 10));
+    }
+
+    #[test]
+    fn error_kinds_test() {
+        assert_eq!(parse(b"a 1;").unwrap_err().kind, ErrorKind::ExpectedEquals);
+        assert_eq!(parse(b"a = ;").unwrap_err().kind, ErrorKind::ExpectedExpression);
+        assert_eq!(parse(b"a = (1 + 2;").unwrap_err().kind, ErrorKind::ExpectedClosingParen);
+        assert_eq!(parse(b"f(x y) = x;").unwrap_err().kind, ErrorKind::ExpectedClosingParen);
+        assert_eq!(parse(b"a = 1").unwrap_err().kind, ErrorKind::ExpectedSemicolon);
+        assert_eq!(parse(b"=").unwrap_err().kind, ErrorKind::ExpectedName);
+        assert_eq!(parse(b"a = f(1, );").unwrap_err().kind, ErrorKind::ExpectedExpression);
+        assert_eq!(parse(b"a = f(1 2);").unwrap_err().kind, ErrorKind::ExpectedClosingParen);
+        assert_eq!(parse(b"a = f(1)(2;").unwrap_err().kind, ErrorKind::ExpectedClosingParen);
+        assert_eq!(parse(b"a = (\\x -> x)(;").unwrap_err().kind, ErrorKind::ExpectedExpression);
+        assert_eq!(parse(b"a = 1 + (2 * );").unwrap_err().kind, ErrorKind::ExpectedExpression);
+        assert_eq!(parse(b"a = 5 * (b - 2;").unwrap_err().kind, ErrorKind::ExpectedClosingParen);
+        assert_eq!(parse(b"a = if b c;").unwrap_err().kind, ErrorKind::ExpectedThen);
+        assert_eq!(parse(b"a = if b then c;").unwrap_err().kind, ErrorKind::ExpectedElse);
+        assert_eq!(parse(b"a = 1 < 2 < 3;").unwrap_err().kind, ErrorKind::ExpectedSemicolon);
+        assert_eq!(parse(b"then = 1;").unwrap_err().kind, ErrorKind::ReservedKeyword);
+        assert_eq!(parse(b"f(else) = 1;").unwrap_err().kind, ErrorKind::ReservedKeyword);
+        assert_eq!(parse(b"f(x, then) = 1;").unwrap_err().kind, ErrorKind::ReservedKeyword);
+        assert_eq!(parse(b"f(x,) = 1;").unwrap_err().kind, ErrorKind::ExpectedClosingParen);
+        assert_eq!(parse(b"a = \\x, if -> x;").unwrap_err().kind, ErrorKind::ReservedKeyword);
+        assert_eq!(parse(b"a = 1 @ 2;").unwrap_err().kind, ErrorKind::UnexpectedCharacter);
+        assert_eq!(parse(b"a = -x;").unwrap_err().kind, ErrorKind::ExpectedExpression);
+        assert_eq!(parse(b"a = \\x x;").unwrap_err().kind, ErrorKind::ExpectedArrow);
+        assert_eq!(parse(b"a = \\x -> ;").unwrap_err().kind, ErrorKind::ExpectedExpression);
+        assert_eq!(parse(b"a = [1, 2;").unwrap_err().kind, ErrorKind::ExpectedClosingBracket);
+        assert_eq!(parse(b"import geometry;").unwrap_err().kind, ErrorKind::ExpectedPath);
+        assert_eq!(parse(b"import \"lib/2d.math\";").unwrap_err().kind, ErrorKind::InvalidNamespace);
+        assert_eq!(parse(b"import \"a.b.math\";").unwrap_err().kind, ErrorKind::InvalidNamespace);
+        assert_eq!(parse(b"import \"a.math\"").unwrap_err().kind, ErrorKind::ExpectedSemicolon);
+        assert_eq!(parse(b"import = 1;").unwrap_err().kind, ErrorKind::ExpectedPath);
+        assert_eq!(parse(b"f(import) = 1;").unwrap_err().kind, ErrorKind::ReservedKeyword);
+    }
+
+    #[test]
+    fn parse_expression_test() {
+        assert_eq!(
+            parse_expression(b" a * 2 + f(3) ").map(|expr| expr.to_string()),
+            Ok("a * 2 + f(3)".to_string())
+        );
+        let errors: &[(&[u8], ErrorKind, usize)] = &[
+            (b"a * 2;", ErrorKind::UnexpectedInput, 5),
+            (b"a = 1", ErrorKind::UnexpectedInput, 2),
+            (b"a *", ErrorKind::ExpectedExpression, 3),
+            (b" ", ErrorKind::ExpectedExpression, 1),
+        ];
+        for &(source, kind, start) in errors {
+            let e = parse_expression(source).unwrap_err();
+            assert_eq!((e.kind, e.span.start), (kind, start), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn nesting_test() {
+        let nested = |depth: usize| format!("a = {}1{};", "(".repeat(depth - 1), ")".repeat(depth - 1));
+        let chain = |depth: usize| format!("a = 1{};", " + 1".repeat(depth - 1));
+        let lambdas = |depth: usize| format!("a = {}1;", "\\-> ".repeat(depth - 1));
+        let branches = |depth: usize| format!("a = {}1;", "if 0 then 0 else ".repeat(depth - 1));
+        // The deepest chain, nested as deeply as anything can be.
+        let mixed = format!(
+            "f(x) = x;\na = {}1{}{};",
+            "f(".repeat(MAX_NESTING - 1),
+            ")".repeat(MAX_NESTING - 1),
+            " + 1".repeat(MAX_DEPTH - MAX_NESTING)
+        );
+        for source in &[nested(MAX_NESTING), chain(MAX_DEPTH), lambdas(MAX_NESTING), branches(MAX_DEPTH), mixed] {
+            // Whatever walks the AST after parsing copes with the deepest.
+            let statements = parse(source.as_bytes()).unwrap();
+            ::math::check(&statements);
+            ::math::format(&statements);
+            assert!(::math::interpret(source.as_bytes()).is_ok(), "{:?}", source);
+        }
+        assert_eq!(::math::interpret(chain(MAX_DEPTH).as_bytes()).unwrap()[&Name("a".to_string())], 1000);
+        let too_deep = [
+            nested(MAX_NESTING + 1),
+            nested(1000),
+            chain(MAX_DEPTH + 1),
+            chain(10_000),
+            lambdas(1000),
+            branches(MAX_DEPTH + 1),
+        ];
+        for source in &too_deep {
+            let e = parse(source.as_bytes()).unwrap_err();
+            assert_eq!(e.kind, ErrorKind::NestedTooDeeply, "{:?}", &source[..20]);
+        }
+        let e = parse_expression(b"[[[[1]]]]").map(|_| ());
+        assert_eq!(e, Ok(()));
+        let e = parse_expression(format!("f({}1{})", "[".repeat(MAX_NESTING), "]".repeat(MAX_NESTING)).as_bytes());
+        assert_eq!(e.unwrap_err().kind, ErrorKind::NestedTooDeeply);
+    }
+
+    // An `else if` chain is read as if each `if` were in parentheses up to the
+    // end of its alternative.
+    #[test]
+    fn else_if_test() {
+        assert_eq!(
+            parse_expression(b"if a then 1 else if b then 2 else if c then 3 else d < 4 < 5 < 6"),
+            parse_expression(b"if a then 1 else (if b then 2 else (if c then 3 else d < 4) < 5) < 6")
+        );
+    }
+
+    #[test]
+    fn tokenized_source_test() {
+        assert_eq!(
+            parse(b"# Scale.\nf(x, a) = x*(a+1); # by a + 1\nb = 5-3-f(-1, 2);").unwrap(),
+            parse(b"f(x, a) = x * (a + 1);\nb = (5 - 3) - f(-1, 2);").unwrap()
+        );
+        assert_eq!(parse(b"  # nothing but a comment\n").unwrap(), vec![]);
     }
 }
@@ -1,17 +1,78 @@
-use super::super::*;
-use std::string;
-use nom::is_space;
+use super::*;
+use super::lexer::{Lexeme, Token};
+use nom::IResult;
+use std::path::Path;
 
-named!(pub variable_name<&[u8], Name>,
-  map!(
-    take_till!(|b: u8| is_space(b) || b == b'=' || b == b'(' || b == b')' || b == b',' || b == b';'),
-    |bytes| Name(to_str(bytes).unwrap())));
+pub fn name(i: &[Lexeme]) -> IResult<&[Lexeme], Name> {
+    match i.first() {
+        Some(&Lexeme { token: Token::Identifier(ref name), .. }) => IResult::Done(&i[1..], name.clone()),
+        _ => IResult::Error(error_position!(nom::ErrorKind::Tag, i)),
+    }
+}
+
+// The name of a variable, function or parameter being defined, where using a
+// keyword gets a more specific error than `ExpectedName`.
+pub fn defined_name(i: &[Lexeme]) -> IResult<&[Lexeme], Name> {
+    match i.first().map(|l| &l.token) {
+        Some(&Token::If) | Some(&Token::Then) | Some(&Token::Else) | Some(&Token::Import) => {
+            IResult::Error(error_position!(ErrorKind::ReservedKeyword.code(), i))
+        }
+        _ => name(i),
+    }
+}
 
-named!(pub function_name<&[u8], Name>,
-  map!(
-    take_till!(|b: u8| is_space(b) || b == b'(' || b == b')' || b == b';'),
-    |bytes| Name(to_str(bytes).unwrap())));
+// Comma-separated names of parameters, as in a definition or a lambda. The
+// list ends at anything but a name, except a keyword, which is an error.
+pub fn parameter_names(i: &[Lexeme]) -> IResult<&[Lexeme], Vec<Name>> {
+    let mut names = vec![];
+    let mut rest = i;
+    loop {
+        let next = if names.is_empty() {
+            rest
+        } else {
+            match token(rest, Token::Comma) {
+                IResult::Done(next, _) => next,
+                _ => return IResult::Done(rest, names),
+            }
+        };
+        match defined_name(next) {
+            IResult::Done(after, name) => {
+                names.push(name);
+                rest = after;
+            }
+            IResult::Error(nom::Err::Position(ref code, _)) if *code == ErrorKind::ReservedKeyword.code() => {
+                return IResult::Error(error_position!(ErrorKind::ReservedKeyword.code(), next));
+            }
+            _ => return IResult::Done(rest, names),
+        }
+    }
+}
+
+/// Parses `s` as a name on its own, as when a name comes from somewhere other
+/// than math source. Fails just as it would in a statement defining it.
+pub fn parse_name(s: &str) -> Result<Name, Error> {
+    let tokens = tokenize(s.as_bytes())?;
+    match defined_name(&tokens) {
+        IResult::Done(rest, name) => match rest.first() {
+            None => Ok(name),
+            Some(next) => Err(Error::new(ErrorKind::UnexpectedInput, next.span)),
+        },
+        IResult::Error(nom::Err::Position(ref code, _)) if *code == ErrorKind::ReservedKeyword.code() => {
+            Err(Error::new(ErrorKind::ReservedKeyword, tokens[0].span))
+        }
+        _ => {
+            let span = tokens.first().map_or(Span::new(s.as_bytes(), 0, 0), |l| l.span);
+            Err(Error::new(ErrorKind::ExpectedName, span))
+        }
+    }
+}
 
-pub fn to_str(u8s: &[u8]) -> Result<String, string::FromUtf8Error> {
-    String::from_utf8(u8s.to_vec())
+/// The namespace that `import` puts the file at `path` in: the file's name
+/// without its extension, if that is a name that is not qualified.
+pub fn namespace(path: &str) -> Option<Name> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    match parse_name(stem) {
+        Ok(ref name) if !name.as_str().contains('.') => Some(name.clone()),
+        _ => None,
+    }
 }
@@ -0,0 +1,367 @@
+use super::*;
+use super::lexer::{tokenize_at, Token};
+
+/// Parses a program written as S-expressions, in the form that
+/// `math::format_sexpr` writes, into the same statements that `parse` gives
+/// for it written as math source. `;` starts a comment, which runs to the end
+/// of the line.
+///
+/// Atoms are read as math source tokens are, so numbers, names and operators
+/// are spelled the same in both. Lists are written in brackets, lambdas as
+/// `(\\ (params...) body)` and imports as `(import "path")`.
+///
+/// Every operation is parenthesised, so a chain such as `1 + 2 + 3` nests as
+/// deeply as it is long, and `MAX_NESTING` bounds it along with everything
+/// else.
+pub fn parse_sexpr(s: &[u8]) -> Result<Vec<Statement>, Error> {
+    let items = items(s)?;
+    let mut reader = Reader {
+        items: &items,
+        position: 0,
+        end: Span::new(s, s.len(), s.len()),
+        depth: 0,
+    };
+    let mut statements = vec![];
+    while reader.position < items.len() {
+        statements.push(reader.statement()?);
+    }
+    return Ok(statements);
+}
+
+enum Item {
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+    Atom(Token),
+}
+
+// Splits `s` into parentheses, brackets and atoms, where an atom is anything
+// else between whitespace and must be a single math source token.
+fn items(s: &[u8]) -> Result<Vec<(Item, Span)>, Error> {
+    let mut items = vec![];
+    let mut position = 0;
+    let mut line = 1;
+    let mut line_start = 0;
+    while position < s.len() {
+        let start = position;
+        let span = |end| Span {
+            start,
+            end,
+            line,
+            column: 1 + start - line_start,
+        };
+        match s[position] {
+            b'\n' => {
+                position += 1;
+                line += 1;
+                line_start = position;
+            }
+            b' ' | b'\t' | b'\r' => position += 1,
+            b';' => {
+                while position < s.len() && s[position] != b'\n' {
+                    position += 1;
+                }
+            }
+            b'(' => {
+                position += 1;
+                items.push((Item::Open, span(position)));
+            }
+            b')' => {
+                position += 1;
+                items.push((Item::Close, span(position)));
+            }
+            b'[' => {
+                position += 1;
+                items.push((Item::OpenBracket, span(position)));
+            }
+            b']' => {
+                position += 1;
+                items.push((Item::CloseBracket, span(position)));
+            }
+            // A string may have spaces in it, so it runs to its closing quote
+            // rather than to the next delimiter.
+            b'"' => {
+                position += 1;
+                while position < s.len() && !b"\"\n".contains(&s[position]) {
+                    position += 1;
+                }
+                if position < s.len() && s[position] == b'"' {
+                    position += 1;
+                }
+                let span = span(position);
+                let mut tokens = tokenize_at(&s[start..position], line, 1).map_err(|e| Error::new(e.kind, span))?;
+                items.push((Item::Atom(tokens.remove(0).token), span));
+            }
+            _ => {
+                while position < s.len() && !b" \t\r\n();[]".contains(&s[position]) {
+                    position += 1;
+                }
+                let span = span(position);
+                let mut tokens = tokenize_at(&s[start..position], line, 1).map_err(|e| Error::new(e.kind, span))?;
+                if tokens.len() != 1 {
+                    return Err(Error::new(ErrorKind::UnexpectedInput, span));
+                }
+                items.push((Item::Atom(tokens.remove(0).token), span));
+            }
+        }
+    }
+    return Ok(items);
+}
+
+struct Reader<'a> {
+    items: &'a [(Item, Span)],
+    position: usize,
+    // Where running out of items is reported.
+    end: Span,
+    // How many calls of `expression` are in progress, held to `MAX_NESTING`
+    // as in math source so that reading cannot overflow the stack.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Result<&'a (Item, Span), Error> {
+        self.items
+            .get(self.position)
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEnd, self.end))
+    }
+
+    fn next(&mut self) -> Result<&'a (Item, Span), Error> {
+        let item = self.peek()?;
+        self.position += 1;
+        Ok(item)
+    }
+
+    fn at_close(&self) -> Result<bool, Error> {
+        Ok(match *self.peek()? {
+            (Item::Close, _) => true,
+            _ => false,
+        })
+    }
+
+    fn close(&mut self) -> Result<Span, Error> {
+        match *self.next()? {
+            (Item::Close, span) => Ok(span),
+            (_, span) => Err(Error::new(ErrorKind::ExpectedClosingParen, span)),
+        }
+    }
+
+    fn name(&mut self) -> Result<Name, Error> {
+        match *self.next()? {
+            (Item::Atom(Token::Identifier(ref name)), _) => Ok(name.clone()),
+            (Item::Atom(Token::If), span)
+            | (Item::Atom(Token::Then), span)
+            | (Item::Atom(Token::Else), span)
+            | (Item::Atom(Token::Import), span) => Err(Error::new(ErrorKind::ReservedKeyword, span)),
+            (_, span) => Err(Error::new(ErrorKind::ExpectedName, span)),
+        }
+    }
+
+    // `(define name expr)`, `(define (name params...) expr)` or
+    // `(import "path")`.
+    fn statement(&mut self) -> Result<Statement, Error> {
+        let start = match *self.next()? {
+            (Item::Open, span) => span,
+            (_, span) => return Err(Error::new(ErrorKind::ExpectedOpenParen, span)),
+        };
+        match *self.next()? {
+            (Item::Atom(Token::Identifier(ref name)), _) if name.as_str() == "define" => {}
+            (Item::Atom(Token::Import), _) => return self.import(start),
+            (_, span) => return Err(Error::new(ErrorKind::ExpectedDefine, span)),
+        }
+        if let (Item::Open, _) = *self.peek()? {
+            self.position += 1;
+            let name = self.name()?;
+            let mut params = vec![];
+            while !self.at_close()? {
+                params.push(self.name()?);
+            }
+            self.position += 1;
+            let body = self.expression()?;
+            check_depth(&body)?;
+            let end = self.close()?;
+            return Ok(Statement::FnDefinition(name, params, body, start.to(end)));
+        }
+        let name = self.name()?;
+        let expr = self.expression()?;
+        check_depth(&expr)?;
+        let end = self.close()?;
+        return Ok(Statement::VarAssignment(name, expr, start.to(end)));
+    }
+
+    fn import(&mut self, start: Span) -> Result<Statement, Error> {
+        let (path, namespace) = match *self.next()? {
+            (Item::Atom(Token::String(ref path)), span) => match namespace(path) {
+                Some(namespace) => (path.clone(), namespace),
+                None => return Err(Error::new(ErrorKind::InvalidNamespace, span)),
+            },
+            (_, span) => return Err(Error::new(ErrorKind::ExpectedPath, span)),
+        };
+        let end = self.close()?;
+        return Ok(Statement::Import(path, namespace, start.to(end)));
+    }
+
+    fn expression(&mut self) -> Result<Expression, Error> {
+        if self.depth == MAX_NESTING {
+            return Err(Error::new(ErrorKind::NestedTooDeeply, self.peek()?.1));
+        }
+        self.depth += 1;
+        let result = self.operand();
+        self.depth -= 1;
+        return result;
+    }
+
+    fn operand(&mut self) -> Result<Expression, Error> {
+        let (ref item, span) = *self.next()?;
+        let operand = match *item {
+            Item::Atom(Token::Integer(value)) => Operand::I64(value),
+            Item::Atom(Token::Float(value)) => Operand::F64(value),
+            Item::Atom(Token::Identifier(ref name)) => Operand::VarSubstitution(name.clone()),
+            Item::Open => return self.list(span),
+            Item::OpenBracket => {
+                let mut items = vec![];
+                loop {
+                    match *self.peek()? {
+                        (Item::CloseBracket, end) => {
+                            self.position += 1;
+                            return Ok(Expression::Operand(Operand::List(items), span.to(end)));
+                        }
+                        (Item::Close, end) => return Err(Error::new(ErrorKind::ExpectedClosingBracket, end)),
+                        _ => items.push(self.expression()?),
+                    }
+                }
+            }
+            _ => return Err(Error::new(ErrorKind::ExpectedExpression, span)),
+        };
+        return Ok(Expression::Operand(operand, span));
+    }
+
+    // The rest of `(operator lhs rhs)`, `(if condition then else)`,
+    // `(\\ (params...) body)` or `(function args...)`, after the `(` at
+    // `start`. The function may be a name or any other expression.
+    fn list(&mut self, start: Span) -> Result<Expression, Error> {
+        let (ref head, span) = *self.next()?;
+        let operand = match *head {
+            Item::Atom(Token::Operator(operator)) => {
+                let lhs = self.expression()?;
+                let rhs = self.expression()?;
+                let end = self.close()?;
+                return Ok(Expression::Operation(Box::new(lhs), operator, Box::new(rhs), start.to(end)));
+            }
+            Item::Atom(Token::If) => {
+                let condition = self.expression()?;
+                let consequent = self.expression()?;
+                let alternative = self.expression()?;
+                Operand::If(Box::new(condition), Box::new(consequent), Box::new(alternative))
+            }
+            Item::Atom(Token::Backslash) => {
+                match *self.next()? {
+                    (Item::Open, _) => {}
+                    (_, span) => return Err(Error::new(ErrorKind::ExpectedOpenParen, span)),
+                }
+                let mut params = vec![];
+                while !self.at_close()? {
+                    params.push(self.name()?);
+                }
+                self.position += 1;
+                Operand::Lambda(params, Box::new(self.expression()?))
+            }
+            Item::Atom(Token::Identifier(ref name)) => {
+                let mut args = vec![];
+                while !self.at_close()? {
+                    args.push(self.expression()?);
+                }
+                Operand::FnApplication(name.clone(), args)
+            }
+            Item::Open
+            | Item::OpenBracket
+            | Item::Atom(Token::Integer(_))
+            | Item::Atom(Token::Float(_)) => {
+                self.position -= 1;
+                let callee = self.expression()?;
+                let mut args = vec![];
+                while !self.at_close()? {
+                    args.push(self.expression()?);
+                }
+                Operand::Call(Box::new(callee), args)
+            }
+            Item::Atom(Token::Then) | Item::Atom(Token::Else) | Item::Atom(Token::Import) => {
+                return Err(Error::new(ErrorKind::ReservedKeyword, span));
+            }
+            _ => return Err(Error::new(ErrorKind::ExpectedName, span)),
+        };
+        let end = self.close()?;
+        return Ok(Expression::Operand(operand, start.to(end)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn programs_test() {
+        for program in ::math::programs::PROGRAMS {
+            let statements = parse(program.as_bytes()).unwrap();
+            let sexpr = ::math::format_sexpr(&statements);
+            assert_eq!(parse_sexpr(sexpr.as_bytes()), Ok(statements), "{:?}", sexpr);
+        }
+    }
+
+    #[test]
+    fn import_test() {
+        let source = b"import \"lib/geometry.math\";\na = geometry.area(2);";
+        let statements = parse(source).unwrap();
+        assert_eq!(parse_sexpr(::math::format_sexpr(&statements).as_bytes()), Ok(statements));
+    }
+
+    #[test]
+    fn comments_and_spacing_test() {
+        let source = b"; doubles\n(define (double x)\n  (* x 2))   ; then uses it\n(define a (double -3))";
+        assert_eq!(
+            parse_sexpr(source),
+            parse(b"double(x) = x * 2;\na = double(-3);")
+        );
+        let span = parse_sexpr(source).unwrap()[1].span();
+        assert_eq!((span.line, span.column, span.end), (4, 1, source.len()));
+    }
+
+    #[test]
+    fn errors_test() {
+        let errors: &[(&[u8], ErrorKind, (usize, usize))] = &[
+            (b"define a 1", ErrorKind::ExpectedOpenParen, (1, 1)),
+            (b"(let a 1)", ErrorKind::ExpectedDefine, (1, 2)),
+            (b"(define if 1)", ErrorKind::ReservedKeyword, (1, 9)),
+            (b"(define (f 1) 1)", ErrorKind::ExpectedName, (1, 12)),
+            (b"(define a 1 2)", ErrorKind::ExpectedClosingParen, (1, 13)),
+            (b"(define a\n  (+ 1))", ErrorKind::ExpectedExpression, (2, 7)),
+            (b"(define a (-> 2))", ErrorKind::ExpectedName, (1, 12)),
+            (b"(define a (+ 1 x-y))", ErrorKind::UnexpectedInput, (1, 16)),
+            (b"(define a $)", ErrorKind::UnexpectedCharacter, (1, 11)),
+            (b"(define a 99999999999999999999)", ErrorKind::IntegerTooLarge, (1, 11)),
+            (b"(define a (f 1", ErrorKind::UnexpectedEnd, (1, 15)),
+            (b"(define a [1 2)", ErrorKind::ExpectedClosingBracket, (1, 15)),
+            (b"(define a (\\ x x))", ErrorKind::ExpectedOpenParen, (1, 14)),
+            (b"(import geometry)", ErrorKind::ExpectedPath, (1, 9)),
+            (b"(import \"lib/2d.math\")", ErrorKind::InvalidNamespace, (1, 9)),
+            (b"(import \"lib/geometry.math)", ErrorKind::UnterminatedString, (1, 9)),
+        ];
+        for &(source, kind, position) in errors {
+            let e = parse_sexpr(source).unwrap_err();
+            assert_eq!((e.kind, (e.span.line, e.span.column)), (kind, position), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn depth_test() {
+        let nested = |depth: usize| {
+            format!("(define a {}1{})", "(+ 1 ".repeat(depth - 1), ")".repeat(depth - 1))
+        };
+        let source = format!("a = {}1{};", "1 + (".repeat(MAX_NESTING - 1), ")".repeat(MAX_NESTING - 1));
+        assert_eq!(parse_sexpr(nested(MAX_NESTING).as_bytes()), parse(source.as_bytes()));
+        for &depth in &[MAX_NESTING + 1, 2000, 100_000] {
+            let e = parse_sexpr(nested(depth).as_bytes()).unwrap_err();
+            assert_eq!(e.kind, ErrorKind::NestedTooDeeply, "{}", depth);
+        }
+    }
+}
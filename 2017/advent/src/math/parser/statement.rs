@@ -1,31 +1,49 @@
 use super::*;
-use super::super::*;
+use super::lexer::{Lexeme, Token};
+use nom::IResult;
 
-named!(pub statements<&[u8], Vec<Statement>>,
-  complete!(many0!(ws!(call!(statement)))));
+// Once a statement's name has been read, each remaining part is wrapped in
+// `return_error!` so that a mistake is reported where it happened rather than
+// as leftover input.
+named!(pub statement<&[Lexeme], Statement>,
+  switch!(opt!(call!(token, Token::Import)),
+    Some(start) => call!(import, start) |
+    None => call!(definition)));
 
-named!(pub statement<&[u8], Statement>,
-  alt_complete!(
-    map!(variable_assignment, |t| Statement::VarAssignment(t.0, t.1)) |
-    map!(function_definition, |t| Statement::FnDefinition(t.0, t.1, t.2))));
-
-named!(variable_assignment<&[u8], (Name, Expression)>,
+named!(definition<&[Lexeme], Statement>,
   do_parse!(
-    name: call!(variable_name) >>
-    ws!(tag!("=")) >>
-    expression: call!(expression) >>
-    ws!(tag!(";")) >>
-    (name, expression)));
+    start: call!(next_span) >>
+    name: return_error!(ErrorKind::ExpectedName.code(), call!(defined_name)) >>
+    parameters: switch!(opt!(call!(token, Token::OpenParen)),
+      Some(_) => map!(call!(parameters), Some) |
+      None => value!(None)) >>
+    return_error!(ErrorKind::ExpectedEquals.code(), call!(token, Token::Equals)) >>
+    expression: return_error!(ErrorKind::ExpectedExpression.code(), call!(expression)) >>
+    end: return_error!(ErrorKind::ExpectedSemicolon.code(), call!(token, Token::Semicolon)) >>
+    (match parameters {
+      Some(parameters) => Statement::FnDefinition(name, parameters, expression, start.to(end)),
+      None => Statement::VarAssignment(name, expression, start.to(end)),
+    })));
 
-named!(function_definition<&[u8], (Name, Vec<Name>, Expression)>,
+named!(parameters<&[Lexeme], Vec<Name>>,
   do_parse!(
-    name: call!(function_name) >>
-    ws!(tag!("(")) >>
-    parameters: separated_list!(
-      ws!(tag!(",")),
-      call!(variable_name)) >>
-    ws!(tag!(")")) >>
-    ws!(tag!("=")) >>
-    expression: call!(expression) >>
-    ws!(tag!(";")) >>
-    (name, parameters, expression)));
+    parameters: call!(parameter_names) >>
+    return_error!(ErrorKind::ExpectedClosingParen.code(), call!(token, Token::CloseParen)) >>
+    (parameters)));
+
+// `import "path";`, after the `import` at `start`.
+fn import(i: &[Lexeme], start: Span) -> IResult<&[Lexeme], Statement> {
+  do_parse!(i,
+    path: return_error!(ErrorKind::ExpectedPath.code(), call!(string)) >>
+    namespace: return_error!(ErrorKind::InvalidNamespace.code(),
+      expr_opt!(namespace(&path))) >>
+    end: return_error!(ErrorKind::ExpectedSemicolon.code(), call!(token, Token::Semicolon)) >>
+    (Statement::Import(path, namespace, start.to(end))))
+}
+
+fn string(i: &[Lexeme]) -> IResult<&[Lexeme], String> {
+    match i.first() {
+        Some(&Lexeme { token: Token::String(ref s), .. }) => IResult::Done(&i[1..], s.clone()),
+        _ => IResult::Error(error_position!(nom::ErrorKind::Tag, i)),
+    }
+}
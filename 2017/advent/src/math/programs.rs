@@ -0,0 +1,40 @@
+// Programs that exercise every feature of the language, along with the ways
+// it can fail. Each alternative backend and transformation is tested by
+// checking that it agrees with the executor on all of them.
+pub const PROGRAMS: &[&str] = &[
+    "a = 1 + 3 * 6;\nb = (1 + 3) * 6;\nc = 8 - 4 - 2;",
+    "a = 1 + 89 / 9;\na(i, j) = 5 * i - j;\nb = 5 * a(5 * -3, 2) + 5;",
+    "a = 1 < 2;\nb = 2 <= 1;\nc = 3 == 3;\nd = 3 != 3;\ne = 4 > 5;\nf = 5 >= 5;",
+    "a = if 0 then 1 / 0 else 2;\nb = if a then 3 else 1 / 0;",
+    "fact(n) = if n <= 1 then 1 else n * fact(n - 1);\na = fact(20);",
+    "fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2);\na = fib(18);",
+    "gcd(a, b) = if b == 0 then a else gcd(b, a - b * (a / b));\nc = gcd(1071, 462);",
+    "a = 2;\nf(x) = x * a;\na = 10;\nb = f(3);\nf(x) = x * a;\nc = f(3);",
+    "x = 100;\nf(x) = x + 1;\ng(y) = f(y * 2) + y;\nb = g(x);",
+    "f(x, x) = x;\na = f(1, 2);",
+    "g(x) = f(x) + 1;\nf(x) = x * 2;\na = g(3);",
+    "even(n) = if n == 0 then 1 else odd(n - 1);\nodd(n) = if n == 0 then 0 else even(n - 1);\na = even(100);",
+    "a = b + 1;",
+    "a = 1;\nb = f(a);",
+    "a = 1;\nb = a(2);",
+    "f(x) = x(1);\na = f(2);",
+    "g = 2;\nf(x) = g + g(x);\ng(x) = x;\na = f(1);",
+    "f(i, j) = i + j;\na = 1;\nb = f(1);",
+    "f(i) = 10 / i;\na = f(2);\nb = f(a - 5);",
+    "a = 9223372036854775807 + 1;",
+    "a = -9223372036854775808;\nb = a / -1;",
+    "f(x) = x + a;\na = 1;\nb = f(1);",
+    "f(x) = x + y;\ng(y) = f(y);\na = g(1);",
+    "f(n) = f(n + 1);\na = f(0);",
+    "f(x) = g(1 / 0);\na = f(1);",
+    "f(x) = 1;\na = f(g(1), 2);",
+    "f(n) = if n == 0 then 0 else 1 + f(n - 1);\na = f(255);\nb = f(256);",
+    "a = abs(-3) + min(4, 2) * max(4, 2);\nb = pow(2, 10) + sqrt(17) + floor(9);\nc = mod(-7, 3) + gcd(-12, 18);",
+    "abs(x) = 0;\na = abs(-3);\nf(x) = sqrt(x) + pow(x, 2);\nb = f(9);",
+    "a = 2.0 * 3;\nb = 2.5;",
+    "a = min(1);",
+    "a = mod(1, 0);",
+    "a = pow(2, 64);\nb = sqrt(-1);",
+    "a = sqrt(-1);",
+    "# Comments and tightly packed operators.\na = 5-3; # a is 2\nb = a*(a+1)-f(a);",
+];
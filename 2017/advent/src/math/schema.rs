@@ -0,0 +1,336 @@
+//! Serialization of math ASTs, with the `serde` feature.
+//!
+//! The format is meant for exchanging programs with other tools, so it is kept
+//! stable. In JSON, every node is an object whose `type` says what it is:
+//!
+//! ```text
+//! statement  := {"type": "assign", "name": name, "value": expression}
+//!             | {"type": "define", "name": name, "params": [name, ...], "body": expression}
+//!             | {"type": "import", "path": <string>}
+//! expression := operand
+//!             | {"type": "operation", "operator": operator, "lhs": expression, "rhs": expression}
+//! operand    := {"type": "integer", "value": <64-bit signed integer>}
+//!             | {"type": "float", "value": <number>}
+//!             | {"type": "variable", "name": name}
+//!             | {"type": "call", "name": name, "args": [expression, ...]}
+//!             | {"type": "apply", "callee": expression, "args": [expression, ...]}
+//!             | {"type": "if", "condition": expression, "then": expression, "else": expression}
+//!             | {"type": "lambda", "params": [name, ...], "body": expression}
+//!             | {"type": "list", "items": [expression, ...]}
+//! operator   := "+" | "-" | "*" | "/" | "==" | "!=" | "<" | "<=" | ">" | ">="
+//! name       := a string that math source accepts as a name, such as "rate_2"
+//! ```
+//!
+//! So `a = f(1) * 2;` is
+//!
+//! ```text
+//! {"type": "assign", "name": "a", "value": {
+//!     "type": "operation", "operator": "*",
+//!     "lhs": {"type": "call", "name": "f", "args": [{"type": "integer", "value": 1}]},
+//!     "rhs": {"type": "integer", "value": 2}}}
+//! ```
+//!
+//! Spans are not part of the format, just as they are not part of what a
+//! program means, and deserialized nodes have `Span::default()`. Fields other
+//! than the ones listed are rejected.
+
+use super::*;
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+// Borrows a node for serializing, in the shape of the schema.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum NodeRef<'a> {
+    Assign {
+        name: &'a Name,
+        value: &'a Expression,
+    },
+    Define {
+        name: &'a Name,
+        params: &'a [Name],
+        body: &'a Expression,
+    },
+    Import {
+        path: &'a str,
+    },
+    Operation {
+        operator: Operator,
+        lhs: &'a Expression,
+        rhs: &'a Expression,
+    },
+    Integer {
+        value: i64,
+    },
+    Float {
+        value: f64,
+    },
+    Variable {
+        name: &'a Name,
+    },
+    Call {
+        name: &'a Name,
+        args: &'a [Expression],
+    },
+    Apply {
+        callee: &'a Expression,
+        args: &'a [Expression],
+    },
+    If {
+        condition: &'a Expression,
+        then: &'a Expression,
+        #[serde(rename = "else")]
+        alternative: &'a Expression,
+    },
+    Lambda {
+        params: &'a [Name],
+        body: &'a Expression,
+    },
+    List {
+        items: &'a [Expression],
+    },
+}
+
+// The same shape, owned, for deserializing. Each kind of node accepts only the
+// types that it can be.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum StatementNode {
+    Assign {
+        name: Name,
+        value: Expression,
+    },
+    Define {
+        name: Name,
+        params: Vec<Name>,
+        body: Expression,
+    },
+    Import {
+        path: String,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ExpressionNode {
+    Operation {
+        operator: Operator,
+        lhs: Box<Expression>,
+        rhs: Box<Expression>,
+    },
+    Integer {
+        value: i64,
+    },
+    Float {
+        value: f64,
+    },
+    Variable {
+        name: Name,
+    },
+    Call {
+        name: Name,
+        args: Vec<Expression>,
+    },
+    Apply {
+        callee: Box<Expression>,
+        args: Vec<Expression>,
+    },
+    If {
+        condition: Box<Expression>,
+        then: Box<Expression>,
+        #[serde(rename = "else")]
+        alternative: Box<Expression>,
+    },
+    Lambda {
+        params: Vec<Name>,
+        body: Box<Expression>,
+    },
+    List {
+        items: Vec<Expression>,
+    },
+}
+
+fn operand_ref(operand: &Operand) -> NodeRef<'_> {
+    match *operand {
+        Operand::I64(value) => NodeRef::Integer { value },
+        Operand::F64(value) => NodeRef::Float { value },
+        Operand::VarSubstitution(ref name) => NodeRef::Variable { name },
+        Operand::FnApplication(ref name, ref args) => NodeRef::Call { name, args },
+        Operand::Call(ref callee, ref args) => NodeRef::Apply { callee, args },
+        Operand::If(ref condition, ref consequent, ref alternative) => NodeRef::If {
+            condition,
+            then: consequent,
+            alternative,
+        },
+        Operand::Lambda(ref params, ref body) => NodeRef::Lambda { params, body },
+        Operand::List(ref items) => NodeRef::List { items },
+    }
+}
+
+impl Serialize for Statement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Statement::VarAssignment(ref name, ref value, _) => {
+                NodeRef::Assign { name, value }.serialize(serializer)
+            }
+            Statement::FnDefinition(ref name, ref params, ref body, _) => {
+                NodeRef::Define { name, params, body }.serialize(serializer)
+            }
+            Statement::Import(ref path, _, _) => NodeRef::Import { path }.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Statement {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Statement, D::Error> {
+        Ok(match StatementNode::deserialize(deserializer)? {
+            StatementNode::Assign { name, value } => {
+                Statement::VarAssignment(name, value, Span::default())
+            }
+            StatementNode::Define { name, params, body } => {
+                Statement::FnDefinition(name, params, body, Span::default())
+            }
+            StatementNode::Import { path } => match parser::namespace(&path) {
+                Some(namespace) => Statement::Import(path, namespace, Span::default()),
+                None => {
+                    let message = parser::ErrorKind::InvalidNamespace.message();
+                    return Err(de::Error::custom(format!("cannot import `{}`: {}", path, message)));
+                }
+            },
+        })
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Expression::Operand(ref operand, _) => operand.serialize(serializer),
+            Expression::Operation(ref lhs, operator, ref rhs, _) => {
+                NodeRef::Operation { operator, lhs, rhs }.serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expression, D::Error> {
+        let operand = match ExpressionNode::deserialize(deserializer)? {
+            ExpressionNode::Operation { operator, lhs, rhs } => {
+                return Ok(Expression::Operation(lhs, operator, rhs, Span::default()));
+            }
+            ExpressionNode::Integer { value } => Operand::I64(value),
+            ExpressionNode::Float { value } => Operand::F64(value),
+            ExpressionNode::Variable { name } => Operand::VarSubstitution(name),
+            ExpressionNode::Call { name, args } => Operand::FnApplication(name, args),
+            ExpressionNode::Apply { callee, args } => Operand::Call(callee, args),
+            ExpressionNode::If {
+                condition,
+                then,
+                alternative,
+            } => Operand::If(condition, then, alternative),
+            ExpressionNode::Lambda { params, body } => Operand::Lambda(params, body),
+            ExpressionNode::List { items } => Operand::List(items),
+        };
+        Ok(Expression::Operand(operand, Span::default()))
+    }
+}
+
+impl Serialize for Operand {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        operand_ref(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Operand {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Operand, D::Error> {
+        match Expression::deserialize(deserializer)? {
+            Expression::Operand(operand, _) => Ok(operand),
+            Expression::Operation(..) => Err(de::Error::custom("an operation is not an operand")),
+        }
+    }
+}
+
+impl Serialize for Operator {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.symbol())
+    }
+}
+
+impl<'de> Deserialize<'de> for Operator {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Operator, D::Error> {
+        let symbol = String::deserialize(deserializer)?;
+        Operator::ALL
+            .iter()
+            .cloned()
+            .find(|operator| operator.symbol() == symbol)
+            .ok_or_else(|| de::Error::custom(format!("`{}` is not an operator", symbol)))
+    }
+}
+
+impl Serialize for Name {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Name {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Name, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        parser::parse_name(&name)
+            .map_err(|e| de::Error::custom(format!("`{}` is not a name: {}", name, e.kind.message())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn schema_test() {
+        let statements = parser::parse(b"a = f(1) * 2;\ng(x, y) = if x < 2.5 then y else -1;").unwrap();
+        assert_eq!(
+            serde_json::to_string(&statements).unwrap(),
+            concat!(
+                r#"[{"type":"assign","name":"a","value":{"type":"operation","operator":"*","#,
+                r#""lhs":{"type":"call","name":"f","args":[{"type":"integer","value":1}]},"#,
+                r#""rhs":{"type":"integer","value":2}}},"#,
+                r#"{"type":"define","name":"g","params":["x","y"],"body":{"type":"if","#,
+                r#""condition":{"type":"operation","operator":"<","lhs":{"type":"variable","name":"x"},"#,
+                r#""rhs":{"type":"float","value":2.5}},"then":{"type":"variable","name":"y"},"#,
+                r#""else":{"type":"integer","value":-1}}}]"#
+            )
+        );
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let lambdas = "a = fold(\\sum, x -> sum + x, 0, [1, 2]);";
+        let imports = "import \"lib/geometry.math\";\na = geometry.area(2);";
+        let calls = "a = f(1)(2) + (\\x -> x)(3);";
+        for program in ::math::programs::PROGRAMS.iter().chain(&[lambdas, imports, calls]) {
+            let statements = parser::parse(program.as_bytes()).unwrap();
+            let json = serde_json::to_string(&statements).unwrap();
+            assert_eq!(serde_json::from_str::<Vec<Statement>>(&json).unwrap(), statements);
+        }
+    }
+
+    #[test]
+    fn invalid_test() {
+        let invalid = [
+            r#"{"type":"assign","name":"if","value":{"type":"integer","value":1}}"#,
+            r#"{"type":"assign","name":"a b","value":{"type":"integer","value":1}}"#,
+            r#"{"type":"assign","name":"a","value":{"type":"integer","value":1.5}}"#,
+            r#"{"type":"assign","name":"a","value":{"type":"integer","value":1,"span":0}}"#,
+            r#"{"type":"assign","name":"a","value":{"type":"operation","operator":"%","lhs":{"type":"integer","value":1},"rhs":{"type":"integer","value":1}}}"#,
+            r#"{"type":"define","name":"a","value":{"type":"integer","value":1}}"#,
+            r#"{"type":"integer","value":1}"#,
+            r#"{"type":"import","path":"lib/2d.math"}"#,
+        ];
+        for json in &invalid {
+            assert!(serde_json::from_str::<Statement>(json).is_err(), "{}", json);
+        }
+        assert!(serde_json::from_str::<Operand>(r#"{"type":"variable","name":"x"}"#).is_ok());
+    }
+}
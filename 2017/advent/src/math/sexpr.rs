@@ -0,0 +1,130 @@
+use super::*;
+
+/// Formats a program as S-expressions, one statement per line, for tools that
+/// would rather not parse infix:
+///
+/// ```text
+/// (define a (* (f 1) 2))
+/// (define (g x y) (if (< x 2.5) y -1))
+/// ```
+///
+/// Every operation and call is parenthesised with its operator or function
+/// first, a call with no args being `(f)` and a call of what an expression
+/// gives being `((f 1) 2)`. A lambda is `(\\ (x y) body)`, a list is
+/// `[1 2 3]` and an import is `(import "lib/geometry.math")`.
+/// `parser::parse_sexpr` reads the result back as the same AST, as long as
+/// that is no more than `parser::MAX_NESTING` deep.
+pub fn format_sexpr(statements: &[Statement]) -> String {
+    statements.iter().map(|s| format!("{}\n", Sexpr(s))).collect()
+}
+
+struct Sexpr<'a, T: 'a>(&'a T);
+
+impl<'a> fmt::Display for Sexpr<'a, Statement> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            Statement::VarAssignment(ref name, ref expr, _) => {
+                write!(f, "(define {} {})", name, Sexpr(expr))
+            }
+            Statement::FnDefinition(ref name, ref params, ref body, _) => {
+                write!(f, "(define ({}", name)?;
+                for param in params {
+                    write!(f, " {}", param)?;
+                }
+                write!(f, ") {})", Sexpr(body))
+            }
+            Statement::Import(ref path, _, _) => write!(f, "(import \"{}\")", path),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Sexpr<'a, Expression> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            Expression::Operand(ref operand, _) => write!(f, "{}", Sexpr(operand)),
+            Expression::Operation(ref lhs, operator, ref rhs, _) => {
+                write!(f, "({} {} {})", operator, Sexpr(&**lhs), Sexpr(&**rhs))
+            }
+        }
+    }
+}
+
+impl<'a> fmt::Display for Sexpr<'a, Operand> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            Operand::I64(value) => write!(f, "{}", value),
+            Operand::F64(value) => format::write_float(f, value),
+            Operand::VarSubstitution(ref name) => write!(f, "{}", name),
+            Operand::FnApplication(ref name, ref args) => {
+                write!(f, "({}", name)?;
+                for arg in args {
+                    write!(f, " {}", Sexpr(arg))?;
+                }
+                f.write_str(")")
+            }
+            Operand::Call(ref callee, ref args) => {
+                write!(f, "({}", Sexpr(&**callee))?;
+                for arg in args {
+                    write!(f, " {}", Sexpr(arg))?;
+                }
+                f.write_str(")")
+            }
+            Operand::If(ref condition, ref consequent, ref alternative) => write!(
+                f,
+                "(if {} {} {})",
+                Sexpr(&**condition),
+                Sexpr(&**consequent),
+                Sexpr(&**alternative)
+            ),
+            Operand::Lambda(ref params, ref body) => {
+                f.write_str("(\\ (")?;
+                for (i, param) in params.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { " " } else { "" }, param)?;
+                }
+                write!(f, ") {})", Sexpr(&**body))
+            }
+            Operand::List(ref items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { " " } else { "" }, Sexpr(item))?;
+                }
+                f.write_str("]")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::format::tests::Rng;
+
+    #[test]
+    fn format_sexpr_test() {
+        let statements = parser::parse(b"a = f(1) * 2;\ng(x, y) = if x < 2.5 then y else -1;\nh() = g();\n\
+                                          b = map(\\x, y -> x, [1, [], 2 + 3]);\nc = f(1)((\\ -> g)())();").unwrap();
+        assert_eq!(
+            format_sexpr(&statements),
+            "(define a (* (f 1) 2))\n\
+             (define (g x y) (if (< x 2.5) y -1))\n\
+             (define (h) (g))\n\
+             (define b (map (\\ (x y) x) [1 [] (+ 2 3)]))\n\
+             (define c (((f 1) ((\\ () g)))))\n"
+        );
+    }
+
+    #[test]
+    fn round_trip_property_test() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let statements: Vec<_> = (0..3).map(|_| rng.statement()).collect();
+            let formatted = format_sexpr(&statements);
+            assert_eq!(
+                parser::parse_sexpr(formatted.as_bytes()),
+                Ok(statements),
+                "{:?} did not round trip",
+                formatted
+            );
+        }
+    }
+}
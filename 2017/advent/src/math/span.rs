@@ -0,0 +1,138 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// A byte range within a math source, along with the 1-based line and column
+/// at which it starts.
+///
+/// Spans are position metadata rather than part of a program's meaning, so
+/// every span compares equal to every other. That keeps `==` on ASTs structural:
+/// the same program parsed from differently formatted sources is still equal.
+/// Nodes built by hand rather than parsed use `Span::default()`, whose line is 0.
+#[derive(Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(source: &[u8], start: usize, end: usize) -> Span {
+        let before = &source[..start];
+        let line_start = before
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        Span {
+            start,
+            end,
+            line: 1 + before.iter().filter(|&&b| b == b'\n').count(),
+            column: 1 + start - line_start,
+        }
+    }
+
+    /// The span running from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end, ..self }
+    }
+
+    /// Quotes the source line that the span starts on beneath `heading`, in the
+    /// style of rustc, underlining the span's part of it. A span that is not
+    /// within `source`, such as `Span::default()`, gives just the heading, and
+    /// only the part of the line within `source` is quoted when `source` starts
+    /// partway through it.
+    pub fn render(&self, source: &[u8], heading: &str) -> String {
+        self.quote(source, &format!("{}:{}", self.line, self.column), heading)
+    }
+
+    /// Like `render`, but also names the file at `path` that `source` is.
+    pub fn render_in(&self, source: &[u8], path: &str, heading: &str) -> String {
+        self.quote(source, &format!("{}:{}:{}", path, self.line, self.column), heading)
+    }
+
+    fn quote(&self, source: &[u8], location: &str, heading: &str) -> String {
+        if self.line == 0 || self.column == 0 || self.start > source.len() {
+            return format!("{}\n", heading);
+        }
+        let line_start = (self.start + 1).saturating_sub(self.column);
+        let line_end = source[line_start..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+            .map(|i| line_start + i)
+            .unwrap_or(source.len());
+        let line = String::from_utf8_lossy(&source[line_start..line_end]);
+        let indent: String = source[line_start..self.start]
+            .iter()
+            .map(|&b| if b == b'\t' { '\t' } else { ' ' })
+            .collect();
+        let underline_end = if self.end > line_end { line_end } else { self.end };
+        let underline = if underline_end > self.start {
+            underline_end - self.start
+        } else {
+            1
+        };
+
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        format!(
+            "{}\n{}--> {}\n{} |\n{} | {}\n{} | {}{}\n",
+            heading,
+            gutter,
+            location,
+            gutter,
+            line_number,
+            line,
+            gutter,
+            indent,
+            "^".repeat(underline)
+        )
+    }
+}
+
+// Spans are printed compactly so that they do not drown out the structure of
+// the ASTs that carry them.
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}@{}:{}", self.start, self.end, self.line, self.column)
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _: &Span) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl Hash for Span {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_and_column_test() {
+        let source = b"a = 1;\nbc = 2;";
+        let span = Span::new(source, 12, 13);
+        assert_eq!((span.line, span.column), (2, 6));
+        let span = Span::new(source, 0, 1);
+        assert_eq!((span.line, span.column), (1, 1));
+    }
+
+    #[test]
+    fn render_test() {
+        let source = b"a = 1;\nbc = x;";
+        assert_eq!(
+            Span::new(source, 12, 13).render(source, "error: unknown variable"),
+            "error: unknown variable\n --> 2:6\n  |\n2 | bc = x;\n  |      ^\n"
+        );
+        assert_eq!(Span::default().render(source, "error"), "error\n");
+        assert_eq!(Span::new(source, 12, 13).render(b"a;", "error"), "error\n");
+        let span = Span { start: 2, end: 3, line: 2, column: 6 };
+        assert_eq!(span.render(b"= x;", "error"), "error\n --> 2:6\n  |\n2 | = x;\n  |   ^\n");
+    }
+}
@@ -0,0 +1,313 @@
+use super::*;
+use super::executor::Executor;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Reads statements from `reader`, yielding each as soon as its `;` has been
+/// read rather than waiting for the whole program. Only the input since the
+/// previous statement is kept, so `reader` can be an unbounded stream.
+pub fn statements<R: Read>(reader: R) -> Statements<R> {
+    Statements {
+        reader,
+        source: vec![],
+        line: 1,
+        column: 1,
+        consumed: 0,
+        scanned: 0,
+        in_comment: false,
+        finished: false,
+    }
+}
+
+/// Runs each statement from `reader` as soon as it is complete, yielding the
+/// value of every assignment as it is made.
+pub fn interpret_stream<R: Read, N: Number>(reader: R) -> Assignments<R, N> {
+    Assignments {
+        statements: statements(reader),
+        executor: Executor::new(),
+        loader: None,
+    }
+}
+
+pub struct Statements<R> {
+    reader: R,
+    // The input from the end of the statement before the previous one, which
+    // starts at `line` and `column`, of which the previous statement used the
+    // first `consumed` bytes.
+    source: Vec<u8>,
+    line: usize,
+    column: usize,
+    consumed: usize,
+    // How far the search for the next `;` has got, and whether that is within
+    // a comment.
+    scanned: usize,
+    in_comment: bool,
+    finished: bool,
+}
+
+impl<R: Read> Statements<R> {
+    /// The input that the spans of the latest statement or error point into,
+    /// for `Error::render`. It need not start at the start of a line, so what
+    /// comes before the statement on its first line may not be quoted.
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    // Forgets the input that earlier statements used, noting where what is
+    // left of it starts.
+    fn discard_consumed(&mut self) {
+        let consumed = &self.source[..self.consumed];
+        match consumed.iter().rposition(|&b| b == b'\n') {
+            Some(i) => {
+                self.line += consumed.iter().filter(|&&b| b == b'\n').count();
+                self.column = self.consumed - i;
+            }
+            None => self.column += self.consumed,
+        }
+        self.source.drain(..self.consumed);
+        self.scanned -= self.consumed;
+        self.consumed = 0;
+    }
+
+    // The position of the next `;`, skipping any in comments.
+    fn find_end(&mut self) -> Option<usize> {
+        while self.scanned < self.source.len() {
+            let b = self.source[self.scanned];
+            self.scanned += 1;
+            match b {
+                b'\n' => self.in_comment = false,
+                b'#' => self.in_comment = true,
+                b';' if !self.in_comment => return Some(self.scanned - 1),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // Parses the input up to `end`.
+    fn parse(&mut self, end: usize) -> Result<Statement, Error> {
+        self.consumed = end;
+        let statements = parser::parse_at(&self.source[..end], self.line, self.column).map_err(Error::ParseError)?;
+        Ok(statements.into_iter().next().expect("input up to a `;` is not blank"))
+    }
+}
+
+impl<R: Read> Iterator for Statements<R> {
+    type Item = Result<Statement, Error>;
+
+    fn next(&mut self) -> Option<Result<Statement, Error>> {
+        self.discard_consumed();
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(end) = self.find_end() {
+                return Some(self.parse(end + 1));
+            }
+            if self.finished {
+                // Whatever follows the last `;` must be blank, or it is an
+                // unfinished statement.
+                let rest = &self.source[self.consumed..];
+                return match parser::tokenize(rest) {
+                    Ok(ref tokens) if tokens.is_empty() => None,
+                    _ => {
+                        let end = self.source.len();
+                        Some(self.parse(end))
+                    }
+                };
+            }
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.finished = true,
+                Ok(n) => self.source.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.finished = true;
+                    self.consumed = self.source.len();
+                    return Some(Err(Error::ReadError(e.to_string())));
+                }
+            }
+        }
+    }
+}
+
+/// Statements that fail to parse or to run are reported and then skipped, so
+/// one mistake does not end the stream. Only assignments of numbers are
+/// yielded.
+pub struct Assignments<R, N = i64> {
+    pub statements: Statements<R>,
+    pub executor: Executor<N>,
+    /// Loads what imports import, looking for it in the current directory
+    /// first. Without one, imports fail to run.
+    pub loader: Option<import::Loader>,
+}
+
+impl<R: Read, N: Number> Iterator for Assignments<R, N> {
+    type Item = Result<(Name, N), Error>;
+
+    fn next(&mut self) -> Option<Result<(Name, N), Error>> {
+        loop {
+            let statement = match self.statements.next()? {
+                Ok(statement) => statement,
+                Err(e) => return Some(Err(e)),
+            };
+            if let (&Statement::Import(..), Some(ref loader)) = (&statement, &self.loader) {
+                let source = self.statements.source();
+                match loader.load_statement(statement, source, Path::new("")) {
+                    Ok(statements) => match self.executor.run(statements) {
+                        Ok(()) => continue,
+                        Err(e) => return Some(Err(Error::ExecutionError(e))),
+                    },
+                    Err(e) => return Some(Err(Error::ImportError(e))),
+                }
+            }
+            let assigned = match statement {
+                Statement::VarAssignment(ref name, _, _) => Some(name.clone()),
+                Statement::FnDefinition(..) | Statement::Import(..) => None,
+            };
+            if let Err(e) = self.executor.execute(statement) {
+                return Some(Err(Error::ExecutionError(e)));
+            }
+            if let Some(name) = assigned {
+                // Assignments of functions and lists have no number to yield.
+                if let Some(value) = self.executor.variables.get(&name) {
+                    return Some(Ok((name, value.clone())));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::name;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Hands out its chunks one per read, counting how many it has.
+    struct Chunks {
+        chunks: Vec<&'static [u8]>,
+        read: Rc<Cell<usize>>,
+    }
+
+    impl Read for Chunks {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.chunks.get(self.read.get()) {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            self.read.set(self.read.get() + 1);
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn incremental_test() {
+        let read = Rc::new(Cell::new(0));
+        let chunks = Chunks {
+            chunks: vec![b"a = 1", b"0; f(x) = x", b" * a; # not ; yet\n", b"b = f(2);", b"c = "],
+            read: read.clone(),
+        };
+        let mut assignments = interpret_stream::<_, i64>(chunks);
+        assert_eq!(assignments.next(), Some(Ok((name("a"), 10))));
+        assert_eq!(read.get(), 2);
+        assert_eq!(assignments.next(), Some(Ok((name("b"), 20))));
+        assert_eq!(read.get(), 4);
+        match assignments.next() {
+            Some(Err(Error::ParseError(e))) => {
+                assert_eq!(e.kind, parser::ErrorKind::ExpectedExpression);
+                assert_eq!((e.span.line, e.span.column), (2, 13));
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert_eq!(assignments.next(), None);
+    }
+
+    #[test]
+    fn spans_test() {
+        let source = b"a = 1;\n\n  b = 2; c = 3\n  + ;\nd = 4;";
+        let mut statements = statements(&source[..]);
+        statements.next();
+        let span = statements.next().unwrap().unwrap().span();
+        assert_eq!((span.line, span.column), (3, 3));
+        match statements.next() {
+            Some(Err(e)) => assert_eq!(
+                e.render(statements.source()),
+                "error: expected an expression\n --> 4:5\n  |\n4 |   + ;\n  |     ^\n"
+            ),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        let span = statements.next().unwrap().unwrap().span();
+        assert_eq!((span.line, span.column), (5, 1));
+        assert!(statements.next().is_none());
+    }
+
+    #[test]
+    fn single_line_test() {
+        let source: String = (0..20_000).map(|i| format!("v{} = {}; ", i, i)).collect();
+        let mut streamed = statements(source.as_bytes());
+        let span = streamed.nth(19_999).unwrap().unwrap().span();
+        assert_eq!((span.line, span.column), (1, source.len() - 15));
+        // Only what is left of the line after the statements so far is kept.
+        assert!(streamed.source().len() < 4096 * 2);
+        assert!(streamed.next().is_none());
+
+        let mut statements = statements(&b"a = 1;  b = 2 + ;"[..]);
+        statements.next();
+        match statements.next() {
+            Some(Err(e)) => assert_eq!(
+                e.render(statements.source()),
+                "error: expected an expression\n --> 1:17\n  |\n1 |   b = 2 + ;\n  |           ^\n"
+            ),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_are_skipped_test() {
+        let source = b"a = 1 +;\nb = 1 / 0;\nc = 3;\n";
+        let results: Vec<_> = interpret_stream::<_, i64>(&source[..]).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_err() && results[1].is_err());
+        assert_eq!(results[2], Ok((name("c"), 3)));
+    }
+
+    #[test]
+    fn imports_test() {
+        let directory = ::std::env::temp_dir().join(format!("math-stream-imports-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&directory).unwrap();
+        ::std::fs::write(directory.join("geometry.math"), "pi = 3;\narea(r) = pi * r * r;").unwrap();
+        let source = b"import \"geometry.math\";\na = geometry.area(2);\nimport \"shapes.math\";\nb = a + 1;\n";
+
+        let mut assignments = interpret_stream::<_, i64>(&source[..]);
+        assignments.loader = Some(import::Loader {
+            search_path: vec![directory.clone()],
+        });
+        assert_eq!(assignments.next(), Some(Ok((name("a"), 12))));
+        match assignments.next() {
+            Some(Err(Error::ImportError(e))) => {
+                assert_eq!(
+                    e.render(),
+                    "error: cannot find `shapes.math` to import\n --> 3:1\n  |\n3 | import \"shapes.math\";\n  | ^^^^^^^^^^^^^^^^^^^^^\n"
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(assignments.next(), Some(Ok((name("b"), 13))));
+
+        // Without a loader, imports cannot run.
+        let results: Vec<_> = interpret_stream::<_, i64>(&source[..]).collect();
+        assert_eq!(results[0], Err(Error::ExecutionError(executor::Error::UnresolvedImport(name("geometry")))));
+        ::std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Streaming any program gives the same results as interpreting it.
+    #[test]
+    fn programs_test() {
+        for program in ::math::programs::PROGRAMS {
+            let streamed = interpret_stream::<_, i64>(program.as_bytes())
+                .collect::<Result<Vec<_>, _>>()
+                .map(|_| ());
+            assert_eq!(streamed, interpret(program.as_bytes()).map(|_| ()), "{:?}", program);
+        }
+    }
+}
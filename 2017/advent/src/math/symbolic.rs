@@ -0,0 +1,476 @@
+//! Algebra on function definitions: differentiation and simplification.
+
+use super::*;
+use super::executor::evaluate_operation;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// (statement) that assigns a variable rather than defining a function.
+    NotAFunction(Name),
+    /// (function, name that is not one of its params)
+    NotAParam(Name, Name),
+    /// (function whose derivative is not known) called with args that depend
+    /// on the param.
+    NotDifferentiable(Name),
+    /// A lambda or a list where a number was expected.
+    NotNumeric,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotAFunction(ref statement) => write!(f, "`{}` is not a function", statement),
+            Error::NotAParam(ref function, ref param) => {
+                write!(f, "`{}` is not a param of `{}`", param, function)
+            }
+            Error::NotDifferentiable(ref function) => {
+                write!(f, "cannot differentiate calls to `{}`", function)
+            }
+            Error::NotNumeric => f.write_str("only numbers can be differentiated"),
+        }
+    }
+}
+
+/// The partial derivative of a function definition with respect to one of its
+/// params, as the definition of a function `d_<function>_<param>` with the
+/// same params. Its body is simplified by `simplify`.
+///
+/// Calls are differentiated by the chain rule when they are to builtins whose
+/// derivatives are known: `abs`, `min`, `max`, `sqrt`, `floor`, `mod` with a
+/// divisor that does not depend on the param, and `pow` with an exponent that
+/// does not. A call to any other function is only allowed if its args do not
+/// depend on the param, since its result is then constant. This takes every
+/// function called to be a builtin, as it cannot see the rest of the program,
+/// unlike the `d` builtin, which also differentiates through the functions
+/// that the program defines.
+///
+/// Comparisons count as constant and an `if` is differentiated branch by
+/// branch, so the derivative is wrong exactly where the function is not smooth.
+pub fn derive(function: &Statement, param: &Name) -> Result<Statement, Error> {
+    match *function {
+        Statement::FnDefinition(ref name, ref params, ref body, span) => {
+            let body = derive_body(name, params, body, param, &|_| Callee::Builtin)?;
+            let name = Name(format!("d_{}_{}", name, param));
+            Ok(Statement::FnDefinition(name, params.clone(), body, span))
+        }
+        Statement::VarAssignment(ref name, _, _) | Statement::Import(_, ref name, _) => {
+            Err(Error::NotAFunction(name.clone()))
+        }
+    }
+}
+
+/// What a name that a function body calls refers to.
+pub(crate) enum Callee {
+    Builtin,
+    /// A function that the program defines, with these params, whose partial
+    /// derivatives `d` can evaluate.
+    Function(Vec<Name>),
+    /// Anything else, such as a lambda held by a variable.
+    Other,
+}
+
+// `derive`, given the parts of the definition. `callee` says what each name
+// that is called refers to.
+//
+// The chain rule takes a call `g(a, b)` to a function the program defines to
+// `d(g, p, a, b) * a' + d(g, q, a, b) * b'`, where `p` and `q` are the params
+// of `g`, rather than inlining the derivative of `g`'s body. That keeps the
+// names in the body of `g` meaning what they meant where `g` was defined, and
+// recursive functions are differentiated only as deeply as they are called.
+pub(crate) fn derive_body(
+    function: &Name,
+    params: &[Name],
+    body: &Expression,
+    param: &Name,
+    callee: &dyn Fn(&Name) -> Callee,
+) -> Result<Expression, Error> {
+    if !params.contains(param) {
+        return Err(Error::NotAParam(function.clone(), param.clone()));
+    }
+    let derivative = Derivative { param, callee };
+    Ok(simplify(derivative.expression(body)?))
+}
+
+/// The function, param and args of a call like `d(f, x)` or `d(f, x, 1, 2)`,
+/// which evaluates the partial derivative of `f` with respect to `x`. The args
+/// are `f`'s, and when there are none, `f`'s params take the values of the
+/// variables of the same names where `d` is called. So `g(x) = d(f, x);`
+/// defines `g` as the derivative of `f(x)`.
+pub(crate) fn derivative_call<'a>(
+    name: &Name,
+    args: &'a [Expression],
+) -> Option<(&'a Name, &'a Name, &'a [Expression])> {
+    if name.as_str() != "d" || args.len() < 2 {
+        return None;
+    }
+    match (&args[0], &args[1]) {
+        (
+            &Expression::Operand(Operand::VarSubstitution(ref function), _),
+            &Expression::Operand(Operand::VarSubstitution(ref param), _),
+        ) => Some((function, param, &args[2..])),
+        _ => None,
+    }
+}
+
+struct Derivative<'a> {
+    param: &'a Name,
+    callee: &'a dyn Fn(&Name) -> Callee,
+}
+
+impl<'a> Derivative<'a> {
+    fn expression(&self, expr: &Expression) -> Result<Expression, Error> {
+        let (lhs, operator, rhs) = match *expr {
+            Expression::Operand(ref operand, _) => return self.operand(operand),
+            Expression::Operation(ref lhs, operator, ref rhs, _) => (&**lhs, operator, &**rhs),
+        };
+        Ok(match operator {
+            Operator::Add | Operator::Subtract => {
+                operation(self.expression(lhs)?, operator, self.expression(rhs)?)
+            }
+            // (uv)' = u'v + uv'
+            Operator::Multiply => operation(
+                operation(self.expression(lhs)?, Operator::Multiply, rhs.clone()),
+                Operator::Add,
+                operation(lhs.clone(), Operator::Multiply, self.expression(rhs)?),
+            ),
+            // (u/v)' = (u'v - uv') / v^2
+            Operator::Divide => operation(
+                operation(
+                    operation(self.expression(lhs)?, Operator::Multiply, rhs.clone()),
+                    Operator::Subtract,
+                    operation(lhs.clone(), Operator::Multiply, self.expression(rhs)?),
+                ),
+                Operator::Divide,
+                operation(rhs.clone(), Operator::Multiply, rhs.clone()),
+            ),
+            _ => integer(0),
+        })
+    }
+
+    fn operand(&self, operand: &Operand) -> Result<Expression, Error> {
+        Ok(match *operand {
+            Operand::I64(_) | Operand::F64(_) => integer(0),
+            Operand::VarSubstitution(ref name) => integer((name == self.param) as i64),
+            Operand::If(ref condition, ref consequent, ref alternative) => Expression::Operand(
+                Operand::If(
+                    condition.clone(),
+                    Box::new(self.expression(consequent)?),
+                    Box::new(self.expression(alternative)?),
+                ),
+                Span::default(),
+            ),
+            Operand::FnApplication(ref name, ref args) => return self.call(name, args),
+            // What a callee gives is not known until it is evaluated.
+            Operand::Call(ref callee, ref args) => {
+                if self.depends(callee) || args.iter().any(|arg| self.depends(arg)) {
+                    return Err(Error::NotDifferentiable(Name(callee.to_string())));
+                }
+                integer(0)
+            }
+            Operand::Lambda(..) | Operand::List(_) => return Err(Error::NotNumeric),
+        })
+    }
+
+    fn call(&self, name: &Name, args: &[Expression]) -> Result<Expression, Error> {
+        if !args.iter().any(|arg| self.depends(arg)) {
+            return Ok(integer(0));
+        }
+        match (self.callee)(name) {
+            Callee::Builtin => {}
+            Callee::Function(ref params) if params.len() == args.len() => return self.chain(name, params, args),
+            Callee::Function(_) | Callee::Other => return Err(Error::NotDifferentiable(name.clone())),
+        }
+        let d = |i: usize| self.expression(&args[i]);
+        let arg = |i: usize| args[i].clone();
+        Ok(match (name.as_str(), args.len()) {
+            ("abs", 1) => conditional(
+                operation(arg(0), Operator::Less, integer(0)),
+                operation(integer(0), Operator::Subtract, d(0)?),
+                d(0)?,
+            ),
+            // Whichever arg the builtin returns.
+            ("min", 2) => conditional(operation(arg(1), Operator::Less, arg(0)), d(1)?, d(0)?),
+            ("max", 2) => conditional(operation(arg(1), Operator::Greater, arg(0)), d(1)?, d(0)?),
+            ("sqrt", 1) => operation(
+                d(0)?,
+                Operator::Divide,
+                operation(integer(2), Operator::Multiply, call(name, vec![arg(0)])),
+            ),
+            ("floor", 1) => integer(0),
+            ("mod", 2) if !self.depends(&args[1]) => d(0)?,
+            ("pow", 2) if !self.depends(&args[1]) => operation(
+                operation(
+                    arg(1),
+                    Operator::Multiply,
+                    call(name, vec![arg(0), operation(arg(1), Operator::Subtract, integer(1))]),
+                ),
+                Operator::Multiply,
+                d(0)?,
+            ),
+            _ => return Err(Error::NotDifferentiable(name.clone())),
+        })
+    }
+
+    // The derivative of a call to `function`, which the program defines with
+    // `params`, summing over the args that depend on the param.
+    fn chain(&self, function: &Name, params: &[Name], args: &[Expression]) -> Result<Expression, Error> {
+        let mut sum: Option<Expression> = None;
+        for (param, arg) in params.iter().zip(args) {
+            if !self.depends(arg) {
+                continue;
+            }
+            let mut d_args = vec![variable(function), variable(param)];
+            d_args.extend(args.iter().cloned());
+            let term = operation(call(&Name("d".to_string()), d_args), Operator::Multiply, self.expression(arg)?);
+            sum = Some(match sum {
+                Some(sum) => operation(sum, Operator::Add, term),
+                None => term,
+            });
+        }
+        Ok(sum.unwrap_or_else(|| integer(0)))
+    }
+
+    // Whether `expr` reads the param. A function body cannot see the params
+    // of its caller, so calls only depend on it through their args.
+    fn depends(&self, expr: &Expression) -> bool {
+        match *expr {
+            Expression::Operand(Operand::I64(_), _) | Expression::Operand(Operand::F64(_), _) => false,
+            Expression::Operand(Operand::VarSubstitution(ref name), _) => name == self.param,
+            Expression::Operand(Operand::FnApplication(_, ref args), _) => {
+                args.iter().any(|arg| self.depends(arg))
+            }
+            Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+                self.depends(condition) || self.depends(consequent) || self.depends(alternative)
+            }
+            Expression::Operand(Operand::Lambda(ref params, ref body), _) => {
+                !params.contains(self.param) && self.depends(body)
+            }
+            Expression::Operand(Operand::List(ref items), _) => items.iter().any(|item| self.depends(item)),
+            Expression::Operand(Operand::Call(ref callee, ref args), _) => {
+                self.depends(callee) || args.iter().any(|arg| self.depends(arg))
+            }
+            Expression::Operation(ref lhs, _, ref rhs, _) => self.depends(lhs) || self.depends(rhs),
+        }
+    }
+}
+
+/// Rewrites an expression into a simpler one that computes the same thing, by
+/// folding constants and applying identities such as `x + 0 = x`, `x * 0 = 0`,
+/// `x - x = 0` and `x + x = 2 * x`. Constant factors are moved to the front
+/// and multiplied together, so that `x * 2 * 3` becomes `6 * x`.
+///
+/// Variables are taken to hold numbers rather than functions or lists, and
+/// arithmetic is treated as exact, so simplifying can remove an overflow. It
+/// never removes a division or a call, either of which might fail for a reason
+/// that matters, and it only folds division where that is exact.
+pub fn simplify(expr: Expression) -> Expression {
+    match expr {
+        Expression::Operand(Operand::FnApplication(name, args), span) => {
+            let args = args.into_iter().map(simplify).collect();
+            Expression::Operand(Operand::FnApplication(name, args), span)
+        }
+        Expression::Operand(Operand::If(condition, consequent, alternative), span) => {
+            let condition = simplify(*condition);
+            let consequent = simplify(*consequent);
+            let alternative = simplify(*alternative);
+            match constant(&condition) {
+                Some(0) => alternative,
+                Some(_) => consequent,
+                None if consequent == alternative && removable(&condition) => consequent,
+                None => Expression::Operand(
+                    Operand::If(Box::new(condition), Box::new(consequent), Box::new(alternative)),
+                    span,
+                ),
+            }
+        }
+        Expression::Operand(Operand::Lambda(params, body), span) => {
+            Expression::Operand(Operand::Lambda(params, Box::new(simplify(*body))), span)
+        }
+        Expression::Operand(Operand::List(items), span) => {
+            Expression::Operand(Operand::List(items.into_iter().map(simplify).collect()), span)
+        }
+        Expression::Operand(Operand::Call(callee, args), span) => {
+            let args = args.into_iter().map(simplify).collect();
+            Expression::Operand(Operand::Call(Box::new(simplify(*callee)), args), span)
+        }
+        Expression::Operation(lhs, operator, rhs, _) => {
+            simplify_operation(simplify(*lhs), operator, simplify(*rhs))
+        }
+        expr => expr,
+    }
+}
+
+// Both sides are already simplified.
+fn simplify_operation(lhs: Expression, operator: Operator, rhs: Expression) -> Expression {
+    let statement = Name(String::new());
+    match (constant(&lhs), operator, constant(&rhs)) {
+        (Some(a), _, Some(b)) => {
+            let exact = operator != Operator::Divide || (b != 0 && a.checked_rem(b) == Some(0));
+            if let (true, Ok(value)) = (exact, evaluate_operation(&statement, operator, a, b)) {
+                return integer(value);
+            }
+        }
+        (_, Operator::Add, Some(0))
+        | (_, Operator::Subtract, Some(0))
+        | (_, Operator::Multiply, Some(1))
+        | (_, Operator::Divide, Some(1)) => return lhs,
+        (Some(0), Operator::Add, _) | (Some(1), Operator::Multiply, _) => return rhs,
+        (_, Operator::Multiply, Some(0)) if removable(&lhs) => return integer(0),
+        (Some(0), Operator::Multiply, _) if removable(&rhs) => return integer(0),
+        (Some(0), Operator::Divide, _) if removable(&rhs) => {
+            return operation(integer(0), Operator::Divide, rhs)
+        }
+        // Constant factors go first, where they can be multiplied together.
+        (None, Operator::Multiply, Some(_)) => return simplify_operation(rhs, operator, lhs),
+        (Some(a), Operator::Multiply, None) => {
+            if let Expression::Operation(ref inner, Operator::Multiply, ref x, _) = rhs {
+                if let Some(product) = constant(inner).and_then(|b| a.checked_mul(b)) {
+                    return operation(integer(product), Operator::Multiply, (**x).clone());
+                }
+            }
+        }
+        _ => {}
+    }
+    if lhs == rhs && removable(&lhs) {
+        match operator {
+            Operator::Add => return simplify_operation(integer(2), Operator::Multiply, lhs),
+            Operator::Subtract => return integer(0),
+            _ => {}
+        }
+    }
+    operation(lhs, operator, rhs)
+}
+
+// Whether dropping an expression cannot lose a failure that matters: it has
+// no division and no call, and it only reads integers.
+fn removable(expr: &Expression) -> bool {
+    match *expr {
+        Expression::Operand(Operand::I64(_), _) | Expression::Operand(Operand::VarSubstitution(_), _) => {
+            true
+        }
+        // Integer number types cannot represent most floats.
+        Expression::Operand(Operand::F64(_), _) => false,
+        // Arithmetic on lambdas and lists fails.
+        Expression::Operand(Operand::FnApplication(..), _)
+        | Expression::Operand(Operand::Call(..), _)
+        | Expression::Operand(Operand::Lambda(..), _)
+        | Expression::Operand(Operand::List(_), _) => false,
+        Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+            removable(condition) && removable(consequent) && removable(alternative)
+        }
+        Expression::Operation(ref lhs, operator, ref rhs, _) => {
+            operator != Operator::Divide && removable(lhs) && removable(rhs)
+        }
+    }
+}
+
+fn constant(expr: &Expression) -> Option<i64> {
+    match *expr {
+        Expression::Operand(Operand::I64(value), _) => Some(value),
+        _ => None,
+    }
+}
+
+fn integer(value: i64) -> Expression {
+    Expression::Operand(Operand::I64(value), Span::default())
+}
+
+fn operation(lhs: Expression, operator: Operator, rhs: Expression) -> Expression {
+    Expression::Operation(Box::new(lhs), operator, Box::new(rhs), Span::default())
+}
+
+fn conditional(condition: Expression, consequent: Expression, alternative: Expression) -> Expression {
+    Expression::Operand(
+        Operand::If(Box::new(condition), Box::new(consequent), Box::new(alternative)),
+        Span::default(),
+    )
+}
+
+fn variable(name: &Name) -> Expression {
+    Expression::Operand(Operand::VarSubstitution(name.clone()), Span::default())
+}
+
+fn call(name: &Name, args: Vec<Expression>) -> Expression {
+    Expression::Operand(Operand::FnApplication(name.clone(), args), Span::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::executor::Executor;
+
+    fn derived(s: &str, param: &str) -> Result<String, Error> {
+        let statements = parser::parse(s.as_bytes()).unwrap();
+        let derivative = derive(&statements[0], &Name(param.to_string()))?;
+        Ok(format(&[derivative]).trim_end().to_string())
+    }
+
+    fn simplified(s: &str) -> String {
+        match parser::parse(format!("a = {};", s).as_bytes()).unwrap().remove(0) {
+            Statement::VarAssignment(_, expr, _) => simplify(expr).to_string(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn derive_test() {
+        assert_eq!(derived("f(x) = 3 * x * x + 2 * x + 7;", "x"), Ok("d_f_x(x) = 6 * x + 2;".to_string()));
+        assert_eq!(
+            derived("f(x, y) = x * x * y + y / x;", "y"),
+            Ok("d_f_y(x, y) = x * x + x / (x * x);".to_string())
+        );
+        assert_eq!(
+            derived("f(x) = pow(x, 3) + sqrt(x) - abs(g(2));", "x"),
+            Ok("d_f_x(x) = 3 * pow(x, 2) + 1 / (2 * sqrt(x));".to_string())
+        );
+        assert_eq!(
+            derived("f(x) = if x < 0 then 0 else max(x, 2 * x);", "x"),
+            Ok("d_f_x(x) = if x < 0 then 0 else if 2 * x > x then 2 else 1;".to_string())
+        );
+    }
+
+    #[test]
+    fn derive_errors_test() {
+        let name = |name: &str| Name(name.to_string());
+        assert_eq!(derived("a = 1;", "x"), Err(Error::NotAFunction(name("a"))));
+        assert_eq!(derived("f(x) = x;", "y"), Err(Error::NotAParam(name("f"), name("y"))));
+        assert_eq!(derived("f(x) = g(x);", "x"), Err(Error::NotDifferentiable(name("g"))));
+        assert_eq!(derived("f(x) = pow(2, x);", "x"), Err(Error::NotDifferentiable(name("pow"))));
+        assert_eq!(derived("f(x) = [x];", "x"), Err(Error::NotNumeric));
+        // A lambda's param shadows the one being differentiated by.
+        assert_eq!(derived("f(x) = g(\\x -> x);", "x"), Ok("d_f_x(x) = 0;".to_string()));
+        assert_eq!(derived("f(x) = g(\\y -> x);", "x"), Err(Error::NotDifferentiable(name("g"))));
+    }
+
+    #[test]
+    fn simplify_test() {
+        assert_eq!(simplified("x * 2 * 3 + 0 * y"), "6 * x");
+        assert_eq!(simplified("(x - x) * z + (y + y) / 1"), "2 * y");
+        assert_eq!(simplified("1 * f(x) + 7 / 2 + 8 / 2"), "f(x) + 7 / 2 + 4");
+        assert_eq!(simplified("0 * f(x) + 0 * (1 / x)"), "0 * f(x) + 0 * (1 / x)");
+        assert_eq!(simplified("if 1 < 2 then x else y"), "x");
+        assert_eq!(simplified("if x < 2 then y * 1 else y"), "y");
+    }
+
+    // The derivative agrees with the slope between nearby points.
+    #[test]
+    fn numeric_test() {
+        let source = "f(x, y) = x * x * y / (1 + x) + sqrt(x) * pow(y, 3) - min(x, y);";
+        let mut executor: Executor<f64> = Executor::new();
+        executor.run(parser::parse(source.as_bytes()).unwrap()).unwrap();
+        let definition = parser::parse(source.as_bytes()).unwrap().remove(0);
+        for param in &["x", "y"] {
+            executor.execute(derive(&definition, &Name(param.to_string())).unwrap()).unwrap();
+        }
+        let h = 1e-6;
+        let program = format!(
+            "a = d_f_x(2, 3);\nb = (f(2 + {h}, 3) - f(2 - {h}, 3)) / (2 * {h});\n\
+             c = d_f_y(2, 3);\nd = (f(2, 3 + {h}) - f(2, 3 - {h})) / (2 * {h});",
+            h = h
+        );
+        executor.run(parser::parse(program.as_bytes()).unwrap()).unwrap();
+        let value = |name: &str| executor.variables[&Name(name.to_string())];
+        assert!((value("a") - value("b")).abs() < 1e-4, "{} != {}", value("a"), value("b"));
+        assert!((value("c") - value("d")).abs() < 1e-4, "{} != {}", value("c"), value("d"));
+    }
+}
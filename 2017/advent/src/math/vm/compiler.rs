@@ -0,0 +1,202 @@
+use super::*;
+use std::collections::HashMap;
+
+pub fn compile(statements: &[Statement]) -> Program {
+    let mut compiler = Compiler {
+        globals: Slots::default(),
+        functions: Slots::default(),
+    };
+    let steps = statements
+        .iter()
+        .map(|statement| compiler.statement(statement))
+        .collect();
+    Program {
+        steps,
+        globals: compiler.globals.names,
+        functions: compiler.functions.names,
+    }
+}
+
+// Gives each distinct name the next free index.
+#[derive(Default)]
+pub(crate) struct Slots {
+    pub(crate) names: Vec<Name>,
+    pub(crate) indices: HashMap<Name, usize>,
+}
+
+impl Slots {
+    pub(crate) fn slot(&mut self, name: &Name) -> usize {
+        if let Some(&index) = self.indices.get(name) {
+            return index;
+        }
+        self.names.push(name.clone());
+        self.indices.insert(name.clone(), self.names.len() - 1);
+        return self.names.len() - 1;
+    }
+}
+
+// Where a variable name is looked up. At the top level every name is a global;
+// inside a function it is either a parameter or one of the function's free
+// variables, which are captured when the function is defined.
+pub(crate) enum Scope<'a> {
+    Global,
+    Function(&'a [Name], &'a [Name]),
+}
+
+// The slots given out so far. `math::compiled` compiles to closures with the
+// same slots, and has its own `impl` for that.
+pub(crate) struct Compiler {
+    pub(crate) globals: Slots,
+    pub(crate) functions: Slots,
+}
+
+impl Compiler {
+    fn statement(&mut self, statement: &Statement) -> (Name, Step) {
+        match *statement {
+            Statement::VarAssignment(ref name, ref expr, _) => {
+                let mut code = vec![];
+                self.expression(expr, &Scope::Global, &mut code);
+                (name.clone(), Step::Assign(self.globals.slot(name), code))
+            }
+            Statement::FnDefinition(ref name, ref params, ref expr, _) => {
+                let mut free = vec![];
+                free_variables(params, expr, &mut free);
+                let mut code = vec![];
+                self.expression(expr, &Scope::Function(params, &free), &mut code);
+                code.push(Instruction::Return);
+                let function = Function {
+                    params: params.len(),
+                    captures: free.iter().map(|name| self.globals.slot(name)).collect(),
+                    code,
+                };
+                (name.clone(), Step::Define(self.functions.slot(name), Rc::new(function)))
+            }
+            Statement::Import(_, ref namespace, _) => {
+                let code = vec![Instruction::Unsupported("unresolved imports")];
+                (namespace.clone(), Step::Assign(self.globals.slot(namespace), code))
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression, scope: &Scope, code: &mut Vec<Instruction>) {
+        match *expr {
+            Expression::Operand(ref operand, _) => self.operand(operand, scope, code),
+            Expression::Operation(ref lhs, operator, ref rhs, _) => {
+                self.expression(lhs, scope, code);
+                self.expression(rhs, scope, code);
+                code.push(Instruction::Operate(operator));
+            }
+        }
+    }
+
+    fn operand(&mut self, operand: &Operand, scope: &Scope, code: &mut Vec<Instruction>) {
+        match *operand {
+            Operand::I64(value) => code.push(Instruction::Push(value)),
+            Operand::F64(value) => code.push(Instruction::PushF64(value)),
+            Operand::VarSubstitution(ref name) => code.push(match *scope {
+                Scope::Global => Instruction::LoadGlobal(self.globals.slot(name)),
+                Scope::Function(params, free) => match params.iter().position(|p| p == name) {
+                    Some(i) => Instruction::LoadLocal(i),
+                    None => Instruction::LoadCapture(free.iter().position(|f| f == name).unwrap()),
+                },
+            }),
+            Operand::FnApplication(ref name, ref args) => {
+                if let Scope::Function(params, free) = *scope {
+                    if params.contains(name) {
+                        code.push(Instruction::CallLocal);
+                        return;
+                    }
+                    if let Some(i) = free.iter().position(|f| f == name) {
+                        code.push(Instruction::CheckCapture(i));
+                    }
+                }
+                let slot = self.functions.slot(name);
+                code.push(Instruction::CheckCall(slot, args.len()));
+                for arg in args {
+                    self.expression(arg, scope, code);
+                }
+                code.push(Instruction::Call(slot));
+            }
+            Operand::If(ref condition, ref consequent, ref alternative) => {
+                self.expression(condition, scope, code);
+                let jump_to_alternative = code.len();
+                code.push(Instruction::JumpIfZero(0));
+                self.expression(consequent, scope, code);
+                let jump_to_end = code.len();
+                code.push(Instruction::Jump(0));
+                code[jump_to_alternative] = Instruction::JumpIfZero(code.len());
+                self.expression(alternative, scope, code);
+                code[jump_to_end] = Instruction::Jump(code.len());
+            }
+            Operand::Lambda(..) => code.push(Instruction::Unsupported("lambdas")),
+            Operand::Call(..) => code.push(Instruction::Unsupported("function values")),
+            Operand::List(..) => code.push(Instruction::Unsupported("lists")),
+        }
+    }
+}
+
+// The names a function body uses that are not its parameters, in order of
+// first use.
+pub(crate) fn free_variables(params: &[Name], expr: &Expression, free: &mut Vec<Name>) {
+    match *expr {
+        Expression::Operand(Operand::I64(_), _)
+        | Expression::Operand(Operand::F64(_), _)
+        | Expression::Operand(Operand::Lambda(..), _)
+        | Expression::Operand(Operand::Call(..), _)
+        | Expression::Operand(Operand::List(_), _) => {}
+        Expression::Operand(Operand::VarSubstitution(ref name), _) => {
+            if !params.contains(name) && !free.contains(name) {
+                free.push(name.clone());
+            }
+        }
+        Expression::Operand(Operand::FnApplication(_, ref args), _) => {
+            for arg in args {
+                free_variables(params, arg, free);
+            }
+        }
+        Expression::Operand(Operand::If(ref condition, ref consequent, ref alternative), _) => {
+            free_variables(params, condition, free);
+            free_variables(params, consequent, free);
+            free_variables(params, alternative, free);
+        }
+        Expression::Operation(ref lhs, _, ref rhs, _) => {
+            free_variables(params, lhs, free);
+            free_variables(params, rhs, free);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_test() {
+        let statements = parser::parse(b"a = 2;\nf(x) = if x < a then x else f(x - a);").unwrap();
+        let program = compile(&statements);
+        assert_eq!(program.globals, vec![Name("a".to_string())]);
+        match program.steps[1].1 {
+            Step::Define(0, ref function) => {
+                assert_eq!(function.captures, vec![0]);
+                assert_eq!(
+                    function.code,
+                    vec![
+                        Instruction::LoadLocal(0),
+                        Instruction::LoadCapture(0),
+                        Instruction::Operate(Operator::Less),
+                        Instruction::JumpIfZero(6),
+                        Instruction::LoadLocal(0),
+                        Instruction::Jump(11),
+                        Instruction::CheckCall(0, 1),
+                        Instruction::LoadLocal(0),
+                        Instruction::LoadCapture(0),
+                        Instruction::Operate(Operator::Subtract),
+                        Instruction::Call(0),
+                        Instruction::Return,
+                    ]
+                );
+            }
+            ref other => panic!("unexpected step {:?}", other),
+        }
+    }
+}
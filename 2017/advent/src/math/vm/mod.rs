@@ -0,0 +1,341 @@
+mod compiler;
+
+pub use self::compiler::compile;
+pub(crate) use self::compiler::{free_variables, Compiler, Scope, Slots};
+
+use super::*;
+use super::executor::{builtins, evaluate_operation, Builtin, Error, Kind, DEFAULT_MAX_CALL_DEPTH};
+use super::number::Number;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Runs a math program on the bytecode VM rather than by walking its AST. The
+/// results, including any error, are exactly those of `math::interpret`, for
+/// programs that only compute with numbers. Functions are not values on the
+/// VM, and it fails with `Error::Unsupported` on lambdas and lists.
+pub fn interpret(s: &[u8]) -> Result<HashMap<Name, i64>, super::Error> {
+    let statements = parser::parse(s).map_err(super::Error::ParseError)?;
+    let results = execute(&statements).map_err(super::Error::ExecutionError)?;
+    return Ok(results);
+}
+
+pub fn execute(statements: &[Statement]) -> Result<HashMap<Name, i64>, Error> {
+    let program = compile(statements);
+    let mut vm = Vm::new(&program);
+    vm.run()?;
+    return Ok(vm.variables());
+}
+
+/// Names are resolved at compile time: globals and functions to slots in the
+/// VM, parameters to offsets from the base of the current frame, and the other
+/// names a function body uses to indices into the values it captured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Push(i64),
+    /// Pushes a float literal, which fails unless it is a whole number.
+    PushF64(f64),
+    LoadGlobal(usize),
+    LoadLocal(usize),
+    LoadCapture(usize),
+    Operate(Operator),
+    JumpIfZero(usize),
+    Jump(usize),
+    /// Checks that the function in a slot exists and takes this many
+    /// arguments. This happens before the arguments are evaluated, just as it
+    /// does in the executor, so that both report the same error first. At the
+    /// top level, a name that is neither a function nor a builtin may be a
+    /// global, which holds a number rather than a function.
+    CheckCall(usize, usize),
+    /// Fails with `Error::WrongType` if the value captured at this index was
+    /// assigned, since a function calls a variable that it captured in place
+    /// of any function of the same name.
+    CheckCapture(usize),
+    /// Fails with `Error::WrongType`, in place of a call of a param, which can
+    /// only hold a number.
+    CallLocal,
+    /// Calls the function in a slot with the arguments on top of the stack.
+    /// If the program has not defined one, the builtin of that name is called
+    /// instead.
+    Call(usize),
+    Return,
+    /// Fails with `Error::Unsupported`, in place of what the VM cannot run.
+    Unsupported(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub steps: Vec<(Name, Step)>,
+    /// The name of each global slot.
+    pub globals: Vec<Name>,
+    /// The name of each function slot.
+    pub functions: Vec<Name>,
+}
+
+/// One compiled top-level statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Evaluate the code and store the result in a global slot.
+    Assign(usize, Vec<Instruction>),
+    /// Store the function in a function slot, capturing its globals.
+    Define(usize, Rc<Function>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub params: usize,
+    /// The global slots to capture when the function is defined.
+    pub captures: Vec<usize>,
+    pub code: Vec<Instruction>,
+}
+
+#[derive(Clone)]
+struct Closure {
+    function: Rc<Function>,
+    captures: Rc<Vec<Option<i64>>>,
+}
+
+struct Frame {
+    closure: Closure,
+    pc: usize,
+    base: usize,
+}
+
+/// Calls are managed on an explicit stack of frames rather than by recursing,
+/// but `max_call_depth` is still enforced so that the VM fails on exactly the
+/// programs that the executor does. The builtins available are those of
+/// `builtins::standard()`, so unlike the executor, the VM has no `d`.
+pub struct Vm<'a> {
+    pub max_call_depth: usize,
+    program: &'a Program,
+    globals: Vec<Option<i64>>,
+    functions: Vec<Option<Closure>>,
+    builtins: Vec<Option<Builtin<i64>>>,
+    stack: Vec<i64>,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &'a Program) -> Vm<'a> {
+        Vm {
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            program,
+            globals: vec![None; program.globals.len()],
+            functions: vec![None; program.functions.len()],
+            builtins: {
+                let mut standard = builtins::standard();
+                program.functions.iter().map(|name| standard.remove(name)).collect()
+            },
+            stack: vec![],
+            frames: vec![],
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let program = self.program;
+        for &(ref statement, ref step) in &program.steps {
+            match *step {
+                Step::Assign(slot, ref code) => {
+                    let result = self.evaluate(statement, code);
+                    self.stack.clear();
+                    self.frames.clear();
+                    self.globals[slot] = Some(result?);
+                }
+                Step::Define(slot, ref function) => {
+                    let captures = function.captures.iter().map(|&g| self.globals[g]).collect();
+                    self.functions[slot] = Some(Closure {
+                        function: function.clone(),
+                        captures: Rc::new(captures),
+                    });
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// The value of every global that has been assigned.
+    pub fn variables(&self) -> HashMap<Name, i64> {
+        self.program
+            .globals
+            .iter()
+            .zip(&self.globals)
+            .filter_map(|(name, value)| value.map(|value| (name.clone(), value)))
+            .collect()
+    }
+
+    fn evaluate(&mut self, statement: &Name, code: &[Instruction]) -> Result<i64, Error> {
+        let mut pc = 0;
+        loop {
+            let instruction = match self.frames.last_mut() {
+                Some(frame) => {
+                    frame.pc += 1;
+                    frame.closure.function.code[frame.pc - 1]
+                }
+                None if pc < code.len() => {
+                    pc += 1;
+                    code[pc - 1]
+                }
+                None => return Ok(self.stack.pop().unwrap()),
+            };
+            match instruction {
+                Instruction::Push(value) => self.stack.push(value),
+                Instruction::PushF64(value) => match i64::from_f64(value) {
+                    Some(value) => self.stack.push(value),
+                    None => {
+                        let literal = format!("{:?}", value);
+                        return Err(Error::NotRepresentable(statement.clone(), literal));
+                    }
+                },
+                Instruction::LoadGlobal(slot) => match self.globals[slot] {
+                    Some(value) => self.stack.push(value),
+                    None => {
+                        let name = self.program.globals[slot].clone();
+                        return Err(Error::UnknownVariable(statement.clone(), name));
+                    }
+                },
+                Instruction::LoadLocal(offset) => {
+                    let base = self.frames.last().unwrap().base;
+                    let value = self.stack[base + offset];
+                    self.stack.push(value);
+                }
+                Instruction::LoadCapture(index) => {
+                    let closure = &self.frames.last().unwrap().closure;
+                    match closure.captures[index] {
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            let name = &self.program.globals[closure.function.captures[index]];
+                            return Err(Error::UnknownVariable(statement.clone(), name.clone()));
+                        }
+                    }
+                }
+                Instruction::Operate(operator) => {
+                    let operand2 = self.stack.pop().unwrap();
+                    let operand1 = self.stack.pop().unwrap();
+                    let result = evaluate_operation(statement, operator, operand1, operand2)?;
+                    self.stack.push(result);
+                }
+                Instruction::JumpIfZero(target) => {
+                    if self.stack.pop().unwrap() == 0 {
+                        self.jump(&mut pc, target);
+                    }
+                }
+                Instruction::Jump(target) => self.jump(&mut pc, target),
+                Instruction::CheckCall(slot, args) => {
+                    let name = &self.program.functions[slot];
+                    let params = match (&self.functions[slot], &self.builtins[slot]) {
+                        (&Some(ref closure), _) => closure.function.params,
+                        (&None, &Some(ref builtin)) => builtin.arity,
+                        (&None, &None) if self.frames.is_empty() && self.is_assigned(name) => {
+                            return Err(called_number(statement))
+                        }
+                        (&None, &None) => {
+                            return Err(Error::UnknownFunction(statement.clone(), name.clone()))
+                        }
+                    };
+                    if params != args {
+                        return Err(Error::ArityMismatch(statement.clone(), name.clone(), params, args));
+                    }
+                }
+                Instruction::CheckCapture(index) => {
+                    if self.frames.last().unwrap().closure.captures[index].is_some() {
+                        return Err(called_number(statement));
+                    }
+                }
+                Instruction::CallLocal => return Err(called_number(statement)),
+                Instruction::Call(slot) => {
+                    if self.functions[slot].is_none() {
+                        let builtin = self.builtins[slot].clone().unwrap();
+                        let base = self.stack.len() - builtin.arity;
+                        let result = (builtin.function)(&self.stack[base..]).map_err(|message| {
+                            let name = self.program.functions[slot].clone();
+                            Error::BuiltinFailed(statement.clone(), name, message)
+                        })?;
+                        self.stack.truncate(base);
+                        self.stack.push(result);
+                        continue;
+                    }
+                    if self.frames.len() >= self.max_call_depth {
+                        let name = self.program.functions[slot].clone();
+                        return Err(Error::CallDepthExceeded(statement.clone(), name));
+                    }
+                    let closure = self.functions[slot].clone().unwrap();
+                    let base = self.stack.len() - closure.function.params;
+                    self.frames.push(Frame {
+                        closure,
+                        pc: 0,
+                        base,
+                    });
+                }
+                Instruction::Return => {
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.truncate(frame.base);
+                    self.stack.push(result);
+                }
+                Instruction::Unsupported(what) => {
+                    return Err(Error::Unsupported(statement.clone(), what.to_string()));
+                }
+            }
+        }
+    }
+
+    fn is_assigned(&self, name: &Name) -> bool {
+        let slot = self.program.globals.iter().position(|global| global == name);
+        slot.map_or(false, |slot| self.globals[slot].is_some())
+    }
+
+    fn jump(&mut self, pc: &mut usize, target: usize) {
+        match self.frames.last_mut() {
+            Some(frame) => frame.pc = target,
+            None => *pc = target,
+        }
+    }
+}
+
+fn called_number(statement: &Name) -> Error {
+    Error::WrongType(statement.clone(), Kind::Function, Kind::Number)
+}
+
+// Every program here is run by both backends, which must agree on the result
+// or on the error.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn differential_test() {
+        for program in ::math::programs::PROGRAMS {
+            let statements = parser::parse(program.as_bytes()).unwrap();
+            assert_eq!(
+                execute(&statements),
+                executor::execute(statements.clone()),
+                "backends disagree on {:?}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn differential_call_depth_test() {
+        let statements = parser::parse(b"f(n) = if n == 0 then 0 else f(n - 1);\na = f(9);\nb = f(10);").unwrap();
+        let program = compile(&statements);
+        let mut vm = Vm::new(&program);
+        vm.max_call_depth = 10;
+        let mut executor = executor::Executor::new();
+        executor.limits.max_call_depth = 10;
+        assert_eq!(vm.run(), executor.run(statements));
+        assert_eq!(vm.variables(), executor.variables.into_iter().collect());
+    }
+
+    #[test]
+    fn interpret_test() {
+        let results = interpret(b"f(x) = x * x;\na = f(f(3));").unwrap();
+        assert_eq!(results[&Name("a".to_string())], 81);
+        assert_eq!(
+            interpret(b"a = 1;\nf = \\x -> x;"),
+            Err(::math::Error::ExecutionError(Error::Unsupported(
+                Name("f".to_string()),
+                "lambdas".to_string()
+            )))
+        );
+    }
+}